@@ -1,8 +1,14 @@
 use crate::log::init_log4rs;
 use ::log::{debug, error, info};
-use bowerbird_core::{config::Config, migrate};
+use bowerbird_core::{
+    config::{Config, DownloadFilterOverride},
+    migrate,
+};
 use bowerbird_pixiv::PixivKit;
-use clap::Parser;
+use bowerbird_server::{Scope, ThumbnailFormat};
+use chrono::{DateTime, Utc};
+use clap::{ArgGroup, Args, Parser};
+use sqlx::PgPool;
 use std::path::PathBuf;
 
 pub mod log;
@@ -21,6 +27,10 @@ struct Main {
 #[derive(Parser)]
 enum SubcommandMain {
     Pixiv(Pixiv),
+    Export(Export),
+    Storage(Storage),
+    Thumbnails(Thumbnails),
+    Token(Token),
     Init,
     Migrate,
     Serve,
@@ -32,14 +42,80 @@ struct Pixiv {
     limit: Option<u32>,
     #[clap(short, long)]
     user_id: Option<String>,
+    #[clap(flatten)]
+    download_filter: PixivDownloadFilter,
     #[clap(subcommand)]
     subcommand: SubcommandPixiv,
 }
 
+/// Rules of the illusts not to be downloaded in this job, overriding the configured ones.
+#[derive(Args)]
+struct PixivDownloadFilter {
+    /// true or false
+    #[clap(long)]
+    skip_ai_generated: Option<bool>,
+    /// true or false
+    #[clap(long)]
+    skip_muted: Option<bool>,
+    #[clap(long)]
+    min_bookmarks: Option<i32>,
+    /// Types to be downloaded, in illust, manga and ugoira
+    #[clap(long, value_delimiter = ',')]
+    types: Vec<String>,
+    #[clap(long)]
+    max_pages: Option<i32>,
+    #[clap(long)]
+    max_width: Option<i32>,
+    #[clap(long)]
+    max_height: Option<i32>,
+    /// Skip the illusts with the tag instead of the configured ones, can be repeated
+    #[clap(long)]
+    exclude_tag: Vec<String>,
+    /// 0 for all ages only, and 1 to include R-18
+    #[clap(long)]
+    max_x_restrict: Option<i32>,
+    /// Skip the illusts created before the time, such as 2022-01-01T00:00:00Z
+    #[clap(long)]
+    date_after: Option<DateTime<Utc>>,
+    /// Skip the illusts created after the time
+    #[clap(long)]
+    date_before: Option<DateTime<Utc>>,
+}
+
+impl From<PixivDownloadFilter> for DownloadFilterOverride {
+    fn from(f: PixivDownloadFilter) -> Self {
+        let not_empty = |v: Vec<String>| (!v.is_empty()).then_some(v);
+        DownloadFilterOverride {
+            skip_ai_generated: f.skip_ai_generated,
+            skip_muted: f.skip_muted,
+            min_bookmarks: f.min_bookmarks,
+            types: not_empty(f.types),
+            max_pages: f.max_pages,
+            max_width: f.max_width,
+            max_height: f.max_height,
+            exclude_tags: not_empty(f.exclude_tag),
+            max_x_restrict: f.max_x_restrict,
+            date_after: f.date_after,
+            date_before: f.date_before,
+        }
+    }
+}
+
 #[derive(Parser)]
 enum SubcommandPixiv {
     Illust(PixivIllust),
     Novel(PixivNovel),
+    /// Check whether the saved works and users are still accessible
+    Recheck,
+    /// Download the files of the saved illusts regardless of the download filters
+    Download(PixivDownload),
+}
+
+#[derive(Parser)]
+struct PixivDownload {
+    /// pixiv ids of the illusts, or all the illusts skipped by the download filters if not set
+    #[clap(long)]
+    illust: Vec<String>,
 }
 
 #[derive(Parser)]
@@ -66,6 +142,130 @@ enum SubcommandPixivAction {
 struct PixivBookmarks {
     #[clap(long)]
     private: bool,
+    /// Fetch all public and private bookmarks, and mark the missing ones as unbookmarked
+    #[clap(long, conflicts_with = "private")]
+    reconcile: bool,
+    /// Only fetch the bookmarks with the bookmark tag
+    #[clap(long, conflicts_with = "reconcile")]
+    tag: Option<String>,
+}
+
+#[derive(Parser)]
+struct Export {
+    #[clap(subcommand)]
+    subcommand: SubcommandExport,
+}
+
+#[derive(Parser)]
+enum SubcommandExport {
+    Epub(ExportEpub),
+    Cbz(ExportCbz),
+}
+
+#[derive(Parser)]
+#[clap(group(ArgGroup::new("target").required(true).args(["novel", "series"])))]
+struct ExportEpub {
+    /// pixiv id of the novel
+    #[clap(long)]
+    novel: Option<String>,
+    /// pixiv id of the novel series
+    #[clap(long)]
+    series: Option<String>,
+    #[clap(short, long)]
+    output: PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(group(ArgGroup::new("target").required(true).args(["illust", "series"])))]
+struct ExportCbz {
+    /// pixiv id of the illust
+    #[clap(long)]
+    illust: Option<String>,
+    /// pixiv id of the illust series
+    #[clap(long)]
+    series: Option<String>,
+    #[clap(short, long)]
+    output: PathBuf,
+}
+
+#[derive(Parser)]
+struct Storage {
+    #[clap(subcommand)]
+    subcommand: SubcommandStorage,
+}
+
+#[derive(Parser)]
+enum SubcommandStorage {
+    /// Move the downloaded files to the paths by the configured path templates
+    Relayout(StorageRelayout),
+}
+
+#[derive(Parser)]
+struct StorageRelayout {
+    /// Only print the files to be moved
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Parser)]
+struct Thumbnails {
+    #[clap(subcommand)]
+    subcommand: SubcommandThumbnails,
+}
+
+#[derive(Parser)]
+enum SubcommandThumbnails {
+    /// Make the thumbnails of the downloaded images into the cache
+    Pregenerate(ThumbnailsPregenerate),
+}
+
+#[derive(Parser)]
+struct ThumbnailsPregenerate {
+    /// Sizes of the thumbnails, separated by commas
+    #[clap(long, required = true, value_delimiter = ',')]
+    size: Vec<u32>,
+    /// Formats of the thumbnails in jpeg, webp and avif, separated by commas
+    #[clap(long, value_delimiter = ',', default_value = "jpeg")]
+    format: Vec<ThumbnailFormat>,
+    #[clap(long)]
+    crop_to_center: bool,
+}
+
+#[derive(Parser)]
+struct Token {
+    #[clap(subcommand)]
+    subcommand: SubcommandToken,
+}
+
+#[derive(Parser)]
+enum SubcommandToken {
+    /// Issue an API token of the server, which is printed only once
+    Create(TokenCreate),
+    List,
+    /// Revoke the token and log out its sessions
+    Revoke(TokenRevoke),
+}
+
+#[derive(Parser)]
+struct TokenCreate {
+    name: String,
+    /// read or admin
+    #[clap(long, default_value = "read")]
+    scope: Scope,
+}
+
+#[derive(Parser)]
+struct TokenRevoke {
+    id: i64,
+}
+
+async fn connect_db(config: &Config, skip_migration: bool) -> anyhow::Result<PgPool> {
+    let db = PgPool::connect(&config.postgres_uri).await?;
+
+    if !skip_migration {
+        migrate(&db).await?;
+    }
+    Ok(db)
 }
 
 async fn run_internal() -> anyhow::Result<()> {
@@ -90,12 +290,7 @@ async fn run_internal() -> anyhow::Result<()> {
 
     let pre_fn = async move {
         let config = config_builder()?;
-
-        let db = sqlx::PgPool::connect(&config.postgres_uri).await?;
-
-        if !skip_migration {
-            migrate(&db).await?;
-        }
+        let db = connect_db(&config, skip_migration).await?;
         let kit = PixivKit::new(config, db).await?;
 
         anyhow::Ok(kit)
@@ -113,12 +308,101 @@ async fn run_internal() -> anyhow::Result<()> {
         SubcommandMain::Init => {
             config_builder()?;
         }
+        SubcommandMain::Export(c) => {
+            use bowerbird_pixiv::export;
+            let config = config_builder()?;
+            let db = connect_db(&config, skip_migration).await?;
+            let storage_dir = config.sub_dir(&config.pixiv.storage_dir);
+            match c.subcommand {
+                SubcommandExport::Epub(c) => {
+                    let data = if let Some(series) = &c.series {
+                        let id = export::novel_series_id_by_source_id(&db, series).await?;
+                        export::novel_series_epub(&db, &storage_dir, id).await?
+                    } else {
+                        let novel = c.novel.as_deref().unwrap_or_default();
+                        let id = export::novel_id_by_source_id(&db, novel).await?;
+                        export::novel_epub(&db, &storage_dir, id).await?
+                    };
+                    std::fs::write(&c.output, data)?;
+                    info!("epub saved to {}", c.output.to_string_lossy());
+                }
+                SubcommandExport::Cbz(c) => {
+                    let data = if let Some(series) = &c.series {
+                        let id = export::illust_series_id_by_source_id(&db, series).await?;
+                        export::illust_series_cbz(&db, &storage_dir, id).await?
+                    } else {
+                        let illust = c.illust.as_deref().unwrap_or_default();
+                        let id = export::illust_id_by_source_id(&db, illust).await?;
+                        export::illust_cbz(&db, &storage_dir, id).await?
+                    };
+                    std::fs::write(&c.output, data)?;
+                    info!("cbz saved to {}", c.output.to_string_lossy());
+                }
+            }
+        }
+        SubcommandMain::Storage(c) => {
+            let config = config_builder()?;
+            let db = connect_db(&config, skip_migration).await?;
+            match c.subcommand {
+                SubcommandStorage::Relayout(c) => {
+                    bowerbird_pixiv::storage::relayout(&db, &config, c.dry_run).await?;
+                }
+            }
+        }
+        SubcommandMain::Thumbnails(c) => {
+            let config = config_builder()?;
+            let db = connect_db(&config, skip_migration).await?;
+            match c.subcommand {
+                SubcommandThumbnails::Pregenerate(c) => {
+                    bowerbird_server::pregenerate_thumbnails(
+                        &config,
+                        &db,
+                        &c.size,
+                        &c.format,
+                        c.crop_to_center,
+                    )
+                    .await?;
+                }
+            }
+        }
+        SubcommandMain::Token(c) => {
+            let config = config_builder()?;
+            let db = connect_db(&config, skip_migration).await?;
+            match c.subcommand {
+                SubcommandToken::Create(c) => {
+                    let (id, token) = bowerbird_server::create_token(&db, &c.name, c.scope).await?;
+                    info!("token {} created with scope {}", id, c.scope);
+                    println!("{token}");
+                }
+                SubcommandToken::List => {
+                    for t in bowerbird_server::list_tokens(&db).await? {
+                        let state = match t.revoked_at {
+                            Some(at) => format!("revoked at {at}"),
+                            None => match t.last_used_at {
+                                Some(at) => format!("last used at {at}"),
+                                None => "never used".to_string(),
+                            },
+                        };
+                        println!("{}\t{}\t{}\t{}", t.id, t.name, t.scope, state);
+                    }
+                }
+                SubcommandToken::Revoke(c) => {
+                    if bowerbird_server::revoke_token(&db, c.id).await? {
+                        info!("token {} revoked", c.id);
+                    } else {
+                        error!("no active token {}", c.id);
+                    }
+                }
+            }
+        }
         SubcommandMain::Pixiv(c) => {
             use bowerbird_pixiv::*;
             let user_id = c.user_id;
             let limit = c.limit;
+            let download_filter = c.download_filter.into();
             let pre_fn = async move {
-                let kit = pre_fn.await?;
+                let mut kit = pre_fn.await?;
+                kit.task_config.download_filter.merge(download_filter);
                 let target_user_id = if let Some(user_id) = user_id {
                     user_id
                 } else {
@@ -138,7 +422,13 @@ async fn run_internal() -> anyhow::Result<()> {
             match &c.subcommand {
                 SubcommandPixiv::Illust(c) => match &c.subcommand {
                     SubcommandPixivAction::Bookmarks(c) => {
-                        exec_and_wait!(illust_bookmarks, limit, c.private);
+                        exec_and_wait!(
+                            illust_bookmarks,
+                            limit,
+                            c.private,
+                            c.reconcile,
+                            c.tag.as_deref()
+                        );
                     }
                     SubcommandPixivAction::Uploads => {
                         exec_and_wait!(illust_uploads, limit);
@@ -148,13 +438,45 @@ async fn run_internal() -> anyhow::Result<()> {
                     let update_exists = c.update_exists;
                     match &c.subcommand {
                         SubcommandPixivAction::Bookmarks(c) => {
-                            exec_and_wait!(novel_bookmarks, limit, update_exists, c.private);
+                            exec_and_wait!(
+                                novel_bookmarks,
+                                limit,
+                                update_exists,
+                                c.private,
+                                c.reconcile,
+                                c.tag.as_deref()
+                            );
                         }
                         SubcommandPixivAction::Uploads => {
                             exec_and_wait!(novel_uploads, limit, update_exists);
                         }
                     };
                 }
+                SubcommandPixiv::Recheck => {
+                    let (kit, _) = pre_fn.await?;
+                    if let Err(e) = recheck::recheck(&kit, limit).await {
+                        error!("{}", e);
+                    }
+                    kit.wait_tasks().await;
+                }
+                SubcommandPixiv::Download(c) => {
+                    let (kit, _) = pre_fn.await?;
+                    let ids = if c.illust.is_empty() {
+                        None
+                    } else {
+                        let mut ids = vec![];
+                        for illust in &c.illust {
+                            ids.push(export::illust_id_by_source_id(&kit.db, illust).await?);
+                        }
+                        Some(ids)
+                    };
+                    if let Err(e) =
+                        download::download_saved_illusts(ids.as_deref(), limit, &kit).await
+                    {
+                        error!("{}", e);
+                    }
+                    kit.wait_tasks().await;
+                }
             }
         }
     };
@@ -0,0 +1,54 @@
+create table pixiv.illust_series
+(
+    id        bigint generated always as identity
+        constraint pixiv_illust_series_pk
+            primary key,
+    source_id text not null
+        constraint pixiv_illust_series_series_id_uindex
+            unique,
+    title     text
+);
+
+alter table public.pixiv_illust
+    add series_id bigint;
+
+alter table public.pixiv_illust
+    add constraint pixiv_illust_illust_series_id_fk
+        foreign key (series_id) references pixiv.illust_series;
+
+create index pixiv_illust_series_id_index
+    on pixiv_illust (series_id);
+
+create or replace view pixiv_illust_detail_latest_view as
+select i.id          as                                                id,
+       i.parent_id   as                                                parent_id,
+       h.id          as                                                history_id,
+       i.inserted_at as                                                inserted_at,
+       i.updated_at  as                                                updated_at,
+       i.source_id,
+       source_inaccessible,
+       tag_ids,
+       total_bookmarks,
+       total_view,
+       is_bookmarked,
+       (select name from pixiv_illust_history_type where id = type_id) illust_type,
+       h.title,
+       caption_html,
+       date,
+       ugoira_frame_duration,
+       m.paths                                                         image_paths,
+       m.urls                                                          image_urls,
+       s.id          as                                                series_id,
+       s.title       as                                                series_title
+
+from pixiv_illust_history h
+         join (select max(id) id from pixiv_illust_history group by item_id) max_id on max_id.id = h.id
+         join pixiv_illust i on i.id = h.item_id
+         left join (select hm.history_id                        history_id,
+                           array_agg(url order by hm.id)        urls,
+                           array_agg(local_path order by hm.id) paths
+                    from pixiv_media m
+                             join pixiv_illust_history_media hm on m.id = hm.media_id
+                    group by hm.history_id) m on m.history_id = h.id
+         left join pixiv.illust_series s on s.id = i.series_id
+;
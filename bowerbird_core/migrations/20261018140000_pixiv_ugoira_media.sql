@@ -0,0 +1,77 @@
+alter table public.pixiv_media
+    add derived_from_id bigint;
+
+alter table public.pixiv_media
+    add constraint pixiv_media_pixiv_media_null_fk_derived_from_id
+        foreign key (derived_from_id) references pixiv_media;
+
+create index pixiv_media_derived_from_id_index
+    on pixiv_media (derived_from_id);
+
+alter table public.pixiv_illust_history
+    add ugoira_zip_id bigint;
+
+alter table public.pixiv_illust_history
+    add constraint pixiv_illust_history_pixiv_media_null_fk_ugoira_zip_id
+        foreign key (ugoira_zip_id) references pixiv_media;
+
+-- Link the saved zips to the ugoira histories by the illust id in the url.
+update pixiv_illust_history h
+set ugoira_zip_id = m.id
+from pixiv_illust i,
+     pixiv_media m
+where i.id = h.item_id
+  and h.type_id = (select id from pixiv_illust_history_type where name = 'ugoira')
+  and m.mime = 'application/zip'
+  and m.url like '%/' || i.source_id || '\_ugoira%';
+
+-- Converted outputs are saved without url next to the zip with the same file stem.
+update pixiv_media c
+set derived_from_id = z.id
+from pixiv_media z
+where z.mime = 'application/zip'
+  and c.url is null
+  and c.id <> z.id
+  and regexp_replace(c.local_path, '\.[^./]*$', '') = regexp_replace(z.local_path, '\.[^./]*$', '');
+
+create or replace view pixiv_illust_detail_latest_view as
+select i.id          as                                                id,
+       i.parent_id   as                                                parent_id,
+       h.id          as                                                history_id,
+       i.inserted_at as                                                inserted_at,
+       i.updated_at  as                                                updated_at,
+       i.source_id,
+       source_inaccessible,
+       tag_ids,
+       total_bookmarks,
+       total_view,
+       is_bookmarked,
+       (select name from pixiv_illust_history_type where id = type_id) illust_type,
+       h.title,
+       caption_html,
+       date,
+       ugoira_frame_duration,
+       m.paths                                                         image_paths,
+       m.urls                                                          image_urls,
+       s.id          as                                                series_id,
+       s.title       as                                                series_title,
+       mz.local_path                                                   ugoira_zip_path,
+       c.paths                                                         ugoira_converted_paths,
+       c.mimes                                                         ugoira_converted_mimes
+
+from pixiv_illust_history h
+         join (select max(id) id from pixiv_illust_history group by item_id) max_id on max_id.id = h.id
+         join pixiv_illust i on i.id = h.item_id
+         left join (select hm.history_id                        history_id,
+                           array_agg(url order by hm.id)        urls,
+                           array_agg(local_path order by hm.id) paths
+                    from pixiv_media m
+                             join pixiv_illust_history_media hm on m.id = hm.media_id
+                    group by hm.history_id) m on m.history_id = h.id
+         left join pixiv.illust_series s on s.id = i.series_id
+         left join pixiv_media mz on mz.id = h.ugoira_zip_id
+         left join lateral (select array_agg(local_path order by id) paths,
+                                   array_agg(mime order by id)       mimes
+                            from pixiv_media
+                            where derived_from_id = h.ugoira_zip_id) c on true
+;
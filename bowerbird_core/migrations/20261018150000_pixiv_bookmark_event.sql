@@ -0,0 +1,46 @@
+alter table public.pixiv_illust
+    add bookmark_private boolean;
+
+alter table public.pixiv_novel
+    add bookmark_private boolean;
+
+create table pixiv.illust_bookmark_event
+(
+    id               bigint generated always as identity
+        constraint pixiv_illust_bookmark_event_pk
+            primary key,
+    item_id          bigint                                 not null
+        constraint pixiv_illust_bookmark_event_pixiv_illust_null_fk
+            references pixiv_illust,
+    is_bookmarked    boolean                                not null,
+    bookmark_private boolean,
+    inserted_at      timestamp with time zone default now() not null
+);
+create index pixiv_illust_bookmark_event_item_id_index
+    on pixiv.illust_bookmark_event (item_id);
+
+create table pixiv.novel_bookmark_event
+(
+    id               bigint generated always as identity
+        constraint pixiv_novel_bookmark_event_pk
+            primary key,
+    item_id          bigint                                 not null
+        constraint pixiv_novel_bookmark_event_pixiv_novel_null_fk
+            references pixiv_novel,
+    is_bookmarked    boolean                                not null,
+    bookmark_private boolean,
+    inserted_at      timestamp with time zone default now() not null
+);
+create index pixiv_novel_bookmark_event_item_id_index
+    on pixiv.novel_bookmark_event (item_id);
+
+-- The time of bookmarking is unknown for the existing bookmarks.
+insert into pixiv.illust_bookmark_event (item_id, is_bookmarked, inserted_at)
+select id, true, coalesce(updated_at, inserted_at, now())
+from pixiv_illust
+where is_bookmarked;
+
+insert into pixiv.novel_bookmark_event (item_id, is_bookmarked, inserted_at)
+select id, true, coalesce(updated_at, inserted_at, now())
+from pixiv_novel
+where is_bookmarked;
@@ -0,0 +1,78 @@
+alter table public.pixiv_illust
+    add checked_at timestamp with time zone;
+alter table public.pixiv_illust
+    add lost_at timestamp with time zone;
+alter table public.pixiv_illust
+    add lost_reason varchar(16);
+
+alter table public.pixiv_novel
+    add checked_at timestamp with time zone;
+alter table public.pixiv_novel
+    add lost_at timestamp with time zone;
+alter table public.pixiv_novel
+    add lost_reason varchar(16);
+
+alter table public.pixiv_user
+    add checked_at timestamp with time zone;
+alter table public.pixiv_user
+    add lost_at timestamp with time zone;
+alter table public.pixiv_user
+    add lost_reason varchar(16);
+
+create index pixiv_illust_checked_at_index
+    on pixiv_illust (checked_at);
+create index pixiv_novel_checked_at_index
+    on pixiv_novel (checked_at);
+create index pixiv_user_checked_at_index
+    on pixiv_user (checked_at);
+
+-- The time is unknown for the works found invisible before.
+update pixiv_illust
+set lost_at     = coalesce(updated_at, inserted_at, now()),
+    lost_reason = 'invisible'
+where source_inaccessible;
+
+update pixiv_novel
+set lost_at     = coalesce(updated_at, inserted_at, now()),
+    lost_reason = 'invisible'
+where source_inaccessible;
+
+create or replace view pixiv_lost_work_view as
+select 'illust'::text                                   as kind,
+       i.id,
+       i.parent_id,
+       i.source_id,
+       h.title,
+       i.lost_at,
+       i.lost_reason,
+       i.checked_at,
+       exists(select
+              from pixiv_illust_history_media hm
+                       join pixiv_media m on m.id = hm.media_id
+              where hm.history_id = h.id
+                and m.local_path is not null)         as preserved
+from pixiv_illust i
+         left join lateral (select id, title
+                            from pixiv_illust_history
+                            where item_id = i.id
+                            order by id desc
+                            limit 1) h on true
+where i.lost_at is not null
+union all
+select 'novel'::text                                    as kind,
+       n.id,
+       n.parent_id,
+       n.source_id,
+       h.title,
+       n.lost_at,
+       n.lost_reason,
+       n.checked_at,
+       coalesce(h.text is not null and h.text <> '', false) as preserved
+from pixiv_novel n
+         left join lateral (select title, text
+                            from pixiv_novel_history
+                            where item_id = n.id
+                            order by id desc
+                            limit 1) h on true
+where n.lost_at is not null
+;
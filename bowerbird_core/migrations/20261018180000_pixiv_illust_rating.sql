@@ -0,0 +1,58 @@
+alter table public.pixiv_illust_history
+    add x_restrict smallint;
+alter table public.pixiv_illust_history
+    add sanity_level smallint;
+-- 0 for unknown, 1 for not AI-generated, and 2 for AI-generated.
+alter table public.pixiv_illust_history
+    add ai_type smallint;
+
+alter table public.pixiv_illust
+    add is_muted boolean;
+
+create or replace view pixiv_illust_detail_latest_view as
+select i.id          as                                                id,
+       i.parent_id   as                                                parent_id,
+       h.id          as                                                history_id,
+       i.inserted_at as                                                inserted_at,
+       i.updated_at  as                                                updated_at,
+       i.source_id,
+       source_inaccessible,
+       tag_ids,
+       total_bookmarks,
+       total_view,
+       is_bookmarked,
+       (select name from pixiv_illust_history_type where id = type_id) illust_type,
+       h.title,
+       caption_html,
+       date,
+       ugoira_frame_duration,
+       m.paths                                                         image_paths,
+       m.urls                                                          image_urls,
+       s.id          as                                                series_id,
+       s.title       as                                                series_title,
+       mz.local_path                                                   ugoira_zip_path,
+       c.paths                                                         ugoira_converted_paths,
+       c.mimes                                                         ugoira_converted_mimes,
+       i.bookmark_private,
+       i.bookmark_tag_ids,
+       h.x_restrict,
+       h.sanity_level,
+       h.ai_type,
+       i.is_muted
+
+from pixiv_illust_history h
+         join (select max(id) id from pixiv_illust_history group by item_id) max_id on max_id.id = h.id
+         join pixiv_illust i on i.id = h.item_id
+         left join (select hm.history_id                        history_id,
+                           array_agg(url order by hm.id)        urls,
+                           array_agg(local_path order by hm.id) paths
+                    from pixiv_media m
+                             join pixiv_illust_history_media hm on m.id = hm.media_id
+                    group by hm.history_id) m on m.history_id = h.id
+         left join pixiv.illust_series s on s.id = i.series_id
+         left join pixiv_media mz on mz.id = h.ugoira_zip_id
+         left join lateral (select array_agg(local_path order by id) paths,
+                                   array_agg(mime order by id)       mimes
+                            from pixiv_media
+                            where derived_from_id = h.ugoira_zip_id) c on true
+;
@@ -0,0 +1,7 @@
+-- Why the files of the illust are not downloaded by the download filters.
+alter table public.pixiv_illust
+    add download_skipped_reason varchar(16);
+
+create index pixiv_illust_download_skipped_reason_index
+    on pixiv_illust (download_skipped_reason)
+    where download_skipped_reason is not null;
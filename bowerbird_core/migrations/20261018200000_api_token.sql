@@ -0,0 +1,34 @@
+-- Tokens to access the HTTP API, only the SHA-256 hashes are stored.
+create table public.api_token
+(
+    id           bigserial
+        constraint api_token_pk
+            primary key,
+    name         varchar(64)                            not null,
+    token_hash   char(64)                               not null
+        constraint api_token_hash_unique
+            unique,
+    scope        varchar(8)                             not null
+        constraint api_token_scope_check
+            check (scope in ('read', 'admin')),
+    created_at   timestamp with time zone default now() not null,
+    last_used_at timestamp with time zone,
+    revoked_at   timestamp with time zone
+);
+
+-- Browser sessions logged in with the API tokens.
+create table public.api_session
+(
+    session_hash char(64)                               not null
+        constraint api_session_pk
+            primary key,
+    token_id     bigint                                 not null
+        constraint api_session_token_id_fk
+            references public.api_token
+            on delete cascade,
+    created_at   timestamp with time zone default now() not null,
+    expires_at   timestamp with time zone               not null
+);
+
+create index api_session_expires_at_index
+    on api_session (expires_at);
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DurationSeconds};
@@ -138,6 +139,335 @@ mod serde_header_value {
 pub use reqwest::header::HeaderValue;
 pub use serde_header_value::SerdeHeaderValue;
 
+/// Output formats of ugoira conversion.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum UgoiraFormat {
+    Mp4,
+    Webm,
+    Webp,
+    Gif,
+    Apng,
+}
+
+impl UgoiraFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            UgoiraFormat::Mp4 => "mp4",
+            UgoiraFormat::Webm => "webm",
+            UgoiraFormat::Webp => "webp",
+            UgoiraFormat::Gif => "gif",
+            UgoiraFormat::Apng => "apng",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            UgoiraFormat::Mp4 => "video/mp4",
+            UgoiraFormat::Webm => "video/webm",
+            UgoiraFormat::Webp => "image/webp",
+            UgoiraFormat::Gif => "image/gif",
+            UgoiraFormat::Apng => "image/apng",
+        }
+    }
+}
+
+/// Settings of ffmpeg to encode an ugoira.
+///
+/// The fields not set in the config file are taken from the default of the format.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct UgoiraCodec {
+    /// Frames are resampled to this frame rate unless `exact_timing` is set.
+    pub framerate: u32,
+    /// Keep the frame delays as is with variable frame rate instead of resampling.
+    pub exact_timing: bool,
+    /// Arguments of ffmpeg put between the input and the output.
+    pub args: Vec<String>,
+}
+
+impl UgoiraCodec {
+    fn new(framerate: u32, args: &[&str]) -> Self {
+        Self {
+            framerate,
+            exact_timing: false,
+            args: args.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    pub fn default_for(format: UgoiraFormat) -> Self {
+        match format {
+            UgoiraFormat::Mp4 => Self::new(
+                60,
+                &[
+                    "-c:v",
+                    "libx264",
+                    "-preset",
+                    "slow",
+                    "-crf",
+                    "22",
+                    "-pix_fmt",
+                    "yuv420p",
+                    "-vf",
+                    "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+                ],
+            ),
+            UgoiraFormat::Webm => Self::new(
+                60,
+                &[
+                    "-c:v",
+                    "libvpx-vp9",
+                    "-crf",
+                    "30",
+                    "-b:v",
+                    "0",
+                    "-pix_fmt",
+                    "yuv420p",
+                ],
+            ),
+            // Each frame of an animated WebP is stored, so a high frame rate only duplicates them.
+            UgoiraFormat::Webp => Self::new(
+                25,
+                &["-c:v", "libwebp_anim", "-quality", "90", "-loop", "0"],
+            ),
+            // GIF delays are in centiseconds.
+            UgoiraFormat::Gif => Self::new(
+                50,
+                &[
+                    "-vf",
+                    "split[a][b];[a]palettegen[p];[b][p]paletteuse",
+                    "-loop",
+                    "0",
+                ],
+            ),
+            UgoiraFormat::Apng => Self::new(60, &["-c:v", "apng", "-plays", "0", "-f", "apng"]),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PartialUgoiraCodec {
+    framerate: Option<u32>,
+    exact_timing: Option<bool>,
+    args: Option<Vec<String>>,
+}
+
+impl PartialUgoiraCodec {
+    fn or_default_for(self, format: UgoiraFormat) -> UgoiraCodec {
+        let default = UgoiraCodec::default_for(format);
+        UgoiraCodec {
+            framerate: self.framerate.unwrap_or(default.framerate),
+            exact_timing: self.exact_timing.unwrap_or(default.exact_timing),
+            args: self.args.unwrap_or(default.args),
+        }
+    }
+}
+
+macro_rules! deserialize_codec {
+    ($name:ident, $format:expr) => {
+        fn $name<'de, D>(d: D) -> std::result::Result<UgoiraCodec, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            PartialUgoiraCodec::deserialize(d).map(|c| c.or_default_for($format))
+        }
+    };
+}
+
+deserialize_codec!(deserialize_mp4, UgoiraFormat::Mp4);
+deserialize_codec!(deserialize_webm, UgoiraFormat::Webm);
+deserialize_codec!(deserialize_webp, UgoiraFormat::Webp);
+deserialize_codec!(deserialize_gif, UgoiraFormat::Gif);
+deserialize_codec!(deserialize_apng, UgoiraFormat::Apng);
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct UgoiraConfig {
+    /// Formats converted from the ugoira zip and saved after downloading.
+    pub outputs: Vec<UgoiraFormat>,
+    #[serde(deserialize_with = "deserialize_mp4")]
+    pub mp4: UgoiraCodec,
+    #[serde(deserialize_with = "deserialize_webm")]
+    pub webm: UgoiraCodec,
+    #[serde(deserialize_with = "deserialize_webp")]
+    pub webp: UgoiraCodec,
+    #[serde(deserialize_with = "deserialize_gif")]
+    pub gif: UgoiraCodec,
+    #[serde(deserialize_with = "deserialize_apng")]
+    pub apng: UgoiraCodec,
+}
+
+impl Default for UgoiraConfig {
+    fn default() -> Self {
+        Self {
+            outputs: vec![UgoiraFormat::Mp4],
+            mp4: UgoiraCodec::default_for(UgoiraFormat::Mp4),
+            webm: UgoiraCodec::default_for(UgoiraFormat::Webm),
+            webp: UgoiraCodec::default_for(UgoiraFormat::Webp),
+            gif: UgoiraCodec::default_for(UgoiraFormat::Gif),
+            apng: UgoiraCodec::default_for(UgoiraFormat::Apng),
+        }
+    }
+}
+
+impl UgoiraConfig {
+    pub fn codec(&self, format: UgoiraFormat) -> &UgoiraCodec {
+        match format {
+            UgoiraFormat::Mp4 => &self.mp4,
+            UgoiraFormat::Webm => &self.webm,
+            UgoiraFormat::Webp => &self.webp,
+            UgoiraFormat::Gif => &self.gif,
+            UgoiraFormat::Apng => &self.apng,
+        }
+    }
+}
+
+/// Pacing and retries of the requests to the pixiv api.
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Requests allowed in a minute on average, 0 for unlimited.
+    pub requests_per_minute: u32,
+    /// Requests allowed to be sent at once after being idle.
+    pub burst: u32,
+    /// The first delay of retrying on http errors, doubled on each retry.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub backoff_base: Duration,
+    /// The first delay of retrying on rate limit without `Retry-After`, doubled on each retry.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub rate_limit_backoff_base: Duration,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub backoff_max: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 120,
+            burst: 10,
+            backoff_base: Duration::from_secs(2),
+            rate_limit_backoff_base: Duration::from_secs(60),
+            backoff_max: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Rules of the illusts not to be downloaded, whose metadata is still saved.
+///
+/// Rules which are `None` or empty are not applied.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DownloadFilter {
+    pub skip_ai_generated: bool,
+    /// Skip the illusts muted by the logged in user.
+    pub skip_muted: bool,
+    pub min_bookmarks: Option<i32>,
+    /// Types to be downloaded, in `illust`, `manga` and `ugoira`.
+    pub types: Vec<String>,
+    pub max_pages: Option<i32>,
+    /// Skip the illusts wider than it.
+    pub max_width: Option<i32>,
+    /// Skip the illusts higher than it.
+    pub max_height: Option<i32>,
+    /// Skip the illusts with any of the tags, matched by the name or the translated name.
+    pub exclude_tags: Vec<String>,
+    /// 0 for all ages only, and 1 to include R-18.
+    pub max_x_restrict: Option<i32>,
+    pub date_after: Option<DateTime<Utc>>,
+    pub date_before: Option<DateTime<Utc>>,
+}
+
+/// Rules of a job overriding the configured [`DownloadFilter`], which are kept if `None`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DownloadFilterOverride {
+    pub skip_ai_generated: Option<bool>,
+    pub skip_muted: Option<bool>,
+    pub min_bookmarks: Option<i32>,
+    pub types: Option<Vec<String>>,
+    pub max_pages: Option<i32>,
+    pub max_width: Option<i32>,
+    pub max_height: Option<i32>,
+    pub exclude_tags: Option<Vec<String>>,
+    pub max_x_restrict: Option<i32>,
+    pub date_after: Option<DateTime<Utc>>,
+    pub date_before: Option<DateTime<Utc>>,
+}
+
+impl DownloadFilter {
+    /// Override the rules with the ones set in `other`.
+    pub fn merge(&mut self, other: DownloadFilterOverride) {
+        macro_rules! merge {
+            ($($field:ident),*) => {
+                $(if let Some(value) = other.$field {
+                    self.$field = value;
+                })*
+            };
+            (option: $($field:ident),*) => {
+                $(if other.$field.is_some() {
+                    self.$field = other.$field;
+                })*
+            };
+        }
+        merge!(skip_ai_generated, skip_muted, types, exclude_tags);
+        merge!(
+            option: min_bookmarks,
+            max_pages,
+            max_width,
+            max_height,
+            max_x_restrict,
+            date_after,
+            date_before
+        );
+    }
+}
+
+/// Rechecking whether the saved works and users are still accessible on pixiv.
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RecheckConfig {
+    /// Items checked within the interval are skipped.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub interval: Duration,
+    /// Number of items loaded from the database at once.
+    pub batch_size: u32,
+    /// Number of items checked at the same time.
+    pub concurrency: usize,
+}
+
+impl Default for RecheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30 * 24 * 60 * 60),
+            batch_size: 100,
+            concurrency: 4,
+        }
+    }
+}
+
+/// Templates of the paths of the downloaded illust files, relative to the storage dir.
+///
+/// Variables are written as `{name}`, in `user_id`, `user_name`, `illust_id`, `title`, `type`,
+/// `date`, `year`, `month`, `day`, `hour`, `minute`, `second`, `page`, `filename` and `ext`.
+/// The date is the one in the URL of the file, and `filename` is the name in the URL without the
+/// extension, such as `92187206_p0`. Use `{{` and `}}` for braces.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PathTemplateConfig {
+    pub single_page: String,
+    /// Used for the illusts with multiple pages and the ugoira.
+    pub multi_page: String,
+}
+
+impl Default for PathTemplateConfig {
+    fn default() -> Self {
+        Self {
+            single_page: "{user_id}/{filename}_{date}.{ext}".to_string(),
+            multi_page: "{user_id}/{illust_id}_{date}/{filename}.{ext}".to_string(),
+        }
+    }
+}
+
 #[serde_as]
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(default)]
@@ -150,6 +480,17 @@ pub struct PixivConfig {
 
     #[serde_as(as = "DurationSeconds<u64>")]
     pub user_need_update_interval: Duration,
+    /// Number of users fetched at the same time when updating user details.
+    pub user_update_concurrency: usize,
+    /// Fetch the tags of each bookmark when syncing the bookmarks of the logged in user,
+    /// which costs an api call per bookmark on every sync.
+    pub sync_bookmark_tags: bool,
+
+    pub ugoira: UgoiraConfig,
+    pub rate_limit: RateLimitConfig,
+    pub recheck: RecheckConfig,
+    pub download_filter: DownloadFilter,
+    pub path_template: PathTemplateConfig,
 }
 
 impl Default for PixivConfig {
@@ -161,6 +502,13 @@ impl Default for PixivConfig {
             refresh_token: "".to_string(),
             language: SerdeHeaderValue("en".parse().unwrap()),
             user_need_update_interval: chrono::Duration::days(7).to_std().unwrap(),
+            user_update_concurrency: 4,
+            sync_bookmark_tags: false,
+            ugoira: UgoiraConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            recheck: RecheckConfig::default(),
+            download_filter: DownloadFilter::default(),
+            path_template: PathTemplateConfig::default(),
         }
     }
 }
@@ -170,6 +518,17 @@ impl Default for PixivConfig {
 pub struct ServerConfig {
     pub listen_addr: SocketAddr,
     pub thumbnail_jpeg_quality: u8,
+    pub thumbnail_webp_quality: u8,
+    /// Only used if the server is built with the `avif` feature.
+    pub thumbnail_avif_quality: u8,
+    /// Dir of the cached thumbnails, relative to the root storage dir if not absolute.
+    pub thumbnail_cache_dir: String,
+    /// Max total size in bytes of the cached thumbnails.
+    pub thumbnail_cache_size: u64,
+    /// Require an API token or a logged in session for all the requests.
+    pub require_auth: bool,
+    /// Hours before a logged in session expires.
+    pub session_ttl_hours: u32,
 }
 
 impl Default for ServerConfig {
@@ -177,6 +536,12 @@ impl Default for ServerConfig {
         Self {
             listen_addr: "127.0.0.1:5000".parse().unwrap(),
             thumbnail_jpeg_quality: 85,
+            thumbnail_webp_quality: 80,
+            thumbnail_avif_quality: 60,
+            thumbnail_cache_dir: "thumbnails".to_string(),
+            thumbnail_cache_size: 1024 * 1024 * 1024,
+            require_auth: false,
+            session_ttl_hours: 24 * 30,
         }
     }
 }
@@ -305,6 +670,48 @@ mod tests {
         assert_eq!(rel_sub_dir, PathBuf::from("/tmp/rel/xxxx"));
     }
 
+    #[test]
+    fn test_ugoira_config_partial() {
+        let config: UgoiraConfig = serde_json::from_str(
+            r#"{"outputs": ["webm", "gif"], "gif": {"framerate": 25, "args": ["-loop", "0"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.outputs, [UgoiraFormat::Webm, UgoiraFormat::Gif]);
+        assert_eq!(config.codec(UgoiraFormat::Gif).framerate, 25);
+        assert_eq!(config.codec(UgoiraFormat::Gif).args, ["-loop", "0"]);
+        assert_eq!(config.webm, UgoiraConfig::default().webm);
+
+        let config: UgoiraConfig =
+            serde_json::from_str(r#"{"mp4": {"exact_timing": true}}"#).unwrap();
+        assert!(config.mp4.exact_timing);
+        assert_eq!(config.mp4.args, UgoiraConfig::default().mp4.args);
+    }
+
+    #[test]
+    fn test_download_filter_merge() {
+        let mut filter = DownloadFilter {
+            skip_ai_generated: true,
+            min_bookmarks: Some(100),
+            exclude_tags: vec!["a".to_string()],
+            ..Default::default()
+        };
+        filter.merge(DownloadFilterOverride {
+            skip_ai_generated: Some(false),
+            exclude_tags: Some(vec!["b".to_string()]),
+            max_pages: Some(10),
+            ..Default::default()
+        });
+        assert_eq!(
+            filter,
+            DownloadFilter {
+                min_bookmarks: Some(100),
+                exclude_tags: vec!["b".to_string()],
+                max_pages: Some(10),
+                ..Default::default()
+            }
+        );
+    }
+
     #[test]
     fn test_proxy() {
         let tempdir = tempfile::tempdir().unwrap();
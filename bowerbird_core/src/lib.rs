@@ -1,15 +1,33 @@
-use std::time::Instant;
+use std::{collections::BTreeSet, time::Instant};
 
 use log::debug;
-use sqlx::{migrate::MigrateError, PgPool};
+use sqlx::{
+    migrate::{MigrateError, Migrator},
+    query_as, PgPool,
+};
 
 pub mod config;
 pub mod model;
 
+static MIGRATOR: Migrator = sqlx::migrate!();
+
 pub async fn migrate(db: &PgPool) -> Result<(), MigrateError> {
     debug!("migration started");
     let t = Instant::now();
-    sqlx::migrate!().run(db).await?;
+    MIGRATOR.run(db).await?;
     debug!("migration finished: {:?}", t.elapsed());
     Ok(())
 }
+
+/// Versions of the migrations not applied to the database yet, or failed.
+pub async fn pending_migrations(db: &PgPool) -> Result<Vec<i64>, sqlx::Error> {
+    let applied: Vec<(i64,)> = query_as("select version from _sqlx_migrations where success")
+        .fetch_all(db)
+        .await?;
+    let applied: BTreeSet<i64> = applied.into_iter().map(|(v,)| v).collect();
+    Ok(MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration() && !applied.contains(&m.version))
+        .map(|m| m.version)
+        .collect())
+}
@@ -1,16 +1,17 @@
 use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
 pub mod pixiv;
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
 pub struct Tag {
     pub id: i64,
     pub alias: Vec<String>,
 }
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
 pub struct Item<E, H> {
     pub id: i64,
     #[sqlx(default)]
@@ -36,7 +37,7 @@ pub struct Item<E, H> {
     pub _count: Option<i64>,
 }
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
 pub struct History<H> {
     pub history_id: i64,
     #[sqlx(default)]
@@ -47,7 +48,7 @@ pub struct History<H> {
     pub extension: H,
 }
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
 pub struct Media<E> {
     pub id: i64,
     pub url: Option<String>,
@@ -59,7 +60,7 @@ pub struct Media<E> {
     pub extension: E,
 }
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
 pub struct Image {
     pub width: i32,
     pub height: i32,
@@ -1,10 +1,11 @@
 use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
 use super::Item;
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
 pub struct User {
     pub is_followed: bool,
     pub total_following: Option<i32>,
@@ -16,7 +17,7 @@ pub struct User {
     pub total_public_bookmarks: Option<i32>,
 }
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
 pub struct UserHistory {
     pub name: Option<String>,
     pub region: Option<String>,
@@ -25,14 +26,21 @@ pub struct UserHistory {
     pub background_path: Option<String>,
 }
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
 pub struct Works {
     pub total_bookmarks: i32,
     pub total_view: i32,
     pub is_bookmarked: bool,
+    #[sqlx(default)]
+    pub bookmark_private: Option<bool>,
+    /// Tags added to the bookmark by the logged in user.
+    #[sqlx(default)]
+    pub bookmark_tag_ids: Option<Vec<i64>>,
+    #[sqlx(default)]
+    pub is_muted: Option<bool>,
 }
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow)]
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
 pub struct IllustHistory {
     pub illust_type: String,
     pub caption_html: String,
@@ -40,7 +48,43 @@ pub struct IllustHistory {
     pub date: Option<DateTime<Utc>>,
     pub image_paths: Option<Vec<Option<String>>>,
     // pub ugoira_frame_duration: Option<Vec<i32>>,
+    #[sqlx(default)]
+    pub ugoira_zip_path: Option<String>,
+    /// Files converted from the ugoira zip, such as mp4 or gif.
+    #[sqlx(default)]
+    pub ugoira_converted_paths: Option<Vec<Option<String>>>,
+    #[sqlx(default)]
+    pub ugoira_converted_mimes: Option<Vec<Option<String>>>,
+    /// 0 for all ages, 1 for R-18, and 2 for R-18G.
+    #[sqlx(default)]
+    pub x_restrict: Option<i16>,
+    #[sqlx(default)]
+    pub sanity_level: Option<i16>,
+    /// 0 for unknown, 1 for not AI-generated, and 2 for AI-generated.
+    #[sqlx(default)]
+    pub ai_type: Option<i16>,
 }
 
 pub type PixivIllust = Item<Works, IllustHistory>;
 pub type PixivUser = Item<User, UserHistory>;
+
+/// An illust or novel not accessible on pixiv anymore.
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, JsonSchema)]
+pub struct LostWork {
+    /// `illust` or `novel`.
+    pub kind: String,
+    pub id: i64,
+    pub parent_id: Option<i64>,
+    pub source_id: Option<String>,
+    pub title: Option<String>,
+    pub lost_at: Option<DateTime<Utc>>,
+    /// `deleted`, `private`, `restricted` or `invisible`.
+    pub lost_reason: Option<String>,
+    pub checked_at: Option<DateTime<Utc>>,
+    /// Whether the images or the text is saved.
+    pub preserved: bool,
+
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub _count: Option<i64>,
+}
@@ -0,0 +1,197 @@
+//! The app-api sent by the kit, including the ones not provided by pixivcrab.
+
+use pixivcrab::models::{
+    illust::{self, Illust},
+    novel, user,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::{PixivKit, Result};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BookmarkTag {
+    pub name: String,
+    /// Whether the tag is added to the bookmark, or only suggested.
+    pub is_registered: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BookmarkDetail {
+    pub tags: Vec<BookmarkTag>,
+}
+
+impl BookmarkDetail {
+    /// Get the names of the tags added to the bookmark.
+    pub fn registered_tags(&self) -> Vec<String> {
+        self.tags
+            .iter()
+            .filter(|t| t.is_registered && !t.name.is_empty())
+            .map(|t| t.name.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BookmarkDetailResponse {
+    bookmark_detail: BookmarkDetail,
+}
+
+pub async fn illust_bookmark_detail(kit: &PixivKit, illust_id: &str) -> Result<BookmarkDetail> {
+    let r: BookmarkDetailResponse = kit
+        .retry_get_json("/v2/illust/bookmark/detail", &[("illust_id", illust_id)])
+        .await?;
+    Ok(r.bookmark_detail)
+}
+
+pub async fn novel_bookmark_detail(kit: &PixivKit, novel_id: &str) -> Result<BookmarkDetail> {
+    let r: BookmarkDetailResponse = kit
+        .retry_get_json("/v2/novel/bookmark/detail", &[("novel_id", novel_id)])
+        .await?;
+    Ok(r.bookmark_detail)
+}
+
+pub async fn user_detail(kit: &PixivKit, user_id: &str) -> Result<user::Response> {
+    kit.retry_get_json("/v1/user/detail", &[("user_id", user_id)])
+        .await
+}
+
+pub async fn ugoira_metadata(kit: &PixivKit, illust_id: &str) -> Result<illust::UgoiraResponse> {
+    kit.retry_get_json("/v1/ugoira/metadata", &[("illust_id", illust_id)])
+        .await
+}
+
+pub async fn novel_text(kit: &PixivKit, novel_id: &str) -> Result<novel::NovelTextResponse> {
+    kit.retry_get_json("/v1/novel/text", &[("novel_id", novel_id)])
+        .await
+}
+
+/// The fields of an illust or novel telling whether it is accessible.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WorkVisibility {
+    pub visible: bool,
+    /// 0 for public, 1 for my pixiv only, and 2 for private.
+    #[serde(default)]
+    pub restrict: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct IllustDetailResponse {
+    illust: WorkVisibility,
+}
+
+#[derive(Debug, Deserialize)]
+struct NovelDetailResponse {
+    novel: WorkVisibility,
+}
+
+pub async fn illust_visibility(kit: &PixivKit, illust_id: &str) -> Result<WorkVisibility> {
+    let r: IllustDetailResponse = kit
+        .retry_get_json("/v1/illust/detail", &[("illust_id", illust_id)])
+        .await?;
+    Ok(r.illust)
+}
+
+pub async fn novel_visibility(kit: &PixivKit, novel_id: &str) -> Result<WorkVisibility> {
+    let r: NovelDetailResponse = kit
+        .retry_get_json("/v2/novel/detail", &[("novel_id", novel_id)])
+        .await?;
+    Ok(r.novel)
+}
+
+/// The fields of an illust not in the models of pixivcrab.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct IllustExtra {
+    /// 0 for unknown, 1 for not AI-generated, and 2 for AI-generated.
+    #[serde(default)]
+    pub illust_ai_type: i32,
+}
+
+impl IllustExtra {
+    pub fn is_ai_generated(&self) -> bool {
+        self.illust_ai_type == 2
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct IllustPageItem {
+    #[serde(flatten)]
+    illust: Illust,
+    #[serde(flatten)]
+    extra: IllustExtra,
+}
+
+/// A page of illusts with the fields not in the models of pixivcrab.
+#[derive(Debug, Deserialize)]
+pub struct IllustPage {
+    illusts: Vec<IllustPageItem>,
+    pub next_url: Option<String>,
+}
+
+impl IllustPage {
+    /// Split into the response of pixivcrab and the extra fields by the ids of the illusts.
+    pub fn into_parts(self) -> (illust::Response, HashMap<i64, IllustExtra>) {
+        let extras = self
+            .illusts
+            .iter()
+            .map(|i| (i.illust.id, i.extra))
+            .collect();
+        let response = illust::Response {
+            illusts: self.illusts.into_iter().map(|i| i.illust).collect(),
+            next_url: self.next_url,
+        };
+        (response, extras)
+    }
+}
+
+/// Filter the bookmarks of the pager by the bookmark tag.
+pub fn with_bookmark_tag<T>(mut pager: pixivcrab::Pager<T>, tag: &str) -> pixivcrab::Pager<T>
+where
+    T: serde::de::DeserializeOwned + pixivcrab::NextUrl + Send,
+{
+    if let Some(mut url) = pager
+        .next_url
+        .as_deref()
+        .and_then(|u| reqwest::Url::parse(u).ok())
+    {
+        url.query_pairs_mut().append_pair("tag", tag);
+        pager.next_url = Some(url.into());
+    }
+    pager
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bookmark_detail() {
+        let r: BookmarkDetailResponse = serde_json::from_str(
+            r#"{"bookmark_detail": {"is_bookmarked": true, "restrict": "private",
+                "tags": [{"name": "a", "is_registered": true},
+                         {"name": "b", "is_registered": false}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(r.bookmark_detail.registered_tags(), ["a"]);
+    }
+
+    #[test]
+    fn test_illust_page() {
+        let illust = serde_json::to_value(Illust::default()).unwrap();
+        let mut ai = illust.clone();
+        ai["id"] = 1.into();
+        ai["illust_ai_type"] = 2.into();
+        let mut unknown = illust;
+        unknown["id"] = 2.into();
+        let page: IllustPage = serde_json::from_value(serde_json::json!({
+            "illusts": [ai, unknown],
+            "next_url": "next"
+        }))
+        .unwrap();
+        let (response, extras) = page.into_parts();
+        assert_eq!(response.illusts.len(), 2);
+        assert_eq!(response.next_url.as_deref(), Some("next"));
+        assert!(extras[&1].is_ai_generated());
+        assert_eq!(extras[&2], IllustExtra::default());
+    }
+}
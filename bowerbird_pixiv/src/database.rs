@@ -1,9 +1,11 @@
+use bowerbird_core::config::UgoiraFormat;
 use bowerbird_utils::{try_skip, ImageMetadata};
 use chrono::Utc;
-use log::{info, warn};
+use futures::{stream, StreamExt};
+use log::{debug, info, warn};
 use path_slash::PathBufExt;
 use snafu::ResultExt;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 use std::{
     collections::{BTreeSet, HashMap, HashSet},
     convert::TryInto,
@@ -11,7 +13,7 @@ use std::{
     time::Duration,
 };
 
-use crate::{download::download_other_image, error};
+use crate::{api, download::download_other_image, error, job, ugoira};
 use crate::{queries::*, Result};
 
 use super::PixivKit;
@@ -89,65 +91,147 @@ async fn update_tags(
     Ok(())
 }
 
+const USER_UPDATE_BATCH_SIZE: usize = 20;
+
+/// Update the details of the users concurrently, and save them in batches.
 pub async fn update_user_id_set(
     users_need_update_set: BTreeSet<String>,
     kit: &PixivKit,
 ) -> Result<()> {
-    for user_id in users_need_update_set {
-        try_skip!(update_user_detail(&user_id, kit).await);
+    let total = users_need_update_set.len();
+    if total == 0 {
+        return Ok(());
     }
-    Ok(())
-}
-
-async fn update_user_detail(user_id: &str, kit: &PixivKit) -> Result<()> {
-    info!("updating pixiv user data: {}", user_id);
-    let resp = kit.retry_api(|api| api.user_detail(user_id)).await?;
+    info!("updating {} pixiv users", total);
+    let concurrency = kit.config.pixiv.user_update_concurrency.max(1);
+
+    let mut fetched = stream::iter(users_need_update_set)
+        .map(|user_id| async move {
+            debug!("fetching pixiv user data: {}", user_id);
+            let resp = api::user_detail(kit, &user_id).await;
+            (user_id, resp)
+        })
+        .buffer_unordered(concurrency)
+        // Not `ready_chunks`, which yields as soon as any user is fetched.
+        .chunks(USER_UPDATE_BATCH_SIZE);
+
+    let (mut updated, mut failed) = (0, 0);
+    while let Some(chunk) = fetched.next().await {
+        let mut batch = vec![];
+        for (user_id, resp) in chunk {
+            match resp {
+                Ok(resp) => batch.push((user_id, resp)),
+                Err(e) => {
+                    warn!("cannot get pixiv user {}: {}", user_id, e);
+                    failed += 1;
+                }
+            }
+        }
 
-    let user = resp.user;
-    let profile = resp.profile;
-    let mut tx = kit.db.begin().await.context(error::DatabaseTransaction)?;
+        let saved = match save_user_details(&batch, kit).await {
+            Ok(()) => batch.len(),
+            Err(e) => {
+                // Save them one by one so that a bad user does not fail the whole batch.
+                warn!(
+                    "cannot save pixiv users in batch, retrying one by one: {}",
+                    e
+                );
+                let mut saved = 0;
+                for user in &batch {
+                    try_skip!(save_user_details(std::slice::from_ref(user), kit).await);
+                    saved += 1;
+                }
+                saved
+            }
+        };
+        updated += saved;
+        job::add_users(saved as u64);
+        failed += batch.len() - saved;
 
-    let item_id = user::update_item_returning_id(user_id, &user, &profile, &mut tx).await?;
+        for (_, resp) in &batch {
+            try_skip!(download_user_images(resp, kit).await);
+        }
+        info!(
+            "updated pixiv users: {}/{} ({} failed)",
+            updated + failed,
+            total,
+            failed
+        );
+    }
+    Ok(())
+}
 
+fn user_image_urls(
+    resp: &pixivcrab::models::user::Response,
+) -> (Option<String>, Option<&str>, Option<&str>) {
     macro_rules! not_empty {
         () => {
             |x| !x.is_empty()
         };
     }
 
-    let mut workspace = resp.workspace;
-    let workspace_image_url_string = workspace
-        .remove("workspace_image_url")
+    let workspace_image_url = resp
+        .workspace
+        .get("workspace_image_url")
+        .cloned()
         .unwrap_or_default()
         .filter(not_empty!());
-    let workspace_image_url = workspace_image_url_string.as_deref();
-    let avatar_url = Some(user.profile_image_urls.medium.as_str()).filter(not_empty!());
-    let background_url = profile.background_image_url.as_deref().filter(not_empty!());
-
-    media::insert_urls(
-        [workspace_image_url, avatar_url, background_url]
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>()
-            .as_slice(),
-        &mut tx,
-    )
-    .await?;
-
-    user::update_history(
-        item_id,
-        &user,
-        &profile,
-        workspace,
-        workspace_image_url,
-        avatar_url,
-        background_url,
-        &mut tx,
-    )
-    .await?;
+    let avatar_url = Some(resp.user.profile_image_urls.medium.as_str()).filter(not_empty!());
+    let background_url = resp
+        .profile
+        .background_image_url
+        .as_deref()
+        .filter(not_empty!());
+    (workspace_image_url, avatar_url, background_url)
+}
+
+/// Save the details of the users in one transaction.
+async fn save_user_details(
+    users: &[(String, pixivcrab::models::user::Response)],
+    kit: &PixivKit,
+) -> Result<()> {
+    let mut tx = kit.db.begin().await.context(error::DatabaseTransaction)?;
+    for (user_id, resp) in users {
+        let user = &resp.user;
+        let profile = &resp.profile;
+        let item_id = user::update_item_returning_id(user_id, user, profile, &mut tx).await?;
+
+        let (workspace_image_url, avatar_url, background_url) = user_image_urls(resp);
+        let workspace_image_url = workspace_image_url.as_deref();
+        let mut workspace = resp.workspace.clone();
+        workspace.remove("workspace_image_url");
+
+        media::insert_urls(
+            [workspace_image_url, avatar_url, background_url]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .as_slice(),
+            &mut tx,
+        )
+        .await?;
 
+        user::update_history(
+            item_id,
+            user,
+            profile,
+            workspace,
+            workspace_image_url,
+            avatar_url,
+            background_url,
+            &mut tx,
+        )
+        .await?;
+    }
     tx.commit().await.context(error::DatabaseTransaction)?;
+    Ok(())
+}
 
+async fn download_user_images(
+    resp: &pixivcrab::models::user::Response,
+    kit: &PixivKit,
+) -> Result<()> {
+    let (workspace_image_url, avatar_url, background_url) = user_image_urls(resp);
     if let Some(avatar_url) = avatar_url {
         download_other_image("avatar", avatar_url, kit).await?;
     }
@@ -155,9 +239,8 @@ async fn update_user_detail(user_id: &str, kit: &PixivKit) -> Result<()> {
         download_other_image("background", background_url, kit).await?;
     }
     if let Some(workspace_image_url) = workspace_image_url {
-        download_other_image("workspace", workspace_image_url, kit).await?;
+        download_other_image("workspace", &workspace_image_url, kit).await?;
     }
-
     Ok(())
 }
 
@@ -188,36 +271,75 @@ pub async fn save_image(
 pub async fn save_image_ugoira(
     db: &PgPool,
     zip_url: String,
-    mut zip_path: PathBuf,
+    zip_path: PathBuf,
     zip_path_db: String,
     zip_size: i32,
-    with_mp4: bool,
+    outputs: &[UgoiraFormat],
 ) -> anyhow::Result<()> {
     let mut tx = db.begin().await.context(error::DatabaseTransaction)?;
     media::insert_ugoira(&zip_url, &zip_path_db, zip_size, &mut tx).await?;
 
-    if with_mp4 {
-        let mut zip_path_db_slash = PathBuf::from_slash(zip_path_db);
-        zip_path_db_slash.set_extension("mp4");
-        let mp4_path_db = zip_path_db_slash.to_slash_lossy().to_string();
+    for format in outputs {
+        let path_db = ugoira::output_path(PathBuf::from_slash(&zip_path_db), *format)
+            .to_slash_lossy()
+            .to_string();
+        let path = ugoira::output_path(&zip_path, *format);
 
-        zip_path.set_extension("mp4");
-        let mp4_path = zip_path;
-
-        let size: i32 = tokio::fs::metadata(&mp4_path)
+        let size: i32 = tokio::fs::metadata(&path)
             .await?
             .len()
             .try_into()
             .unwrap_or_default();
 
-        media::insert_ugoira_mp4(&mp4_path_db, size, &mut tx).await?;
+        media::insert_ugoira_converted(&zip_url, &path_db, format.mime(), size, &mut tx).await?;
     }
     tx.commit().await.context(error::DatabaseTransaction)?;
     Ok(())
 }
 
+/// Get the tags of the bookmark of the logged in user, if they should be synced.
+///
+/// The item is saved without its bookmark tags updated if they cannot be fetched.
+async fn fetch_bookmark_tags(
+    kind: &str,
+    source_id: &str,
+    is_bookmarked: bool,
+    bookmark_private: Option<bool>,
+    kit: &PixivKit,
+) -> Option<Vec<String>> {
+    if !is_bookmarked || bookmark_private.is_none() || !kit.config.pixiv.sync_bookmark_tags {
+        return None;
+    }
+    let detail = match kind {
+        "illust" => api::illust_bookmark_detail(kit, source_id).await,
+        _ => api::novel_bookmark_detail(kit, source_id).await,
+    };
+    match detail {
+        Ok(detail) => Some(detail.registered_tags()),
+        Err(e) => {
+            warn!("cannot get bookmark tags of {} {}: {}", kind, source_id, e);
+            None
+        }
+    }
+}
+
+async fn save_bookmark_tags(
+    kind: &str,
+    item_id: i64,
+    tags: &[String],
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<()> {
+    for name in tags {
+        tag::upsert_tag(std::slice::from_ref(name), &mut *tx).await?;
+    }
+    update_bookmark_tags(kind, item_id, tags, &mut *tx).await
+}
+
+/// `bookmark_private` is the visibility of the bookmarks if they are of the logged in user.
 pub async fn save_illusts(
     illusts: &[pixivcrab::models::illust::Illust],
+    extras: &HashMap<i64, api::IllustExtra>,
+    bookmark_private: Option<bool>,
     kit: &PixivKit,
     on_user_need_update: impl FnMut(&str),
     mut on_ugoira_metadata: impl FnMut(&str, (&str, &[i32])),
@@ -229,8 +351,20 @@ pub async fn save_illusts(
         on_user_need_update
     );
 
+    let series: HashMap<_, _> = illusts
+        .iter()
+        .filter_map(|i| i.series.as_ref())
+        .map(|s| (s.id, s))
+        .collect();
+    illust::upsert_illust_series(series.values().copied(), &kit.db).await?;
+
     for i in illusts {
         let id = i.id.to_string();
+        let bookmark_tags = if i.visible {
+            fetch_bookmark_tags("illust", &id, i.is_bookmarked, bookmark_private, kit).await
+        } else {
+            None
+        };
         let mut tx = kit.db.begin().await.context(error::DatabaseTransaction)?;
         if !i.visible {
             if i.id != 0 {
@@ -240,22 +374,33 @@ pub async fn save_illusts(
             tx.commit().await.context(error::DatabaseTransaction)?;
             continue;
         }
-        let delay = if i.r#type == "ugoira" {
-            let ugoira = kit.retry_api(|api| api.ugoira_metadata(&id)).await?;
+        let ugoira = if i.r#type == "ugoira" {
+            let ugoira = api::ugoira_metadata(kit, &id).await?;
             let delay: Vec<i32> = ugoira
                 .ugoira_metadata
                 .frames
                 .iter()
                 .map(|frame| frame.delay)
                 .collect();
-            on_ugoira_metadata(&id, (&ugoira.ugoira_metadata.zip_urls.medium, &delay));
-            Some(delay)
+            // get higher resolution images
+            let zip_url = ugoira
+                .ugoira_metadata
+                .zip_urls
+                .medium
+                .replace("600x600", "1920x1080");
+            on_ugoira_metadata(&id, (&zip_url, &delay));
+            Some((zip_url, delay))
         } else {
             None
         };
-        let delay_slice = delay.as_deref();
+        let delay_slice = ugoira.as_ref().map(|(_, delay)| delay.as_slice());
+        let zip_url = ugoira.as_ref().map(|(zip_url, _)| zip_url.as_str());
 
-        let item_id = illust::upsert_item_returning_id(i, &mut tx).await?;
+        let item_id = illust::upsert_item_returning_id(i, bookmark_private, &mut tx).await?;
+        log_bookmark_event("illust", item_id, &mut tx).await?;
+        if let Some(bookmark_tags) = &bookmark_tags {
+            save_bookmark_tags("illust", item_id, bookmark_tags, &mut tx).await?;
+        }
 
         let urls: Vec<String> = if i.page_count <= 1 {
             i.meta_single_page
@@ -272,20 +417,37 @@ pub async fn save_illusts(
         let urls_str = urls.iter().map(|x| x.as_str()).collect::<Vec<_>>();
 
         media::insert_urls(&urls_str, &mut tx).await?;
-        if let Some(history_id) =
-            illust::insert_history_returning_id(item_id, i, &urls, delay_slice, &mut tx).await?
+        if let Some(zip_url) = zip_url {
+            media::insert_urls(&[zip_url], &mut tx).await?;
+        }
+        let extra = extras.get(&i.id).copied().unwrap_or_default();
+        if let Some(history_id) = illust::insert_history_returning_id(
+            item_id,
+            i,
+            extra.illust_ai_type,
+            &urls,
+            delay_slice,
+            zip_url,
+            &mut tx,
+        )
+        .await?
         {
             illust::insert_history_media(history_id, &urls, &mut tx).await?;
         }
+        if let Some(zip_url) = zip_url {
+            illust::link_ugoira_zip(item_id, zip_url, &mut tx).await?;
+        }
 
         tx.commit().await.context(error::DatabaseTransaction)?;
     }
     Ok(())
 }
 
+/// `bookmark_private` is the visibility of the bookmarks if they are of the logged in user.
 pub async fn save_novels(
     novels: &[pixivcrab::models::novel::Novel],
     update_exists: bool,
+    bookmark_private: Option<bool>,
     kit: &PixivKit,
     mut on_each_should_continue: impl FnMut() -> bool,
     on_user_need_update: impl FnMut(&str),
@@ -309,11 +471,20 @@ pub async fn save_novels(
             return Ok(());
         }
 
-        let mut tx = kit.db.begin().await.context(error::DatabaseTransaction)?;
-
         let id = n.id.to_string();
+        let bookmark_tags = if n.visible {
+            fetch_bookmark_tags("novel", &id, n.is_bookmarked, bookmark_private, kit).await
+        } else {
+            None
+        };
 
-        let item_id = novel::upsert_item_returning_id(n, &mut tx).await?;
+        let mut tx = kit.db.begin().await.context(error::DatabaseTransaction)?;
+
+        let item_id = novel::upsert_item_returning_id(n, bookmark_private, &mut tx).await?;
+        log_bookmark_event("novel", item_id, &mut tx).await?;
+        if let Some(bookmark_tags) = &bookmark_tags {
+            save_bookmark_tags("novel", item_id, bookmark_tags, &mut tx).await?;
+        }
 
         if !n.visible {
             if n.id != 0 {
@@ -331,10 +502,25 @@ pub async fn save_novels(
         }
 
         info!("pixiv: getting novel text of {}", id);
-        let r = kit.retry_api(|api| api.novel_text(&id)).await?;
-
-        novel::insert_history(item_id, n, &r.novel_text, &mut tx).await?;
+        let r = api::novel_text(kit, &id).await?;
+
+        let cover_url = n
+            .image_urls
+            .large
+            .as_deref()
+            .or(n.image_urls.medium.as_deref())
+            .filter(|x| !x.is_empty());
+        if let Some(cover_url) = cover_url {
+            media::insert_urls(&[cover_url], &mut tx).await?;
+        }
+        novel::insert_history(item_id, n, &r.novel_text, cover_url, &mut tx).await?;
         tx.commit().await.context(error::DatabaseTransaction)?;
+
+        if let Some(cover_url) = cover_url {
+            if let Err(e) = download_other_image("novel_cover", cover_url, kit).await {
+                warn!("fail to download novel cover {}: {}", cover_url, e);
+            }
+        }
     }
 
     Ok(())
@@ -1,10 +1,11 @@
 use aria2_ws::TaskOptions;
+use bowerbird_core::config::UgoiraConfig;
 use bowerbird_utils::{
     downloader::{BoxFutureResult, Task, TaskHooks},
     get_image_metadata, try_skip,
 };
 use futures::{Future, FutureExt};
-use log::warn;
+use log::{debug, info, warn};
 
 use snafu::ResultExt;
 use sqlx::PgPool;
@@ -16,10 +17,15 @@ use std::{
 use tokio::{fs::metadata, task::spawn_blocking};
 
 use crate::{
+    api::IllustExtra,
     database::save_image,
     error,
-    queries::media,
-    utils::{filename_from_url, ugoira_to_mp4, IllustUrl},
+    filter::{self, SkipReason},
+    job,
+    path_template::IllustInfo,
+    queries::{illust, media},
+    ugoira,
+    utils::{filename_from_url, IllustUrl},
     Result,
 };
 
@@ -43,13 +49,25 @@ async fn on_success_ugoira(
     path_db: String,
     ugoira_frame_delay: Vec<i32>,
     ffmpeg_path: Option<PathBuf>,
+    ugoira_config: UgoiraConfig,
 ) -> anyhow::Result<()> {
-    let with_mp4 = ffmpeg_path.is_some();
+    let mut outputs = vec![];
     if let Some(ffmpeg_path) = ffmpeg_path {
-        let zip_path = path.clone();
-        spawn_blocking(move || ugoira_to_mp4(&ffmpeg_path, &zip_path, ugoira_frame_delay))
+        for format in ugoira_config.outputs.clone() {
+            let zip_path = path.clone();
+            let ffmpeg_path = ffmpeg_path.clone();
+            let frame_delay = ugoira_frame_delay.clone();
+            let codec = ugoira_config.codec(format).clone();
+            let converted = spawn_blocking(move || {
+                ugoira::convert(&ffmpeg_path, &zip_path, &frame_delay, format, &codec)
+            })
             .await
-            .expect("on_success_ugoira: spawn_blocking failed")?;
+            .expect("on_success_ugoira: spawn_blocking failed");
+            match converted {
+                Ok(_) => outputs.push(format),
+                Err(e) => warn!("cannot convert {:?} to {:?}: {}", path, format, e),
+            }
+        }
     }
     let zip_size: i64 = tokio::fs::metadata(&path).await?.len().try_into()?;
 
@@ -59,7 +77,7 @@ async fn on_success_ugoira(
         path,
         path_db,
         zip_size.try_into().unwrap_or_default(),
-        with_mp4,
+        &outputs,
     )
     .await?;
 
@@ -145,30 +163,22 @@ async fn on_path_exists(
 
 async fn download_illust(
     url: Option<String>,
-    user_id: &str,
-    illust_id: &str,
+    info: &IllustInfo<'_>,
     is_multi_page: bool,
     ugoira_frame_delay: Option<Vec<i32>>,
     kit: &PixivKit,
 ) -> Result<()> {
     let url = url.ok_or_else(|| {
         error::UnknownData {
-            message: format!("empty url for {}", illust_id),
+            message: format!("empty url for {}", info.illust_id),
         }
         .build()
     })?;
 
-    let parsed_url = IllustUrl::new(&url)?;
-    let date = parsed_url.date_without_slash();
-
-    let path_db = if is_multi_page {
-        let filename = parsed_url.filename;
-        format!("{user_id}/{illust_id}_{date}/{filename}")
-    } else {
-        let filename = parsed_url.filename_without_ext;
-        let ext = parsed_url.ext;
-        format!("{user_id}/{filename}_{date}.{ext}")
-    };
+    let path_db = kit
+        .task_config
+        .path_templates
+        .illust_path(info, &url, is_multi_page)?;
 
     let path = kit.task_config.parent_dir.join(&path_db);
 
@@ -181,6 +191,7 @@ async fn download_illust(
             path_db.clone(),
             ugoira_frame_delay,
             kit.task_config.ffmpeg_path.clone(),
+            kit.task_config.ugoira.clone(),
         )
         .boxed()
     } else {
@@ -218,12 +229,35 @@ fn build_task(
     task
 }
 
+/// Download the images of an illust, and the zip too if it is an ugoira.
+async fn download_illust_files(
+    info: &IllustInfo<'_>,
+    urls: Vec<Option<String>>,
+    is_multi_page: bool,
+    ugoira: Option<(String, Vec<i32>)>,
+    kit: &PixivKit,
+) -> Result<()> {
+    if let Some((zip_url, delay)) = ugoira {
+        if let Err(err) = download_illust(Some(zip_url.clone()), info, true, Some(delay), kit).await
+        {
+            warn!("fail to build task from {}: {}", zip_url, err);
+        }
+    }
+    for url in urls {
+        try_skip!(download_illust(url, info, is_multi_page, None, kit).await);
+    }
+    Ok(())
+}
+
 pub async fn download_illusts(
     illusts: &[pixivcrab::models::illust::Illust],
+    extras: &HashMap<i64, IllustExtra>,
     ugoira_map: &mut HashMap<String, (String, Vec<i32>)>,
     mut on_each_should_continue: impl FnMut() -> bool,
     kit: &PixivKit,
 ) -> Result<()> {
+    let mut to_download = vec![];
+    let mut skipped_list = vec![];
     for i in illusts {
         if !on_each_should_continue() {
             break;
@@ -233,54 +267,79 @@ pub async fn download_illusts(
             continue;
         }
         let illust_id = i.id.to_string();
-        let is_ugoira = i.r#type == "ugoira";
-
-        if is_ugoira {
-            if let Some((zip_url, delay)) = ugoira_map.remove(&illust_id) {
-                let zip_url = zip_url.replace("600x600", "1920x1080");
-                if let Err(err) = download_illust(
-                    // get higher resolution images
-                    Some(zip_url.clone()),
-                    &i.user.id.to_string(),
-                    &illust_id,
-                    true,
-                    Some(delay),
-                    kit,
-                )
-                .await
-                {
-                    warn!("fail to build task from {}: {}", zip_url, err);
-                }
-            }
+        let ugoira = ugoira_map.remove(&illust_id);
+        let extra = extras.get(&i.id).copied().unwrap_or_default();
+        let skipped = filter::skip_reason(&kit.task_config.download_filter, i, extra);
+        match skipped {
+            Some(reason) => debug!("skip downloading illust {}: {}", illust_id, reason.as_str()),
+            None => to_download.push((i, illust_id.clone(), ugoira)),
         }
+        skipped_list.push((illust_id, skipped.map(SkipReason::as_str)));
+    }
+    // The skip reasons of the page are saved at once.
+    illust::set_download_skipped(&skipped_list, &kit.db).await?;
 
-        if i.page_count == 1 {
-            try_skip!(
-                download_illust(
-                    i.meta_single_page.original_image_url.clone(),
-                    &i.user.id.to_string(),
-                    &illust_id,
-                    is_ugoira,
-                    None,
-                    kit,
-                )
-                .await
-            );
+    for (i, illust_id, ugoira) in to_download {
+        let is_ugoira = i.r#type == "ugoira";
+        let urls = if i.page_count == 1 {
+            vec![i.meta_single_page.original_image_url.clone()]
         } else {
-            for img in &i.meta_pages {
-                try_skip!(
-                    download_illust(
-                        img.image_urls.original.clone(),
-                        &i.user.id.to_string(),
-                        &illust_id,
-                        true,
-                        None,
-                        kit,
-                    )
-                    .await
-                );
-            }
-        }
+            i.meta_pages
+                .iter()
+                .map(|img| img.image_urls.original.clone())
+                .collect()
+        };
+        let user_id = i.user.id.to_string();
+        let info = IllustInfo {
+            user_id: &user_id,
+            user_name: &i.user.name,
+            illust_id: &illust_id,
+            title: &i.title,
+            illust_type: &i.r#type,
+        };
+        download_illust_files(
+            &info,
+            urls,
+            i.page_count != 1 || is_ugoira,
+            ugoira.filter(|_| is_ugoira),
+            kit,
+        )
+        .await?;
     }
     Ok(())
 }
+
+/// Download the files of the saved illusts by their ids,
+/// or of all the illusts skipped by the download filters if `ids` is `None`.
+///
+/// The download filters are not applied, and the skip reasons are cleared.
+/// Return the number of the illusts found.
+pub async fn download_saved_illusts(
+    ids: Option<&[i64]>,
+    limit: Option<u32>,
+    kit: &PixivKit,
+) -> Result<usize> {
+    let rows = illust::download_rows(ids, limit.map(i64::from), &kit.db).await?;
+    let count = rows.len();
+    info!("downloading {} saved illusts", count);
+    for row in rows {
+        let is_ugoira = row.illust_type.as_deref() == Some("ugoira");
+        let urls = row.image_urls.unwrap_or_default();
+        let is_multi_page = urls.len() != 1 || is_ugoira;
+        let ugoira = row
+            .ugoira_zip_url
+            .filter(|_| is_ugoira)
+            .map(|url| (url, row.ugoira_frame_duration.unwrap_or_default()));
+        let info = IllustInfo {
+            user_id: &row.user_source_id,
+            user_name: row.user_name.as_deref().unwrap_or_default(),
+            illust_id: &row.source_id,
+            title: row.title.as_deref().unwrap_or_default(),
+            illust_type: row.illust_type.as_deref().unwrap_or_default(),
+        };
+        download_illust_files(&info, urls, is_multi_page, ugoira, kit).await?;
+        illust::set_download_skipped(&[(row.source_id.clone(), None)], &kit.db).await?;
+        job::add_items(1);
+    }
+    Ok(count)
+}
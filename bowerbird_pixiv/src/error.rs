@@ -28,4 +28,19 @@ pub enum Error {
     Utils {
         source: bowerbird_utils::error::Error,
     },
+
+    #[snafu(display("invalid argument: {message}"))]
+    InvalidArgument { message: String },
+
+    #[snafu(display("not found: {message}"))]
+    NotFound { message: String },
+
+    #[snafu(display("{message}: {source}"))]
+    Io {
+        source: std::io::Error,
+        message: String,
+    },
+
+    #[snafu(display("zip: {source}"))]
+    Zip { source: zip::result::ZipError },
 }
@@ -0,0 +1,270 @@
+use chrono::{DateTime, Datelike, Utc};
+use snafu::ResultExt;
+use sqlx::PgPool;
+use std::{
+    collections::BTreeSet,
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+};
+use tokio::task::spawn_blocking;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use super::strip_tags;
+use crate::{error, novel_markup::escape_html, queries::illust::ExportRow, Result};
+
+/// A work packed into the archive.
+#[derive(Debug, Clone, Default)]
+pub struct ComicWork {
+    pub title: String,
+    pub source_id: String,
+    pub caption: Option<String>,
+    pub date: Option<DateTime<Utc>>,
+    pub pages: Vec<PathBuf>,
+}
+
+/// Metadata and works of a CBZ archive.
+#[derive(Debug, Clone, Default)]
+pub struct Comic {
+    pub title: String,
+    pub series: Option<String>,
+    pub author: Option<String>,
+    pub tags: Vec<String>,
+    pub works: Vec<ComicWork>,
+}
+
+fn is_image(path: &Path) -> bool {
+    matches!(
+        mime_guess::from_path(path).first(),
+        Some(m) if m.type_() == mime_guess::mime::IMAGE
+    )
+}
+
+fn push_element(out: &mut String, name: &str, value: impl AsRef<str>) {
+    let value = value.as_ref();
+    if !value.is_empty() {
+        out.push_str(&format!("  <{name}>{}</{name}>\n", escape_html(value)));
+    }
+}
+
+/// Build the ComicInfo.xml with the first page of each work as a bookmark.
+fn comic_info(comic: &Comic, bookmarks: &[(usize, &str)], page_count: usize) -> String {
+    let mut out = String::from(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<ComicInfo xmlns:xsd="http://www.w3.org/2001/XMLSchema" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+"#,
+    );
+    push_element(&mut out, "Title", &comic.title);
+    if let Some(series) = &comic.series {
+        push_element(&mut out, "Series", series);
+    }
+    if let [work] = comic.works.as_slice() {
+        if let Some(caption) = &work.caption {
+            push_element(&mut out, "Summary", strip_tags(caption).trim());
+        }
+        push_element(
+            &mut out,
+            "Web",
+            format!("https://www.pixiv.net/artworks/{}", work.source_id),
+        );
+    }
+    if let Some(date) = comic.works.iter().find_map(|w| w.date) {
+        push_element(&mut out, "Year", date.year().to_string());
+        push_element(&mut out, "Month", date.month().to_string());
+        push_element(&mut out, "Day", date.day().to_string());
+    }
+    if let Some(author) = &comic.author {
+        push_element(&mut out, "Writer", author);
+    }
+    push_element(&mut out, "Tags", comic.tags.join(","));
+    push_element(&mut out, "PageCount", page_count.to_string());
+    push_element(&mut out, "Manga", "Yes");
+    if comic.works.len() > 1 {
+        out.push_str("  <Pages>\n");
+        for (image, title) in bookmarks {
+            out.push_str(&format!(
+                "    <Page Image=\"{image}\" Bookmark=\"{}\" />\n",
+                escape_html(title)
+            ));
+        }
+        out.push_str("  </Pages>\n");
+    }
+    out.push_str("</ComicInfo>\n");
+    out
+}
+
+/// Build the CBZ file in memory.
+///
+/// Pages are stored uncompressed in the order of the works, named by their index
+/// so that readers sort them correctly.
+/// Pages which are not images or cannot be read are skipped.
+pub fn build(comic: &Comic) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let total: usize = comic.works.iter().map(|w| w.pages.len()).sum();
+    let width = total.to_string().len().max(3);
+    let mut index = 0;
+    let mut bookmarks = vec![];
+    for work in &comic.works {
+        let mut first = true;
+        for path in work.pages.iter().filter(|p| is_image(p)) {
+            let Ok(data) = std::fs::read(path) else {
+                log::warn!("skip unreadable page {:?}", path);
+                continue;
+            };
+            if first {
+                bookmarks.push((index, work.title.as_str()));
+                first = false;
+            }
+            let ext = path
+                .extension()
+                .map(|e| e.to_string_lossy().to_ascii_lowercase())
+                .unwrap_or_default();
+            let name = format!("{index:0width$}.{ext}");
+            zip.start_file(&name, stored).context(error::Zip)?;
+            zip.write_all(&data).context(error::Io {
+                message: format!("write {name} to cbz"),
+            })?;
+            index += 1;
+        }
+    }
+
+    zip.start_file("ComicInfo.xml", deflated)
+        .context(error::Zip)?;
+    zip.write_all(comic_info(comic, &bookmarks, index).as_bytes())
+        .context(error::Io {
+            message: "write ComicInfo.xml to cbz",
+        })?;
+
+    Ok(zip.finish().context(error::Zip)?.into_inner())
+}
+
+async fn build_from_rows(
+    storage_dir: &Path,
+    mut comic: Comic,
+    rows: Vec<ExportRow>,
+) -> Result<Vec<u8>> {
+    let mut tags = BTreeSet::new();
+    for row in rows {
+        for tag in row.tags.iter().flatten() {
+            if tags.insert(tag.clone()) {
+                comic.tags.push(tag.clone());
+            }
+        }
+        comic.works.push(ComicWork {
+            title: row.title.unwrap_or_default(),
+            source_id: row.source_id.unwrap_or_default(),
+            caption: row.caption_html.filter(|c| !c.is_empty()),
+            date: row.date,
+            pages: row
+                .image_paths
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|p| storage_dir.join(p))
+                .collect(),
+        });
+    }
+
+    spawn_blocking(move || build(&comic))
+        .await
+        .expect("build_from_rows: spawn_blocking failed")
+}
+
+/// Export the pages of the illust as a CBZ.
+pub async fn illust_cbz(db: &PgPool, storage_dir: &Path, id: i64) -> Result<Vec<u8>> {
+    let row = crate::queries::illust::export_by_id(id, db)
+        .await?
+        .ok_or_else(|| {
+            error::NotFound {
+                message: format!("illust {id}"),
+            }
+            .build()
+        })?;
+    let comic = Comic {
+        title: row.title.clone().unwrap_or_default(),
+        series: row.series_title.clone(),
+        author: row.author_name.clone(),
+        ..Default::default()
+    };
+    build_from_rows(storage_dir, comic, vec![row]).await
+}
+
+/// Export all saved illusts in the series as a CBZ, ordered by date.
+pub async fn illust_series_cbz(db: &PgPool, storage_dir: &Path, series_id: i64) -> Result<Vec<u8>> {
+    let rows = crate::queries::illust::export_by_series_id(series_id, db).await?;
+    let first = rows.first().ok_or_else(|| {
+        error::NotFound {
+            message: format!("illusts of series {series_id}"),
+        }
+        .build()
+    })?;
+    let comic = Comic {
+        title: first.series_title.clone().unwrap_or_default(),
+        series: first.series_title.clone(),
+        author: first.author_name.clone(),
+        ..Default::default()
+    };
+    build_from_rows(storage_dir, comic, rows).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use zip::ZipArchive;
+
+    use super::*;
+
+    #[test]
+    fn test_build_cbz() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        let page = |name: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, name).unwrap();
+            path
+        };
+        let comic = Comic {
+            title: "Series".to_string(),
+            series: Some("Series".to_string()),
+            author: Some("Author".to_string()),
+            tags: vec!["a".to_string(), "b&c".to_string()],
+            works: vec![
+                ComicWork {
+                    title: "One".to_string(),
+                    source_id: "1".to_string(),
+                    date: Some("2022-03-04T00:00:00Z".parse().unwrap()),
+                    pages: vec![page("1_p0.png"), page("1_p1.jpg"), dir.join("missing.png")],
+                    ..Default::default()
+                },
+                ComicWork {
+                    title: "Two".to_string(),
+                    source_id: "2".to_string(),
+                    pages: vec![page("2.zip"), page("2_p0.PNG")],
+                    ..Default::default()
+                },
+            ],
+        };
+        let data = build(&comic).unwrap();
+
+        let mut zip = ZipArchive::new(Cursor::new(data)).unwrap();
+        let names: Vec<_> = (0..zip.len())
+            .map(|i| zip.by_index(i).unwrap().name().to_string())
+            .collect();
+        assert_eq!(names, ["000.png", "001.jpg", "002.png", "ComicInfo.xml"]);
+
+        let mut info = String::new();
+        zip.by_name("ComicInfo.xml")
+            .unwrap()
+            .read_to_string(&mut info)
+            .unwrap();
+        assert!(info.contains("<Series>Series</Series>"));
+        assert!(info.contains("<Year>2022</Year>"));
+        assert!(info.contains("<Tags>a,b&amp;c</Tags>"));
+        assert!(info.contains("<PageCount>3</PageCount>"));
+        assert!(info.contains("<Page Image=\"2\" Bookmark=\"Two\" />"));
+        assert!(!info.contains("<Web>"));
+    }
+}
@@ -0,0 +1,589 @@
+use chrono::{DateTime, Utc};
+use snafu::ResultExt;
+use sqlx::PgPool;
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+};
+use tokio::task::spawn_blocking;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use super::strip_tags;
+use crate::{
+    error,
+    novel_markup::{self, escape_html, Document, HtmlResolver, ImageRef, Node},
+    queries::{illust, novel::ExportRow},
+    Result,
+};
+
+/// Novels on pixiv are mostly in Japanese and the language is not provided by the api.
+const LANGUAGE: &str = "ja";
+
+const STYLE: &str = "\
+body { line-height: 1.8; }
+h1, h2 { text-align: center; }
+img.novel-image { display: block; max-width: 100%; margin: 1em auto; }
+div.page-break { page-break-before: always; break-before: page; }
+";
+
+/// A novel written into the book.
+#[derive(Debug, Clone)]
+pub struct BookNovel {
+    pub title: String,
+    pub doc: Document,
+}
+
+/// Metadata and contents of an EPUB book.
+#[derive(Debug, Clone, Default)]
+pub struct Book {
+    pub identifier: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub date: Option<DateTime<Utc>>,
+    pub source: Option<String>,
+    pub tags: Vec<String>,
+    pub cover: Option<PathBuf>,
+    pub novels: Vec<BookNovel>,
+    /// Local files of the images referenced in the novels.
+    pub images: HashMap<ImageRef, PathBuf>,
+}
+
+enum Item<'a> {
+    PageStart(u32),
+    Node(&'a Node),
+}
+
+/// A part of a novel written into a single XHTML file.
+struct Section<'a> {
+    title: String,
+    /// Started by a `[chapter:]`.
+    is_chapter: bool,
+    items: Vec<Item<'a>>,
+}
+
+fn start_section(sections: &mut Vec<Section>, title: &[Node]) {
+    let title = novel_markup::title_text(title);
+    match sections.last_mut() {
+        // Reuse the last section if nothing is written to it yet,
+        // e.g. the novel begins with a chapter.
+        Some(s) if !s.items.iter().any(|i| matches!(i, Item::Node(_))) => {
+            s.title = title;
+            s.is_chapter = true;
+        }
+        _ => sections.push(Section {
+            title,
+            is_chapter: true,
+            items: vec![],
+        }),
+    }
+}
+
+/// Split the novel into sections at the chapters.
+/// Returns the sections and the index of the section containing each page.
+fn split_sections(novel: &BookNovel) -> (Vec<Section<'_>>, Vec<usize>) {
+    let mut sections = vec![Section {
+        title: novel.title.clone(),
+        is_chapter: false,
+        items: vec![],
+    }];
+    let mut page_sections = vec![];
+    for (i, page) in novel.doc.pages.iter().enumerate() {
+        // Start the chapter before the page anchor so that jumps land on the chapter.
+        if let Some(Node::Chapter(title)) = page.nodes.first() {
+            start_section(&mut sections, title);
+        }
+        page_sections.push(sections.len() - 1);
+        sections
+            .last_mut()
+            .unwrap()
+            .items
+            .push(Item::PageStart(i as u32 + 1));
+        for (j, node) in page.nodes.iter().enumerate() {
+            match node {
+                Node::Chapter(title) if j > 0 => start_section(&mut sections, title),
+                _ => (),
+            }
+            sections.last_mut().unwrap().items.push(Item::Node(node));
+        }
+    }
+    (sections, page_sections)
+}
+
+struct SectionResolver<'a> {
+    page_files: &'a [String],
+    images: &'a HashMap<ImageRef, String>,
+}
+
+impl HtmlResolver for SectionResolver<'_> {
+    fn image_src(&self, image: &ImageRef) -> Option<String> {
+        self.images.get(image).map(|href| format!("../{href}"))
+    }
+
+    fn page_href(&self, page: u32) -> String {
+        match (page as usize)
+            .checked_sub(1)
+            .and_then(|i| self.page_files.get(i))
+        {
+            Some(file) => format!("{file}#page-{page}"),
+            None => format!("#page-{page}"),
+        }
+    }
+}
+
+struct NavPoint {
+    title: String,
+    href: String,
+    children: Vec<NavPoint>,
+}
+
+fn xhtml(title: &str, head: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<!DOCTYPE html>
+<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{LANGUAGE}\" lang=\"{LANGUAGE}\">
+<head>
+<meta charset=\"utf-8\" />
+<title>{}</title>
+{head}</head>
+<body>
+{body}
+</body>
+</html>
+",
+        escape_html(title)
+    )
+}
+
+fn nav_list(points: &[NavPoint], out: &mut String) {
+    out.push_str("<ol>\n");
+    for p in points {
+        out.push_str(&format!(
+            "<li><a href=\"{}\">{}</a>",
+            escape_html(&p.href),
+            escape_html(&p.title)
+        ));
+        if !p.children.is_empty() {
+            out.push('\n');
+            nav_list(&p.children, out);
+        }
+        out.push_str("</li>\n");
+    }
+    out.push_str("</ol>\n");
+}
+
+fn ncx_points(
+    points: &[NavPoint],
+    play_order: &HashMap<String, usize>,
+    id: &mut usize,
+    out: &mut String,
+) {
+    for p in points {
+        *id += 1;
+        let href = p.href.split('#').next().unwrap_or_default();
+        out.push_str(&format!(
+            "<navPoint id=\"nav-{}\" playOrder=\"{}\"><navLabel><text>{}</text></navLabel><content src=\"{}\" />\n",
+            id,
+            play_order.get(href).copied().unwrap_or_default(),
+            escape_html(&p.title),
+            escape_html(&p.href)
+        ));
+        ncx_points(&p.children, play_order, id, out);
+        out.push_str("</navPoint>\n");
+    }
+}
+
+fn w3c_date(date: &DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn image_extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_else(|| "jpg".to_string())
+}
+
+fn media_type(href: &str) -> String {
+    mime_guess::from_path(href)
+        .first_or_octet_stream()
+        .to_string()
+}
+
+type Zip = ZipWriter<Cursor<Vec<u8>>>;
+
+fn add_file(zip: &mut Zip, name: &str, data: &[u8], options: FileOptions) -> Result<()> {
+    zip.start_file(name, options).context(error::Zip)?;
+    zip.write_all(data).context(error::Io {
+        message: format!("write {name} to epub"),
+    })
+}
+
+/// Build the EPUB 3 file in memory.
+///
+/// Each novel is split into XHTML files at the chapters, and the table of contents
+/// lists the novels with their chapters.
+/// Images and the cover which cannot be read are skipped.
+pub fn build(book: &Book) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // The mimetype must be the first file and not compressed.
+    add_file(&mut zip, "mimetype", b"application/epub+zip", stored)?;
+    add_file(
+        &mut zip,
+        "META-INF/container.xml",
+        br#"<?xml version="1.0" encoding="utf-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles>
+<rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml" />
+</rootfiles>
+</container>
+"#,
+        deflated,
+    )?;
+    add_file(&mut zip, "OEBPS/style.css", STYLE.as_bytes(), deflated)?;
+
+    // (id, href, properties)
+    let mut manifest: Vec<(String, String, Option<&str>)> = vec![];
+
+    let cover = book
+        .cover
+        .as_ref()
+        .and_then(|p| Some((std::fs::read(p).ok()?, image_extension(p))));
+    if let Some((data, ext)) = cover {
+        let href = format!("images/cover.{ext}");
+        add_file(&mut zip, &format!("OEBPS/{href}"), &data, stored)?;
+        manifest.push(("cover-image".to_string(), href, Some("cover-image")));
+    }
+
+    let mut image_hrefs = HashMap::new();
+    for (image, path) in &book.images {
+        let Ok(data) = std::fs::read(path) else {
+            continue;
+        };
+        let name = match image {
+            ImageRef::Pixiv { illust_id, page } => format!("pixiv_{illust_id}_p{page}"),
+            ImageRef::Uploaded { id } => format!("uploaded_{id}"),
+        };
+        let href = format!("images/{name}.{}", image_extension(path));
+        add_file(&mut zip, &format!("OEBPS/{href}"), &data, stored)?;
+        manifest.push((format!("image-{name}"), href.clone(), None));
+        image_hrefs.insert(image.clone(), href);
+    }
+
+    let mut spine = vec![];
+    let mut nav = vec![];
+    let mut chapter_index = 0;
+    for (ni, novel) in book.novels.iter().enumerate() {
+        let (sections, page_sections) = split_sections(novel);
+        let files: Vec<String> = (0..sections.len())
+            .map(|si| format!("n{}-{}.xhtml", ni + 1, si + 1))
+            .collect();
+        let page_files: Vec<String> = page_sections.iter().map(|&i| files[i].clone()).collect();
+        let resolver = SectionResolver {
+            page_files: &page_files,
+            images: &image_hrefs,
+        };
+
+        let mut chapters = vec![];
+        for (si, section) in sections.iter().enumerate() {
+            let mut body = String::new();
+            if si == 0 {
+                body.push_str(&format!("<h1>{}</h1>\n", escape_html(&novel.title)));
+            }
+            for item in &section.items {
+                match item {
+                    Item::PageStart(page) => body.push_str(&format!(
+                        "<div class=\"{}\" id=\"page-{page}\"></div>\n",
+                        if *page > 1 { "page page-break" } else { "page" }
+                    )),
+                    Item::Node(node) => novel_markup::render_html_nodes(
+                        std::slice::from_ref(*node),
+                        &mut chapter_index,
+                        &resolver,
+                        &mut body,
+                    ),
+                }
+            }
+            let head = "<link rel=\"stylesheet\" type=\"text/css\" href=\"../style.css\" />\n";
+            let href = format!("text/{}", files[si]);
+            add_file(
+                &mut zip,
+                &format!("OEBPS/{href}"),
+                xhtml(&section.title, head, &body).as_bytes(),
+                deflated,
+            )?;
+            let id = format!("text-{}", files[si].trim_end_matches(".xhtml"));
+            manifest.push((id.clone(), href.clone(), None));
+            spine.push((id, href.clone()));
+            if section.is_chapter {
+                chapters.push(NavPoint {
+                    title: section.title.clone(),
+                    href,
+                    children: vec![],
+                });
+            }
+        }
+        nav.push(NavPoint {
+            title: novel.title.clone(),
+            href: format!("text/{}", files[0]),
+            children: chapters,
+        });
+    }
+
+    let mut nav_body = String::from("<nav epub:type=\"toc\" id=\"toc\">\n<h1>Contents</h1>\n");
+    nav_list(&nav, &mut nav_body);
+    nav_body.push_str("</nav>");
+    add_file(
+        &mut zip,
+        "OEBPS/nav.xhtml",
+        xhtml(&book.title, "", &nav_body).as_bytes(),
+        deflated,
+    )?;
+
+    let play_order: HashMap<String, usize> = spine
+        .iter()
+        .enumerate()
+        .map(|(i, (_, href))| (href.clone(), i + 1))
+        .collect();
+    let mut ncx = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<ncx xmlns=\"http://www.daisy.org/z3986/2005/ncx/\" version=\"2005-1\">
+<head>
+<meta name=\"dtb:uid\" content=\"{}\" />
+</head>
+<docTitle><text>{}</text></docTitle>
+<navMap>
+",
+        escape_html(&book.identifier),
+        escape_html(&book.title)
+    );
+    ncx_points(&nav, &play_order, &mut 0, &mut ncx);
+    ncx.push_str("</navMap>\n</ncx>\n");
+    add_file(&mut zip, "OEBPS/toc.ncx", ncx.as_bytes(), deflated)?;
+
+    let mut metadata = format!(
+        "<dc:identifier id=\"book-id\">{}</dc:identifier>
+<dc:title>{}</dc:title>
+<dc:language>{LANGUAGE}</dc:language>
+<meta property=\"dcterms:modified\">{}</meta>
+",
+        escape_html(&book.identifier),
+        escape_html(&book.title),
+        w3c_date(&Utc::now())
+    );
+    if let Some(author) = &book.author {
+        metadata.push_str(&format!(
+            "<dc:creator>{}</dc:creator>\n",
+            escape_html(author)
+        ));
+    }
+    if let Some(date) = &book.date {
+        metadata.push_str(&format!("<dc:date>{}</dc:date>\n", w3c_date(date)));
+    }
+    if let Some(source) = &book.source {
+        metadata.push_str(&format!("<dc:source>{}</dc:source>\n", escape_html(source)));
+    }
+    if let Some(description) = &book.description {
+        metadata.push_str(&format!(
+            "<dc:description>{}</dc:description>\n",
+            escape_html(&strip_tags(description))
+        ));
+    }
+    for tag in &book.tags {
+        metadata.push_str(&format!("<dc:subject>{}</dc:subject>\n", escape_html(tag)));
+    }
+    if manifest.iter().any(|(id, ..)| id == "cover-image") {
+        // For EPUB 2 readers.
+        metadata.push_str("<meta name=\"cover\" content=\"cover-image\" />\n");
+    }
+
+    let mut opf = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"book-id\" xml:lang=\"{LANGUAGE}\">
+<metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">
+{metadata}</metadata>
+<manifest>
+<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\" />
+<item id=\"ncx\" href=\"toc.ncx\" media-type=\"application/x-dtbncx+xml\" />
+<item id=\"style\" href=\"style.css\" media-type=\"text/css\" />
+"
+    );
+    for (id, href, properties) in &manifest {
+        opf.push_str(&format!(
+            "<item id=\"{}\" href=\"{}\" media-type=\"{}\"{} />\n",
+            escape_html(id),
+            escape_html(href),
+            media_type(href),
+            properties
+                .map(|p| format!(" properties=\"{p}\""))
+                .unwrap_or_default()
+        ));
+    }
+    opf.push_str("</manifest>\n<spine toc=\"ncx\">\n");
+    for (id, _) in &spine {
+        opf.push_str(&format!("<itemref idref=\"{}\" />\n", escape_html(id)));
+    }
+    opf.push_str("</spine>\n</package>\n");
+    add_file(&mut zip, "OEBPS/content.opf", opf.as_bytes(), deflated)?;
+
+    Ok(zip.finish().context(error::Zip)?.into_inner())
+}
+
+/// Parse the novels, resolve the images and the cover from the storage, and build the book.
+async fn build_from_rows(
+    db: &PgPool,
+    storage_dir: &Path,
+    mut book: Book,
+    rows: Vec<ExportRow>,
+) -> Result<Vec<u8>> {
+    let mut tags = BTreeSet::new();
+    for row in &rows {
+        book.novels.push(BookNovel {
+            title: row.title.clone().unwrap_or_default(),
+            doc: novel_markup::parse(row.text.as_deref().unwrap_or_default()),
+        });
+        for tag in row.tags.iter().flatten() {
+            if tags.insert(tag.clone()) {
+                book.tags.push(tag.clone());
+            }
+        }
+    }
+    book.cover = rows
+        .iter()
+        .find_map(|r| r.cover_path.as_ref())
+        .map(|p| storage_dir.join(p));
+
+    let illust_ids: Vec<String> = book
+        .novels
+        .iter()
+        .flat_map(|n| n.doc.images())
+        .filter_map(|i| match i {
+            ImageRef::Pixiv { illust_id, .. } => Some(illust_id.clone()),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let paths = illust::paths_by_source_ids(&illust_ids, db).await?;
+    book.images = book
+        .novels
+        .iter()
+        .flat_map(|n| n.doc.images())
+        .filter_map(|i| match i {
+            ImageRef::Pixiv { illust_id, page } => {
+                let path = paths
+                    .get(illust_id)?
+                    .get((*page as usize).checked_sub(1)?)?
+                    .as_ref()?;
+                Some((i.clone(), storage_dir.join(path)))
+            }
+            // Uploaded images are not saved.
+            ImageRef::Uploaded { .. } => None,
+        })
+        .collect();
+
+    spawn_blocking(move || build(&book))
+        .await
+        .expect("build_from_rows: spawn_blocking failed")
+}
+
+/// Export the novel as an EPUB.
+pub async fn novel_epub(db: &PgPool, storage_dir: &Path, id: i64) -> Result<Vec<u8>> {
+    let row = crate::queries::novel::export_by_id(id, db)
+        .await?
+        .ok_or_else(|| {
+            error::NotFound {
+                message: format!("novel {id}"),
+            }
+            .build()
+        })?;
+    let source_id = row.source_id.clone().unwrap_or_default();
+    let book = Book {
+        identifier: format!("urn:bowerbird:pixiv:novel:{source_id}"),
+        title: row.title.clone().unwrap_or_default(),
+        author: row.author_name.clone(),
+        description: row.caption_html.clone().filter(|c| !c.is_empty()),
+        date: row.date,
+        source: Some(format!(
+            "https://www.pixiv.net/novel/show.php?id={source_id}"
+        )),
+        ..Default::default()
+    };
+    build_from_rows(db, storage_dir, book, vec![row]).await
+}
+
+/// Export all saved novels in the series as an EPUB, ordered by date.
+pub async fn novel_series_epub(db: &PgPool, storage_dir: &Path, series_id: i64) -> Result<Vec<u8>> {
+    let rows = crate::queries::novel::export_by_series_id(series_id, db).await?;
+    let first = rows.first().ok_or_else(|| {
+        error::NotFound {
+            message: format!("novels of series {series_id}"),
+        }
+        .build()
+    })?;
+    let book = Book {
+        identifier: format!("urn:bowerbird:pixiv:novel-series:{series_id}"),
+        title: first.series_title.clone().unwrap_or_default(),
+        author: first.author_name.clone(),
+        date: first.date,
+        ..Default::default()
+    };
+    build_from_rows(db, storage_dir, book, rows).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn read_entry(zip: &mut zip::ZipArchive<Cursor<Vec<u8>>>, name: &str) -> String {
+        let mut s = String::new();
+        zip.by_name(name).unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn test_build_epub() {
+        let book = Book {
+            identifier: "urn:test:1".to_string(),
+            title: "Book & Title".to_string(),
+            author: Some("Author".to_string()),
+            tags: vec!["tag1".to_string()],
+            novels: vec![BookNovel {
+                title: "Novel".to_string(),
+                doc: novel_markup::parse(
+                    "prologue [jump:2]\n[newpage]\n[chapter:One]\n[[rb:漢字 > かんじ]]\n[chapter:Two]\ntext",
+                ),
+            }],
+            ..Default::default()
+        };
+        let data = build(&book).unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(data)).unwrap();
+
+        let mimetype = zip.by_index(0).unwrap();
+        assert_eq!(mimetype.name(), "mimetype");
+        assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+        drop(mimetype);
+
+        let opf = read_entry(&mut zip, "OEBPS/content.opf");
+        assert!(opf.contains("<dc:title>Book &amp; Title</dc:title>"));
+        assert!(opf.contains("<dc:creator>Author</dc:creator>"));
+        assert!(opf.contains("<dc:subject>tag1</dc:subject>"));
+        assert!(opf.contains("<itemref idref=\"text-n1-3\" />"));
+
+        let nav = read_entry(&mut zip, "OEBPS/nav.xhtml");
+        assert!(nav.contains("<a href=\"text/n1-2.xhtml\">One</a>"));
+        assert!(nav.contains("<a href=\"text/n1-3.xhtml\">Two</a>"));
+
+        let first = read_entry(&mut zip, "OEBPS/text/n1-1.xhtml");
+        assert!(first.contains("<h1>Novel</h1>"));
+        assert!(first.contains("href=\"n1-2.xhtml#page-2\""));
+
+        let second = read_entry(&mut zip, "OEBPS/text/n1-2.xhtml");
+        assert!(second.contains("id=\"page-2\""));
+        assert!(second.contains("<ruby>漢字<rp>(</rp><rt>かんじ</rt><rp>)</rp></ruby>"));
+    }
+}
@@ -0,0 +1,106 @@
+//! Export saved works for other readers.
+
+use sqlx::PgPool;
+use std::collections::HashMap;
+
+use crate::{
+    error,
+    queries::{illust, novel},
+    Result,
+};
+
+pub mod cbz;
+pub mod epub;
+
+pub use cbz::{illust_cbz, illust_series_cbz};
+pub use epub::{novel_epub, novel_series_epub};
+
+/// Get the id of the novel in database by its pixiv id.
+pub async fn novel_id_by_source_id(db: &PgPool, source_id: &str) -> Result<i64> {
+    novel::id_by_source_id(source_id, db).await?.ok_or_else(|| {
+        error::NotFound {
+            message: format!("pixiv novel {source_id}"),
+        }
+        .build()
+    })
+}
+
+/// Get the id of the novel series in database by its pixiv id.
+pub async fn novel_series_id_by_source_id(db: &PgPool, source_id: &str) -> Result<i64> {
+    novel::series_id_by_source_id(source_id, db)
+        .await?
+        .ok_or_else(|| {
+            error::NotFound {
+                message: format!("pixiv novel series {source_id}"),
+            }
+            .build()
+        })
+}
+
+/// Get the id of the illust in database by its pixiv id.
+pub async fn illust_id_by_source_id(db: &PgPool, source_id: &str) -> Result<i64> {
+    illust::id_by_source_id(source_id, db)
+        .await?
+        .ok_or_else(|| {
+            error::NotFound {
+                message: format!("pixiv illust {source_id}"),
+            }
+            .build()
+        })
+}
+
+/// Get the id of the illust series in database by its pixiv id.
+pub async fn illust_series_id_by_source_id(db: &PgPool, source_id: &str) -> Result<i64> {
+    illust::series_id_by_source_id(source_id, db)
+        .await?
+        .ok_or_else(|| {
+            error::NotFound {
+                message: format!("pixiv illust series {source_id}"),
+            }
+            .build()
+        })
+}
+
+/// Get the local paths of the pages of the illusts by their pixiv ids.
+pub async fn illust_paths_by_source_ids(
+    db: &PgPool,
+    source_ids: &[String],
+) -> Result<HashMap<String, Vec<Option<String>>>> {
+    illust::paths_by_source_ids(source_ids, db).await
+}
+
+/// Convert the caption in HTML to plain text.
+pub(crate) fn strip_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        if rest[start + 1..].starts_with("br") {
+            out.push('\n');
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_tags() {
+        assert_eq!(
+            strip_tags("a<br />b &amp; <a href=\"x\">link</a>"),
+            "a\nb & link"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use bowerbird_core::config::DownloadFilter;
+use pixivcrab::models::illust::Illust;
+
+use crate::{api::IllustExtra, queries::flatten_alias};
+
+/// Why the download of an illust is skipped by the [`DownloadFilter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    AiGenerated,
+    Muted,
+    Bookmarks,
+    Type,
+    Pages,
+    Resolution,
+    Tag,
+    XRestrict,
+    Date,
+}
+
+impl SkipReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::AiGenerated => "ai_generated",
+            SkipReason::Muted => "muted",
+            SkipReason::Bookmarks => "bookmarks",
+            SkipReason::Type => "type",
+            SkipReason::Pages => "pages",
+            SkipReason::Resolution => "resolution",
+            SkipReason::Tag => "tag",
+            SkipReason::XRestrict => "x_restrict",
+            SkipReason::Date => "date",
+        }
+    }
+}
+
+/// Get the first rule of the filter which the illust does not pass.
+pub fn skip_reason(
+    filter: &DownloadFilter,
+    illust: &Illust,
+    extra: IllustExtra,
+) -> Option<SkipReason> {
+    macro_rules! check {
+        ($skip:expr, $reason:ident) => {
+            if $skip {
+                return Some(SkipReason::$reason);
+            }
+        };
+    }
+    let exceeds = |limit: Option<i32>, value: i32| limit.is_some_and(|l| value > l);
+
+    check!(
+        filter.skip_ai_generated && extra.is_ai_generated(),
+        AiGenerated
+    );
+    check!(filter.skip_muted && illust.is_muted, Muted);
+    check!(
+        filter
+            .min_bookmarks
+            .is_some_and(|m| illust.total_bookmarks < m),
+        Bookmarks
+    );
+    check!(
+        !filter.types.is_empty() && !filter.types.contains(&illust.r#type),
+        Type
+    );
+    check!(exceeds(filter.max_pages, illust.page_count), Pages);
+    check!(
+        exceeds(filter.max_width, illust.width) || exceeds(filter.max_height, illust.height),
+        Resolution
+    );
+    check!(
+        illust
+            .tags
+            .iter()
+            .flat_map(flatten_alias)
+            .any(|alias| filter.exclude_tags.iter().any(|t| t == alias)),
+        Tag
+    );
+    check!(exceeds(filter.max_x_restrict, illust.x_restrict), XRestrict);
+    check!(
+        filter.date_after.is_some_and(|d| illust.create_date < d)
+            || filter.date_before.is_some_and(|d| illust.create_date > d),
+        Date
+    );
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_reason() {
+        let illust = Illust {
+            r#type: "manga".to_string(),
+            page_count: 12,
+            width: 1000,
+            height: 3000,
+            total_bookmarks: 50,
+            x_restrict: 1,
+            create_date: "2022-06-01T00:00:00Z".parse().unwrap(),
+            tags: vec![pixivcrab::models::Tag {
+                name: "漫画".to_string(),
+                translated_name: Some("manga".to_string()),
+                added_by_uploaded_user: None,
+            }],
+            ..Default::default()
+        };
+        let extra = IllustExtra { illust_ai_type: 2 };
+        let skip = |filter: DownloadFilter| skip_reason(&filter, &illust, extra);
+
+        assert_eq!(skip(DownloadFilter::default()), None);
+        assert_eq!(
+            skip(DownloadFilter {
+                skip_ai_generated: true,
+                ..Default::default()
+            }),
+            Some(SkipReason::AiGenerated)
+        );
+        assert_eq!(
+            skip(DownloadFilter {
+                min_bookmarks: Some(100),
+                ..Default::default()
+            }),
+            Some(SkipReason::Bookmarks)
+        );
+        assert_eq!(
+            skip(DownloadFilter {
+                types: vec!["illust".to_string(), "ugoira".to_string()],
+                ..Default::default()
+            }),
+            Some(SkipReason::Type)
+        );
+        assert_eq!(
+            skip(DownloadFilter {
+                max_pages: Some(12),
+                max_height: Some(2048),
+                ..Default::default()
+            }),
+            Some(SkipReason::Resolution)
+        );
+        assert_eq!(
+            skip(DownloadFilter {
+                exclude_tags: vec!["manga".to_string()],
+                ..Default::default()
+            }),
+            Some(SkipReason::Tag)
+        );
+        assert_eq!(
+            skip(DownloadFilter {
+                max_x_restrict: Some(0),
+                ..Default::default()
+            }),
+            Some(SkipReason::XRestrict)
+        );
+        assert_eq!(
+            skip(DownloadFilter {
+                date_after: Some("2022-01-01T00:00:00Z".parse().unwrap()),
+                date_before: Some("2022-03-01T00:00:00Z".parse().unwrap()),
+                ..Default::default()
+            }),
+            Some(SkipReason::Date)
+        );
+    }
+}
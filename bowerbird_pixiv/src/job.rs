@@ -0,0 +1,141 @@
+//! Progress of the sync job running in the current task.
+//!
+//! The sync functions report to the [`Progress`] set by [`with_progress`] if any,
+//! so that they can be monitored without changing their signatures.
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::Notify;
+
+/// Max number of the latest error messages kept.
+const MAX_ERRORS: usize = 20;
+
+#[derive(Debug, Default)]
+pub struct Progress {
+    items_processed: AtomicU64,
+    users_updated: AtomicU64,
+    error_count: AtomicU64,
+    errors: Mutex<Vec<String>>,
+    cancelled: AtomicBool,
+    cancel_notify: Notify,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct ProgressSnapshot {
+    /// Illusts or novels saved.
+    pub items_processed: u64,
+    pub users_updated: u64,
+    pub error_count: u64,
+    /// The latest error messages.
+    pub errors: Vec<String>,
+}
+
+impl Progress {
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            items_processed: self.items_processed.load(Ordering::Relaxed),
+            users_updated: self.users_updated.load(Ordering::Relaxed),
+            error_count: self.error_count.load(Ordering::Relaxed),
+            errors: self.errors.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn record_error(&self, e: impl Display) {
+        self.error_count.fetch_add(1, Ordering::Relaxed);
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() >= MAX_ERRORS {
+            errors.remove(0);
+        }
+        errors.push(e.to_string());
+    }
+
+    /// Cancel the tasks spawned by [`crate::PixivKit::spawn_limited`] in the job.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.cancel_notify.notify_waiters();
+    }
+
+    /// Wait until the job is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            // Created before checking the flag not to miss the notification.
+            let notified = self.cancel_notify.notified();
+            if self.cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+tokio::task_local! {
+    static PROGRESS: Arc<Progress>;
+}
+
+/// Run the future with the progress reported to `progress`,
+/// including the tasks spawned by [`crate::PixivKit::spawn_limited`] in it.
+pub async fn with_progress<F: Future>(progress: Arc<Progress>, f: F) -> F::Output {
+    PROGRESS.scope(progress, f).await
+}
+
+pub(crate) fn current() -> Option<Arc<Progress>> {
+    PROGRESS.try_with(Arc::clone).ok()
+}
+
+pub(crate) fn add_items(n: u64) {
+    let _ = PROGRESS.try_with(|p| p.items_processed.fetch_add(n, Ordering::Relaxed));
+}
+
+pub(crate) fn add_users(n: u64) {
+    let _ = PROGRESS.try_with(|p| p.users_updated.fetch_add(n, Ordering::Relaxed));
+}
+
+pub(crate) fn record_error(e: impl Display) {
+    let _ = PROGRESS.try_with(|p| p.record_error(e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_progress() {
+        add_items(1);
+        let progress = Arc::new(Progress::default());
+        with_progress(progress.clone(), async {
+            add_items(2);
+            add_users(1);
+            for i in 0..MAX_ERRORS + 1 {
+                record_error(i);
+            }
+        })
+        .await;
+        let snapshot = progress.snapshot();
+        assert_eq!(snapshot.items_processed, 2);
+        assert_eq!(snapshot.users_updated, 1);
+        assert_eq!(snapshot.error_count, MAX_ERRORS as u64 + 1);
+        assert_eq!(snapshot.errors.len(), MAX_ERRORS);
+        assert_eq!(snapshot.errors[0], "1");
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let progress = Arc::new(Progress::default());
+        let waiting = tokio::spawn({
+            let progress = progress.clone();
+            async move { progress.cancelled().await }
+        });
+        progress.cancel();
+        waiting.await.unwrap();
+        // Returns at once after cancelled.
+        progress.cancelled().await;
+    }
+}
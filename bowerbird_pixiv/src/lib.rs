@@ -1,7 +1,8 @@
-use bowerbird_core::config::Config;
+use bowerbird_core::config::{Config, DownloadFilter, UgoiraConfig};
 use bowerbird_utils::{check_ffmpeg, downloader::Aria2Downloader, logged_rustls_with_native_root};
-use futures::Future;
+use futures::{future, pin_mut, Future};
 use log::{debug, error, info, warn};
+use path_template::PathTemplates;
 use pixivcrab::{AppApi, Pager};
 use reqwest::ClientBuilder;
 use serde::de::DeserializeOwned;
@@ -12,14 +13,24 @@ use std::{
     fmt::Debug,
     path::PathBuf,
     sync::Arc,
-    time::Duration,
 };
+use throttle::Throttle;
 use tokio::{spawn, sync::Semaphore};
 
+mod api;
 pub mod database;
 pub mod download;
 mod error;
+pub mod export;
+pub mod filter;
+pub mod job;
+pub mod novel_markup;
+pub mod path_template;
 mod queries;
+pub mod recheck;
+pub mod storage;
+pub mod throttle;
+pub mod ugoira;
 mod utils;
 
 pub use error::Error;
@@ -40,8 +51,12 @@ where
 #[derive(Debug, Clone)]
 pub struct TaskConfig {
     pub ffmpeg_path: Option<PathBuf>,
+    pub ugoira: UgoiraConfig,
     pub proxy: Option<String>,
     pub parent_dir: PathBuf,
+    /// The configured download filter, with the rules of the current job merged.
+    pub download_filter: DownloadFilter,
+    pub path_templates: PathTemplates,
 }
 
 pub struct PixivKit {
@@ -52,16 +67,21 @@ pub struct PixivKit {
     pub config: Config,
     pub auth_result: pixivcrab::AuthResult,
     pub max_tries: i32,
+    pub throttle: Throttle,
+    raw_client: reqwest::Client,
+    api_base_url: String,
     tasks_semaphore: Arc<Semaphore>,
     tasks_initial_permits: usize,
 }
 
 macro_rules! retry_impl {
-    ($fut:expr, $max_tries:expr) => {
+    ($fut:expr, $kit:expr) => {
         use pixivcrab::error::Error;
 
         let mut tries = 1;
+        let mut rate_limited = 0;
         loop {
+            $kit.throttle.acquire().await;
             match $fut.await {
                 Ok(r) => {
                     return Ok(r);
@@ -69,19 +89,23 @@ macro_rules! retry_impl {
                 Err(e) => {
                     match &e {
                         Error::Http { .. } => {
-                            if tries < $max_tries {
-                                warn!("retrying on pixiv api error: {}", e);
-                                tokio::time::sleep(Duration::from_secs(2)).await;
+                            if tries < $kit.max_tries {
+                                let delay = $kit.throttle.retry_delay((tries - 1) as u32);
+                                warn!("retrying in {:?} on pixiv api error: {}", delay, e);
+                                tokio::time::sleep(delay).await;
                                 tries += 1;
                                 continue;
                             }
                         }
                         Error::UnexpectedStatus { status, text } => {
-                            if status.as_u16() == 403
-                                && text.to_ascii_lowercase().contains("rate limit")
-                            {
-                                warn!("pixiv api rate limit reached, will retry in 60s: {}", e);
-                                tokio::time::sleep(Duration::from_secs(60)).await;
+                            if throttle::is_rate_limited(*status, text) {
+                                let delay = $kit.throttle.rate_limit_delay(rate_limited);
+                                warn!(
+                                    "pixiv api rate limit reached, will retry in {:?}: {}",
+                                    delay, e
+                                );
+                                tokio::time::sleep(delay).await;
+                                rate_limited += 1;
                                 continue;
                             }
                         }
@@ -97,22 +121,34 @@ macro_rules! retry_impl {
 impl PixivKit {
     /// Log in to pixiv, save token, start aria2, and check ffmpeg.
     pub async fn new(mut config: Config, db: PgPool) -> Result<Self> {
-        let mut api_client = ClientBuilder::new().cookie_store(true);
-        if config.ssl_key_log {
-            api_client = logged_rustls_with_native_root(api_client).context(error::Utils)?;
-        }
-        if let Some(proxy) = config
-            .pxoxy(&config.pixiv.proxy_api)
-            .context(error::Config)?
-        {
-            debug!("pixiv api proxy set: {:?}", proxy);
-            api_client = api_client.proxy(proxy);
-        }
+        let api_client = || -> Result<ClientBuilder> {
+            let mut api_client = ClientBuilder::new().cookie_store(true);
+            if config.ssl_key_log {
+                api_client = logged_rustls_with_native_root(api_client).context(error::Utils)?;
+            }
+            if let Some(proxy) = config
+                .pxoxy(&config.pixiv.proxy_api)
+                .context(error::Config)?
+            {
+                debug!("pixiv api proxy set: {:?}", proxy);
+                api_client = api_client.proxy(proxy);
+            }
+            Ok(api_client)
+        };
         let mut api_config = pixivcrab::AppApiConfig::default();
         api_config.set_language_header_value((*config.pixiv.language).clone());
+        // The same settings as the client of pixivcrab, used by raw requests.
+        let raw_client = api_client()?
+            .user_agent(&api_config.user_agent)
+            .timeout(api_config.timeout)
+            .default_headers(api_config.base_headers.clone())
+            .build()
+            .map_err(|source| pixivcrab::error::Error::Http { source })
+            .context(error::PixivApi)?;
+        let api_base_url = api_config.base_url.clone();
         let api = pixivcrab::AppApi::new_with_config(
             pixivcrab::AuthMethod::RefreshToken(config.pixiv.refresh_token.clone()),
-            api_client,
+            api_client()?,
             api_config,
         )
         .context(error::PixivApi)?;
@@ -130,10 +166,14 @@ impl PixivKit {
 
         let task_config = TaskConfig {
             ffmpeg_path: check_ffmpeg(&config.ffmpeg_path).await,
+            ugoira: config.pixiv.ugoira.clone(),
             parent_dir: config.sub_dir(&config.pixiv.storage_dir),
             proxy: config.pxoxy_string(&config.pixiv.proxy_download),
+            download_filter: config.pixiv.download_filter.clone(),
+            path_templates: PathTemplates::new(&config.pixiv.path_template)?,
         };
         let tasks_initial_permits = num_cpus::get();
+        let throttle = Throttle::new(config.pixiv.rate_limit.clone());
         Ok(Self {
             tasks_initial_permits,
             tasks_semaphore: Arc::new(Semaphore::new(tasks_initial_permits)),
@@ -144,6 +184,9 @@ impl PixivKit {
             config,
             auth_result,
             max_tries: 3,
+            throttle,
+            raw_client,
+            api_base_url,
         })
     }
 
@@ -151,21 +194,81 @@ impl PixivKit {
         &self.auth_result.user.id
     }
 
+    /// Get the next page of the pager, sent as [`Self::retry_get_json`] to respect `Retry-After`.
     pub async fn retry_pager<T>(&self, pager: &mut Pager<T>) -> Result<Option<T>>
     where
         T: DeserializeOwned + pixivcrab::NextUrl + Debug + Send,
     {
-        retry_impl!(pager.next(), self.max_tries);
+        let Some(url) = pager.next_url.clone() else {
+            return Ok(None);
+        };
+        let r: T = self.retry_get_json_url(&url, &[]).await?;
+        pager.next_url = r.next_url();
+        Ok(Some(r))
     }
 
-    pub async fn retry_api<'a, T, Fut>(&'a self, mut f: impl FnMut(&'a AppApi) -> Fut) -> Result<T>
-    where
-        Fut: Future<Output = std::result::Result<T, pixivcrab::error::Error>>,
-    {
-        retry_impl!(f(&self.api), self.max_tries);
+    /// Get the next page of the illusts along with the fields not in the models of pixivcrab.
+    ///
+    /// Only the url of the pager is used, and the page is parsed once into [`api::IllustPage`].
+    pub async fn retry_illust_pager(
+        &self,
+        pager: &mut Pager<pixivcrab::models::illust::Response>,
+    ) -> Result<
+        Option<(
+            pixivcrab::models::illust::Response,
+            HashMap<i64, api::IllustExtra>,
+        )>,
+    > {
+        let Some(url) = pager.next_url.clone() else {
+            return Ok(None);
+        };
+        let page: api::IllustPage = self.retry_get_json_url(&url, &[]).await?;
+        pager.next_url = page.next_url.clone();
+        Ok(Some(page.into_parts()))
+    }
+
+    /// Send the GET request to the app-api with retries.
+    ///
+    /// All the requests are sent by this instead of pixivcrab,
+    /// whose errors do not keep the headers, so that `Retry-After` is respected on rate limit.
+    pub async fn retry_get_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T> {
+        self.retry_get_json_url(&format!("{}{}", self.api_base_url, path), query)
+            .await
+    }
+
+    async fn retry_get_json_url<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T> {
+        let get_json = || async {
+            use pixivcrab::error::Error;
+
+            let response = self
+                .api
+                .send_authorized(self.raw_client.get(url).query(query))
+                .await?;
+            let status = response.status();
+            if !status.is_success() {
+                self.throttle.set_retry_after(response.headers());
+                let text = response.text().await.unwrap_or_default();
+                return Err(Error::UnexpectedStatus { status, text });
+            }
+            let body = response
+                .bytes()
+                .await
+                .map_err(|source| Error::Http { source })?;
+            serde_json::from_slice(&body).map_err(|source| Error::UnexpectedJson { source })
+        };
+        retry_impl!(get_json(), self);
     }
 
     pub async fn wait_tasks(self) {
+        info!("pixiv api throttle stats: {:?}", self.throttle.stats());
         self.downloader.wait_and_shutdown().await;
         let _ = self
             .tasks_semaphore
@@ -179,13 +282,27 @@ impl PixivKit {
         F: Future<Output = anyhow::Result<()>> + Send + 'static,
     {
         let semaphore = self.tasks_semaphore.clone();
+        let progress = job::current();
         spawn(async move {
-            let _permit = semaphore
-                .acquire()
-                .await
-                .expect("spawn_limited: failed to acquire permit");
-            if let Err(e) = f.await {
-                error!("task error: {}", e);
+            let f = async {
+                let _permit = semaphore
+                    .acquire()
+                    .await
+                    .expect("spawn_limited: failed to acquire permit");
+                if let Err(e) = f.await {
+                    error!("task error: {}", e);
+                    job::record_error(e);
+                }
+            };
+            match progress {
+                // The task is dropped if the job is cancelled.
+                Some(progress) => {
+                    let job = progress.clone();
+                    let cancelled = job.cancelled();
+                    pin_mut!(f, cancelled);
+                    job::with_progress(progress, future::select(f, cancelled)).await;
+                }
+                None => f.await,
             }
         });
     }
@@ -201,20 +318,71 @@ macro_rules! generate_limiter {
     };
 }
 
+/// Fail if the bookmarks cannot be reconciled.
+fn check_reconcile(
+    kit: &PixivKit,
+    user_id: &str,
+    limit: Option<u32>,
+    tag: Option<&str>,
+) -> Result<()> {
+    if user_id != kit.current_user_id() {
+        return error::InvalidArgument {
+            message: "only the bookmarks of the logged in user can be reconciled",
+        }
+        .fail();
+    }
+    if limit.is_some() {
+        return error::InvalidArgument {
+            message: "bookmarks cannot be reconciled with a limit",
+        }
+        .fail();
+    }
+    if tag.is_some() {
+        return error::InvalidArgument {
+            message: "bookmarks cannot be reconciled with a bookmark tag",
+        }
+        .fail();
+    }
+    Ok(())
+}
+
+/// Mark the saved `illust`s or `novel`s missing from the fetched bookmarks as unbookmarked.
+async fn reconcile_bookmarks(
+    kind: &str,
+    source_ids: BTreeSet<String>,
+    kit: &PixivKit,
+) -> Result<()> {
+    if source_ids.is_empty() {
+        warn!("no {} bookmarks fetched, skip reconciliation", kind);
+        return Ok(());
+    }
+    let source_ids: Vec<String> = source_ids.into_iter().collect();
+    let removed = queries::reconcile_bookmarks(kind, &source_ids, &kit.db).await?;
+    info!("{} {} bookmarks removed since the last sync", removed, kind);
+    Ok(())
+}
+
+/// Save the illusts of the pager and return their ids.
 async fn illusts(
     limit: Option<u32>,
     mut pager: pixivcrab::Pager<pixivcrab::models::illust::Response>,
+    bookmark_private: Option<bool>,
     kit: &PixivKit,
-) -> Result<()> {
+) -> Result<BTreeSet<String>> {
     let mut users_need_update_set = BTreeSet::new();
+    let mut source_ids = BTreeSet::new();
     let mut items_sent = 0;
     let mut ugoira_map: HashMap<String, (String, Vec<i32>)> = HashMap::new();
-    while let Some(r) = {
+    while let Some((r, extras)) = {
         info!("getting illusts with offset: {}", items_sent);
-        kit.retry_pager(&mut pager).await?
+        kit.retry_illust_pager(&mut pager).await?
     } {
+        source_ids.extend(r.illusts.iter().map(|i| i.id.to_string()));
+        let items_before = items_sent;
         database::save_illusts(
             &r.illusts,
+            &extras,
+            bookmark_private,
             kit,
             |u| {
                 users_need_update_set.insert(u.to_string());
@@ -226,11 +394,13 @@ async fn illusts(
         .await?;
         download::download_illusts(
             &r.illusts,
+            &extras,
             &mut ugoira_map,
             generate_limiter!(limit, items_sent),
             kit,
         )
         .await?;
+        job::add_items((items_sent - items_before).into());
         if limit_reached(limit, items_sent) {
             break;
         }
@@ -239,31 +409,56 @@ async fn illusts(
 
     database::update_user_id_set(users_need_update_set, kit).await?;
 
-    Ok(())
+    Ok(source_ids)
 }
 
 pub async fn illust_uploads(kit: &PixivKit, user_id: &str, limit: Option<u32>) -> Result<()> {
     let pager = kit.api.illust_uploads(user_id);
-    illusts(limit, pager, kit).await
+    illusts(limit, pager, None, kit).await?;
+    Ok(())
 }
 
+/// Save the bookmarked illusts of the user.
+///
+/// With `reconcile`, all public and private bookmarks of the logged in user are fetched,
+/// and the saved illusts missing from them are marked as unbookmarked.
+/// Otherwise only the bookmarks with the bookmark `tag` are fetched if it is given.
 pub async fn illust_bookmarks(
     kit: &PixivKit,
     user_id: &str,
     limit: Option<u32>,
     private: bool,
+    reconcile: bool,
+    tag: Option<&str>,
 ) -> Result<()> {
-    let pager = kit.api.illust_bookmarks(user_id, private);
-    illusts(limit, pager, kit).await
+    if reconcile {
+        check_reconcile(kit, user_id, limit, tag)?;
+        let mut source_ids = BTreeSet::new();
+        for private in [false, true] {
+            let pager = kit.api.illust_bookmarks(user_id, private);
+            source_ids.extend(illusts(None, pager, Some(private), kit).await?);
+        }
+        return reconcile_bookmarks("illust", source_ids, kit).await;
+    }
+    let bookmark_private = (user_id == kit.current_user_id()).then_some(private);
+    let mut pager = kit.api.illust_bookmarks(user_id, private);
+    if let Some(tag) = tag {
+        pager = api::with_bookmark_tag(pager, tag);
+    }
+    illusts(limit, pager, bookmark_private, kit).await?;
+    Ok(())
 }
 
+/// Save the novels of the pager and return their ids.
 async fn novels(
     limit: Option<u32>,
     update_exists: bool,
     mut pager: pixivcrab::Pager<pixivcrab::models::novel::Response>,
+    bookmark_private: Option<bool>,
     kit: &PixivKit,
-) -> Result<()> {
+) -> Result<BTreeSet<String>> {
     let mut users_need_update_set = BTreeSet::new();
+    let mut source_ids = BTreeSet::new();
     let mut items_sent = 0;
 
     while let Some(r) = {
@@ -271,9 +466,12 @@ async fn novels(
         kit.retry_pager(&mut pager).await?
     } {
         debug!("novels: {:?}", r);
+        source_ids.extend(r.novels.iter().map(|n| n.id.to_string()));
+        let items_before = items_sent;
         database::save_novels(
             &r.novels,
             update_exists,
+            bookmark_private,
             kit,
             generate_limiter!(limit, items_sent),
             |u| {
@@ -281,6 +479,7 @@ async fn novels(
             },
         )
         .await?;
+        job::add_items((items_sent - items_before).into());
         if limit_reached(limit, items_sent) {
             break;
         }
@@ -289,18 +488,39 @@ async fn novels(
 
     database::update_user_id_set(users_need_update_set, kit).await?;
 
-    Ok(())
+    Ok(source_ids)
 }
 
+/// Save the bookmarked novels of the user.
+///
+/// With `reconcile`, all public and private bookmarks of the logged in user are fetched,
+/// and the saved novels missing from them are marked as unbookmarked.
+/// Otherwise only the bookmarks with the bookmark `tag` are fetched if it is given.
 pub async fn novel_bookmarks(
     kit: &PixivKit,
     user_id: &str,
     limit: Option<u32>,
     update_exists: bool,
     private: bool,
+    reconcile: bool,
+    tag: Option<&str>,
 ) -> Result<()> {
-    let pager = kit.api.novel_bookmarks(user_id, private);
-    novels(limit, update_exists, pager, kit).await
+    if reconcile {
+        check_reconcile(kit, user_id, limit, tag)?;
+        let mut source_ids = BTreeSet::new();
+        for private in [false, true] {
+            let pager = kit.api.novel_bookmarks(user_id, private);
+            source_ids.extend(novels(None, update_exists, pager, Some(private), kit).await?);
+        }
+        return reconcile_bookmarks("novel", source_ids, kit).await;
+    }
+    let bookmark_private = (user_id == kit.current_user_id()).then_some(private);
+    let mut pager = kit.api.novel_bookmarks(user_id, private);
+    if let Some(tag) = tag {
+        pager = api::with_bookmark_tag(pager, tag);
+    }
+    novels(limit, update_exists, pager, bookmark_private, kit).await?;
+    Ok(())
 }
 
 pub async fn novel_uploads(
@@ -310,7 +530,8 @@ pub async fn novel_uploads(
     update_exists: bool,
 ) -> Result<()> {
     let pager = kit.api.novel_uploads(user_id);
-    novels(limit, update_exists, pager, kit).await
+    novels(limit, update_exists, pager, None, kit).await?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -333,6 +554,8 @@ mod tests {
             .await
             .unwrap();
         let kit = PixivKit::new(generate_config(), db).await.unwrap();
-        illust_bookmarks(&kit, &uid, Some(10), false).await.unwrap();
+        illust_bookmarks(&kit, &uid, Some(10), false, false, None)
+            .await
+            .unwrap();
     }
 }
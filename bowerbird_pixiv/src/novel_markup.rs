@@ -0,0 +1,538 @@
+//! Parser and renderers for the pixiv novel markup.
+//!
+//! Novel texts are saved as returned by pixiv, with markups like `[newpage]`,
+//! `[chapter:Title]`, `[[rb:漢字 > かんじ]]`, `[jump:2]`, `[[jumpuri:Text > https://...]]`,
+//! `[pixivimage:12345678-1]` and `[uploadedimage:123]`.
+//! [`parse`] turns the text into a [`Document`], which can be rendered with
+//! [`render_html`] or [`render_text`].
+
+/// An image referenced in the novel text.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ImageRef {
+    /// `[pixivimage:{illust_id}-{page}]`. `page` starts from 1.
+    Pixiv { illust_id: String, page: u32 },
+    /// `[uploadedimage:{id}]`.
+    Uploaded { id: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Text(String),
+    LineBreak,
+    /// `[chapter:...]`. The title may contain ruby.
+    Chapter(Vec<Node>),
+    /// `[[rb:base > ruby]]`
+    Ruby {
+        base: String,
+        ruby: String,
+    },
+    /// `[jump:page]`. `page` starts from 1.
+    Jump(u32),
+    /// `[[jumpuri:text > uri]]`
+    JumpUri {
+        text: String,
+        uri: String,
+    },
+    Image(ImageRef),
+}
+
+/// A page split by `[newpage]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Page {
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Document {
+    pub pages: Vec<Page>,
+}
+
+impl Document {
+    /// Iterate over all nodes of all pages.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.pages.iter().flat_map(|p| p.nodes.iter())
+    }
+
+    /// Images referenced in the document, in order of appearance.
+    pub fn images(&self) -> impl Iterator<Item = &ImageRef> {
+        self.nodes().filter_map(|n| match n {
+            Node::Image(i) => Some(i),
+            _ => None,
+        })
+    }
+}
+
+/// Split `s` at the first `>` and trim both parts.
+fn split_arrow(s: &str) -> Option<(&str, &str)> {
+    let (a, b) = s.split_once('>')?;
+    Some((a.trim(), b.trim()))
+}
+
+/// Find the `]` closing a tag whose content starts at the beginning of `s`,
+/// skipping the brackets of nested `[[...]]` tags.
+fn find_closing_bracket(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 0 => return Some(i),
+            ']' => depth -= 1,
+            '\n' => return None,
+            _ => (),
+        }
+    }
+    None
+}
+
+fn parse_image(kind: &str, arg: &str) -> Option<ImageRef> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return None;
+    }
+    match kind {
+        "pixivimage" => {
+            let (illust_id, page) = match arg.split_once('-') {
+                Some((id, page)) => (id, page.parse().ok()?),
+                None => (arg, 1),
+            };
+            if illust_id.is_empty() || !illust_id.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            Some(ImageRef::Pixiv {
+                illust_id: illust_id.to_string(),
+                page,
+            })
+        }
+        "uploadedimage" => Some(ImageRef::Uploaded {
+            id: arg.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// A markup parsed at the start of the input, with the length it consumed.
+enum Markup {
+    NewPage,
+    Node(Node),
+}
+
+fn parse_markup(s: &str) -> Option<(Markup, usize)> {
+    if let Some(rest) = s.strip_prefix("[[") {
+        let end = rest.find("]]")?;
+        let inner = &rest[..end];
+        if inner.contains('\n') {
+            return None;
+        }
+        let len = end + 4;
+        let (kind, arg) = inner.split_once(':')?;
+        let node = match kind {
+            "rb" => {
+                let (base, ruby) = split_arrow(arg)?;
+                Node::Ruby {
+                    base: base.to_string(),
+                    ruby: ruby.to_string(),
+                }
+            }
+            "jumpuri" => {
+                let (text, uri) = split_arrow(arg)?;
+                Node::JumpUri {
+                    text: text.to_string(),
+                    uri: uri.to_string(),
+                }
+            }
+            _ => return None,
+        };
+        return Some((Markup::Node(node), len));
+    }
+
+    let rest = s.strip_prefix('[')?;
+    let end = find_closing_bracket(rest)?;
+    let inner = &rest[..end];
+    let len = end + 2;
+    if inner == "newpage" {
+        return Some((Markup::NewPage, len));
+    }
+    let (kind, arg) = inner.split_once(':')?;
+    let node = match kind {
+        "chapter" => Node::Chapter(parse_inline(arg.trim())),
+        "jump" => Node::Jump(arg.trim().parse().ok()?),
+        "pixivimage" | "uploadedimage" => Node::Image(parse_image(kind, arg)?),
+        _ => return None,
+    };
+    Some((Markup::Node(node), len))
+}
+
+fn push_text(nodes: &mut Vec<Node>, s: &str) {
+    if s.is_empty() {
+        return;
+    }
+    if let Some(Node::Text(t)) = nodes.last_mut() {
+        t.push_str(s);
+    } else {
+        nodes.push(Node::Text(s.to_string()));
+    }
+}
+
+/// Parse the text into pages of nodes.
+fn parse_pages(text: &str) -> Vec<Vec<Node>> {
+    let mut pages = vec![vec![]];
+    let mut i = 0;
+    let mut text_start = 0;
+    // Skip the line break right after a block markup (`[chapter:]` and `[newpage]`).
+    let mut skip_line_break = false;
+
+    while i < text.len() {
+        let rest = &text[i..];
+        let c = rest.chars().next().unwrap();
+        let nodes = pages.last_mut().unwrap();
+        match c {
+            '\n' | '\r' => {
+                push_text(nodes, &text[text_start..i]);
+                let len = if rest.starts_with("\r\n") { 2 } else { 1 };
+                if !skip_line_break {
+                    nodes.push(Node::LineBreak);
+                }
+                skip_line_break = false;
+                i += len;
+                text_start = i;
+            }
+            '[' => match parse_markup(rest) {
+                Some((markup, len)) => {
+                    push_text(nodes, &text[text_start..i]);
+                    match markup {
+                        Markup::NewPage => {
+                            pages.push(vec![]);
+                            skip_line_break = true;
+                        }
+                        Markup::Node(n) => {
+                            skip_line_break = matches!(n, Node::Chapter(_));
+                            nodes.push(n);
+                        }
+                    }
+                    i += len;
+                    text_start = i;
+                }
+                None => {
+                    skip_line_break = false;
+                    i += 1;
+                }
+            },
+            _ => {
+                skip_line_break = false;
+                i += c.len_utf8();
+            }
+        }
+    }
+    push_text(pages.last_mut().unwrap(), &text[text_start..]);
+    pages
+}
+
+/// Parse inline markups only. Used for chapter titles.
+fn parse_inline(text: &str) -> Vec<Node> {
+    let mut nodes = vec![];
+    let mut i = 0;
+    let mut text_start = 0;
+    while let Some(pos) = text[i..].find("[[") {
+        i += pos;
+        match parse_markup(&text[i..]) {
+            Some((Markup::Node(n @ Node::Ruby { .. }), len)) => {
+                push_text(&mut nodes, &text[text_start..i]);
+                nodes.push(n);
+                i += len;
+                text_start = i;
+            }
+            _ => i += 2,
+        }
+    }
+    push_text(&mut nodes, &text[text_start..]);
+    nodes
+}
+
+/// Parse the novel text into a [`Document`].
+///
+/// Unknown or malformed markups are kept as plain text.
+/// Line breaks right after `[chapter:]` and around `[newpage]` are dropped.
+pub fn parse(text: &str) -> Document {
+    let pages = parse_pages(text)
+        .into_iter()
+        .map(|mut nodes| {
+            while matches!(nodes.last(), Some(Node::LineBreak)) {
+                nodes.pop();
+            }
+            Page { nodes }
+        })
+        .collect();
+    Document { pages }
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => r.push_str("&amp;"),
+            '<' => r.push_str("&lt;"),
+            '>' => r.push_str("&gt;"),
+            '"' => r.push_str("&quot;"),
+            '\'' => r.push_str("&#39;"),
+            _ => r.push(c),
+        }
+    }
+    r
+}
+
+fn is_safe_uri(uri: &str) -> bool {
+    let lower = uri.to_ascii_lowercase();
+    lower.starts_with("https://") || lower.starts_with("http://")
+}
+
+/// Resolves the images and page links when rendering HTML.
+///
+/// Implemented for closures resolving the `src` of the images.
+pub trait HtmlResolver {
+    /// The `src` of the image, or `None` to render a placeholder.
+    fn image_src(&self, image: &ImageRef) -> Option<String>;
+
+    /// The `href` of `[jump:page]`.
+    fn page_href(&self, page: u32) -> String {
+        format!("#page-{page}")
+    }
+}
+
+impl<F> HtmlResolver for F
+where
+    F: Fn(&ImageRef) -> Option<String>,
+{
+    fn image_src(&self, image: &ImageRef) -> Option<String> {
+        self(image)
+    }
+}
+
+/// Render nodes to XHTML-compatible HTML.
+///
+/// `chapter_index` is increased for each chapter to generate the anchors `chapter-{n}`.
+pub fn render_html_nodes(
+    nodes: &[Node],
+    chapter_index: &mut usize,
+    resolver: &impl HtmlResolver,
+    out: &mut String,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(&escape_html(t)),
+            Node::LineBreak => out.push_str("<br />\n"),
+            Node::Chapter(title) => {
+                *chapter_index += 1;
+                out.push_str(&format!("<h2 id=\"chapter-{}\">", chapter_index));
+                render_html_nodes(title, chapter_index, resolver, out);
+                out.push_str("</h2>\n");
+            }
+            Node::Ruby { base, ruby } => out.push_str(&format!(
+                "<ruby>{}<rp>(</rp><rt>{}</rt><rp>)</rp></ruby>",
+                escape_html(base),
+                escape_html(ruby)
+            )),
+            Node::Jump(page) => out.push_str(&format!(
+                "<a class=\"jump\" href=\"{}\">{page}</a>",
+                escape_html(&resolver.page_href(*page))
+            )),
+            Node::JumpUri { text, uri } => {
+                if is_safe_uri(uri) {
+                    out.push_str(&format!(
+                        "<a href=\"{}\" rel=\"noopener noreferrer\">{}</a>",
+                        escape_html(uri),
+                        escape_html(text)
+                    ));
+                } else {
+                    out.push_str(&escape_html(text));
+                }
+            }
+            Node::Image(image) => match resolver.image_src(image) {
+                Some(src) => out.push_str(&format!(
+                    "<img class=\"novel-image\" src=\"{}\" alt=\"\" />",
+                    escape_html(&src)
+                )),
+                None => out.push_str(&format!(
+                    "<span class=\"novel-image-missing\">{}</span>",
+                    escape_html(&image_placeholder(image))
+                )),
+            },
+        }
+    }
+}
+
+/// Render the document to HTML.
+///
+/// Each page is wrapped in `<section class="page" id="page-{n}">`.
+/// Images without a `src` from the resolver are rendered as text.
+pub fn render_html(doc: &Document, resolver: impl HtmlResolver) -> String {
+    let mut out = String::new();
+    let mut chapter_index = 0;
+    for (i, page) in doc.pages.iter().enumerate() {
+        out.push_str(&format!("<section class=\"page\" id=\"page-{}\">\n", i + 1));
+        render_html_nodes(&page.nodes, &mut chapter_index, &resolver, &mut out);
+        out.push_str("\n</section>\n");
+    }
+    out
+}
+
+/// The text of a chapter title, with ruby replaced by its base text.
+pub fn title_text(nodes: &[Node]) -> String {
+    let mut out = String::new();
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Ruby { base, .. } => out.push_str(base),
+            Node::JumpUri { text, .. } => out.push_str(text),
+            _ => (),
+        }
+    }
+    out
+}
+
+fn image_placeholder(image: &ImageRef) -> String {
+    match image {
+        ImageRef::Pixiv { illust_id, page } => format!("[pixivimage:{illust_id}-{page}]"),
+        ImageRef::Uploaded { id } => format!("[uploadedimage:{id}]"),
+    }
+}
+
+fn render_text_nodes(nodes: &[Node], out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::LineBreak => out.push('\n'),
+            Node::Chapter(title) => {
+                if !out.is_empty() && !out.ends_with("\n\n") {
+                    out.push_str(if out.ends_with('\n') { "\n" } else { "\n\n" });
+                }
+                render_text_nodes(title, out);
+                out.push_str("\n\n");
+            }
+            // Aozora Bunko style ruby.
+            Node::Ruby { base, ruby } => out.push_str(&format!("{base}《{ruby}》")),
+            Node::Jump(page) => out.push_str(&format!("(p. {page})")),
+            Node::JumpUri { text, uri } => out.push_str(&format!("{text} ({uri})")),
+            Node::Image(image) => out.push_str(&image_placeholder(image)),
+        }
+    }
+}
+
+/// Render the document to plain text.
+///
+/// Ruby is written as `漢字《かんじ》` and pages are separated by blank lines.
+pub fn render_text(doc: &Document) -> String {
+    let mut out = String::new();
+    for (i, page) in doc.pages.iter().enumerate() {
+        if i > 0 {
+            out.push_str("\n\n");
+        }
+        render_text_nodes(&page.nodes, &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Node {
+        Node::Text(s.to_string())
+    }
+
+    #[test]
+    fn test_parse_plain() {
+        let doc = parse("line 1\nline 2");
+        assert_eq!(
+            doc.pages,
+            vec![Page {
+                nodes: vec![text("line 1"), Node::LineBreak, text("line 2")]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_newpage_and_chapter() {
+        let doc = parse("[chapter:第一章]\nfirst\n[newpage]\n[chapter:[[rb:序 > じょ]]]\nsecond\n");
+        assert_eq!(doc.pages.len(), 2);
+        assert_eq!(
+            doc.pages[0].nodes,
+            vec![Node::Chapter(vec![text("第一章")]), text("first")]
+        );
+        assert_eq!(
+            doc.pages[1].nodes,
+            vec![
+                Node::Chapter(vec![Node::Ruby {
+                    base: "序".to_string(),
+                    ruby: "じょ".to_string()
+                }]),
+                text("second")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_inline_markups() {
+        let doc = parse(
+            "a[[rb:漢字 > かんじ]]b[jump:3][[jumpuri:link > https://example.com]][pixivimage:123-2][uploadedimage:45]",
+        );
+        assert_eq!(
+            doc.pages[0].nodes,
+            vec![
+                text("a"),
+                Node::Ruby {
+                    base: "漢字".to_string(),
+                    ruby: "かんじ".to_string()
+                },
+                text("b"),
+                Node::Jump(3),
+                Node::JumpUri {
+                    text: "link".to_string(),
+                    uri: "https://example.com".to_string()
+                },
+                Node::Image(ImageRef::Pixiv {
+                    illust_id: "123".to_string(),
+                    page: 2
+                }),
+                Node::Image(ImageRef::Uploaded {
+                    id: "45".to_string()
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_as_text() {
+        let s = "[jump:x] [[rb:no arrow]] [chapter:unclosed\n[unknown:1] [[rb:a > b]";
+        let doc = parse(s);
+        assert_eq!(render_text(&doc), s);
+    }
+
+    #[test]
+    fn test_render_html() {
+        let doc = parse("[chapter:C<1>]\n[[rb:漢字 > かんじ]]\n[newpage]\n[[jumpuri:x > javascript:alert(1)]][pixivimage:1][uploadedimage:2]");
+        let html = render_html(&doc, |i: &ImageRef| match i {
+            ImageRef::Pixiv { illust_id, .. } => Some(format!("/img/{illust_id}.jpg")),
+            _ => None,
+        });
+        assert_eq!(
+            html,
+            "<section class=\"page\" id=\"page-1\">\n\
+             <h2 id=\"chapter-1\">C&lt;1&gt;</h2>\n\
+             <ruby>漢字<rp>(</rp><rt>かんじ</rt><rp>)</rp></ruby>\n\
+             </section>\n\
+             <section class=\"page\" id=\"page-2\">\n\
+             x<img class=\"novel-image\" src=\"/img/1.jpg\" alt=\"\" />\
+             <span class=\"novel-image-missing\">[uploadedimage:2]</span>\n\
+             </section>\n"
+        );
+    }
+
+    #[test]
+    fn test_render_text() {
+        let doc = parse("intro\n[chapter:One]\n[[rb:漢字 > かんじ]] [jump:2]\n[newpage]\n[[jumpuri:site > https://example.com]]");
+        assert_eq!(
+            render_text(&doc),
+            "intro\n\nOne\n\n漢字《かんじ》 (p. 2)\n\nsite (https://example.com)"
+        );
+    }
+}
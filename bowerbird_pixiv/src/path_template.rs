@@ -0,0 +1,325 @@
+//! Paths of the downloaded illust files by the templates in [`PathTemplateConfig`].
+
+use bowerbird_core::config::PathTemplateConfig;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::path::{Component, Path};
+
+use crate::{error, utils::IllustUrl, Result};
+
+lazy_static! {
+    static ref RE_PAGE: Regex = Regex::new(r"_p(\d+)$").unwrap();
+}
+
+/// Max number of characters of a variable in the path.
+const MAX_VALUE_CHARS: usize = 64;
+
+/// Max number of characters of a path, below the 256 of `pixiv_media.local_path`
+/// to leave room for the extensions of the files converted from the ugoira zips.
+const MAX_PATH_CHARS: usize = 250;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    UserId,
+    UserName,
+    IllustId,
+    Title,
+    Type,
+    Date,
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Page,
+    Filename,
+    Ext,
+}
+
+impl Var {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "user_id" => Var::UserId,
+            "user_name" => Var::UserName,
+            "illust_id" => Var::IllustId,
+            "title" => Var::Title,
+            "type" => Var::Type,
+            "date" => Var::Date,
+            "year" => Var::Year,
+            "month" => Var::Month,
+            "day" => Var::Day,
+            "hour" => Var::Hour,
+            "minute" => Var::Minute,
+            "second" => Var::Second,
+            "page" => Var::Page,
+            "filename" => Var::Filename,
+            "ext" => Var::Ext,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Var(Var),
+}
+
+/// The illust which the file belongs to.
+#[derive(Debug, Clone, Copy)]
+pub struct IllustInfo<'a> {
+    pub user_id: &'a str,
+    pub user_name: &'a str,
+    pub illust_id: &'a str,
+    pub title: &'a str,
+    pub illust_type: &'a str,
+}
+
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let invalid = |message: &str| {
+            error::InvalidArgument {
+                message: format!("path template {template:?}: {message}"),
+            }
+            .fail()
+        };
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return invalid("unmatched '{'"),
+                        }
+                    }
+                    let Some(var) = Var::from_name(&name) else {
+                        return invalid(&format!("unknown variable {name:?}"));
+                    };
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Var(var));
+                }
+                '}' => return invalid("unmatched '}'"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        let has = |var| segments.contains(&Segment::Var(var));
+        if !(has(Var::Filename) || has(Var::IllustId) && has(Var::Page)) {
+            return invalid("either {filename}, or both {illust_id} and {page} are required");
+        }
+        let template = Self { segments };
+        let sample = template.render_with(|_| "x".into());
+        if !Path::new(&sample)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return invalid("must be a relative path without '.' or '..'");
+        }
+        Ok(template)
+    }
+
+    fn render_with(&self, mut value: impl FnMut(Var) -> String) -> String {
+        let mut out = String::new();
+        for s in &self.segments {
+            match s {
+                Segment::Literal(l) => out.push_str(l),
+                Segment::Var(v) => out.push_str(&sanitize(&value(*v))),
+            }
+        }
+        out
+    }
+
+    /// Get the path of the file of the URL, with the variables sanitized.
+    pub fn render(&self, info: &IllustInfo, url: &IllustUrl) -> String {
+        let date: Vec<&str> = url.date.split('/').collect();
+        let date_part = |i: usize| date.get(i).copied().unwrap_or_default().to_string();
+        self.render_with(|var| match var {
+            Var::UserId => info.user_id.to_string(),
+            Var::UserName => info.user_name.to_string(),
+            Var::IllustId => info.illust_id.to_string(),
+            Var::Title => info.title.to_string(),
+            Var::Type => info.illust_type.to_string(),
+            Var::Date => url.date_without_slash(),
+            Var::Year => date_part(0),
+            Var::Month => date_part(1),
+            Var::Day => date_part(2),
+            Var::Hour => date_part(3),
+            Var::Minute => date_part(4),
+            Var::Second => date_part(5),
+            Var::Page => RE_PAGE
+                .captures(url.filename_without_ext)
+                .map_or_else(|| "0".to_string(), |c| c[1].to_string()),
+            Var::Filename => url.filename_without_ext.to_string(),
+            Var::Ext => url.ext.to_string(),
+        })
+    }
+}
+
+/// Make the value usable as a part of a file name on common file systems.
+pub fn sanitize(value: &str) -> String {
+    let replaced: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed: String = replaced
+        .trim()
+        .trim_matches('.')
+        .chars()
+        .take(MAX_VALUE_CHARS)
+        .collect();
+    let trimmed = trimmed.trim_end();
+    if trimmed.is_empty() {
+        "_".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// The templates of the paths of single-page and multi-page illusts.
+#[derive(Debug, Clone)]
+pub struct PathTemplates {
+    single_page: PathTemplate,
+    multi_page: PathTemplate,
+}
+
+impl PathTemplates {
+    pub fn new(config: &PathTemplateConfig) -> Result<Self> {
+        Ok(Self {
+            single_page: PathTemplate::parse(&config.single_page)?,
+            multi_page: PathTemplate::parse(&config.multi_page)?,
+        })
+    }
+
+    /// Get the path relative to the storage dir of the file of the illust.
+    pub fn illust_path(&self, info: &IllustInfo, url: &str, is_multi_page: bool) -> Result<String> {
+        let url = IllustUrl::new(url)?;
+        let template = if is_multi_page {
+            &self.multi_page
+        } else {
+            &self.single_page
+        };
+        let path = template.render(info, &url);
+        if path.chars().count() > MAX_PATH_CHARS {
+            return error::InvalidArgument {
+                message: format!(
+                    "path of illust {} is longer than {MAX_PATH_CHARS} characters: {path:?}",
+                    info.illust_id
+                ),
+            }
+            .fail();
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://i.pximg.net/img-original/img/2021/08/22/22/03/33/92187206_p1.jpg";
+
+    fn info() -> IllustInfo<'static> {
+        IllustInfo {
+            user_id: "123",
+            user_name: "a/b: c",
+            illust_id: "92187206",
+            title: "../..",
+            illust_type: "manga",
+        }
+    }
+
+    #[test]
+    fn test_default_templates() {
+        let templates = PathTemplates::new(&PathTemplateConfig::default()).unwrap();
+        assert_eq!(
+            templates.illust_path(&info(), URL, false).unwrap(),
+            "123/92187206_p1_20210822220333.jpg"
+        );
+        assert_eq!(
+            templates.illust_path(&info(), URL, true).unwrap(),
+            "123/92187206_20210822220333/92187206_p1.jpg"
+        );
+    }
+
+    #[test]
+    fn test_path_too_long() {
+        let templates = PathTemplates::new(&PathTemplateConfig {
+            single_page: "{title}/{title}/{title}/{title}/{filename}.{ext}".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let title = "t".repeat(MAX_VALUE_CHARS);
+        let info = IllustInfo {
+            title: &title,
+            ..info()
+        };
+        let err = templates.illust_path(&info, URL, false).unwrap_err();
+        assert!(err.to_string().contains("92187206"), "{err}");
+        let info = IllustInfo {
+            title: "title",
+            ..info
+        };
+        assert!(templates.illust_path(&info, URL, false).is_ok());
+    }
+
+    #[test]
+    fn test_render() {
+        let template = PathTemplate::parse(
+            "{user_name}/{year}-{month}/{type}/{{{title}}}_{illust_id}_p{page}.{ext}",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&info(), &IllustUrl::new(URL).unwrap()),
+            "a_b_ c/2021-08/manga/{_}_92187206_p1.jpg"
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for template in [
+            "{user_id}/{unknown}/{filename}.{ext}",
+            "{user_id}/{filename}.{ext",
+            "{user_id}}/{filename}.{ext}",
+            "{user_id}/{illust_id}.{ext}",
+            "/{user_id}/{filename}.{ext}",
+            "{user_id}/../{filename}.{ext}",
+        ] {
+            assert!(PathTemplate::parse(template).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize(" a\\b*c? "), "a_b_c_");
+        assert_eq!(sanitize(".."), "_");
+        assert_eq!(sanitize(&"あ".repeat(100)).chars().count(), MAX_VALUE_CHARS);
+    }
+}
@@ -1,8 +1,8 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use chrono::{DateTime, Utc};
 use snafu::ResultExt;
-use sqlx::{query, query_unchecked, PgExecutor, QueryBuilder};
+use sqlx::{query, query_as, query_unchecked, PgExecutor, QueryBuilder};
 
 use crate::{error, Result};
 
@@ -40,7 +40,9 @@ pub async fn set_source_inaccessible(
     query(&format!(
         "
         UPDATE {table_name}
-        SET source_inaccessible = true
+        SET source_inaccessible = true,
+            lost_at = coalesce(lost_at, now()),
+            lost_reason = coalesce(lost_reason, 'invisible')
         WHERE source_id = $1
         "
     ))
@@ -53,6 +55,91 @@ pub async fn set_source_inaccessible(
     Ok(())
 }
 
+/// Add a bookmark event of the `illust` or `novel` if its bookmark state differs from the last event.
+pub async fn log_bookmark_event(kind: &str, item_id: i64, e: impl PgExecutor<'_>) -> Result<()> {
+    query(&format!(
+        "
+        insert into pixiv.{kind}_bookmark_event (item_id, is_bookmarked, bookmark_private)
+        select i.id, coalesce(i.is_bookmarked, false), i.bookmark_private
+        from pixiv_{kind} i
+                 left join lateral (select is_bookmarked, bookmark_private
+                                    from pixiv.{kind}_bookmark_event
+                                    where item_id = i.id
+                                    order by id desc
+                                    limit 1) e on true
+        where i.id = $1
+          and (coalesce(i.is_bookmarked, false), i.bookmark_private)
+            is distinct from (coalesce(e.is_bookmarked, false), e.bookmark_private)
+        "
+    ))
+    .bind(item_id)
+    .execute(e)
+    .await
+    .with_context(|_| error::Database {
+        message: format!("log_bookmark_event: {:?}, {:?}", kind, item_id),
+    })?;
+    Ok(())
+}
+
+/// Mark the bookmarked `illust`s or `novel`s not in `source_ids` as unbookmarked,
+/// and return the number of them.
+pub async fn reconcile_bookmarks(
+    kind: &str,
+    source_ids: &[String],
+    e: impl PgExecutor<'_>,
+) -> Result<u64> {
+    let r = query(&format!(
+        "
+        with removed as (
+            update pixiv_{kind}
+                set is_bookmarked = false,
+                    bookmark_private = null,
+                    updated_at = now()
+                where is_bookmarked
+                    and not source_id = any ($1)
+                returning id)
+        insert
+        into pixiv.{kind}_bookmark_event (item_id, is_bookmarked)
+        select id, false
+        from removed
+        "
+    ))
+    .bind(source_ids)
+    .execute(e)
+    .await
+    .with_context(|_| error::Database {
+        message: format!("reconcile_bookmarks: {:?}", kind),
+    })?;
+    Ok(r.rows_affected())
+}
+
+/// Set the bookmark tags of the `illust` or `novel`, which should be already in `pixiv_tag`.
+pub async fn update_bookmark_tags(
+    kind: &str,
+    item_id: i64,
+    tags: &[String],
+    e: impl PgExecutor<'_>,
+) -> Result<()> {
+    query(&format!(
+        "
+        update pixiv_{kind}
+        set bookmark_tag_ids = (select array_agg(id) from pixiv_tag where alias && $2::varchar[])
+        where id = $1
+        "
+    ))
+    .bind(item_id)
+    .bind(tags)
+    .execute(e)
+    .await
+    .with_context(|_| error::Database {
+        message: format!(
+            "update_bookmark_tags: {:?}, {:?}, {:?}",
+            kind, item_id, tags
+        ),
+    })?;
+    Ok(())
+}
+
 pub mod user {
     use std::collections::BTreeMap;
 
@@ -274,6 +361,7 @@ pub mod tag {
 
 pub mod media {
     use bowerbird_utils::Hsv;
+    use sqlx::FromRow;
 
     use super::*;
 
@@ -361,6 +449,7 @@ pub mod media {
         Ok(())
     }
 
+    /// Insert the ugoira zip of `url`, only filling `local_path` if no other row holds it.
     pub async fn insert_ugoira(
         url: &str,
         local_path: &str,
@@ -370,8 +459,13 @@ pub mod media {
         query!(
             "
             insert into pixiv_media (url, local_path, mime, size)
-            values ($1, $2, $3, $4)
-            on conflict (url) do nothing
+            select $1,
+                case when exists(select 1 from pixiv_media where local_path = $2) then null else $2::varchar end,
+                $3,
+                $4
+            on conflict (url) do update set local_path = coalesce(pixiv_media.local_path, excluded.local_path),
+                                            mime       = excluded.mime,
+                                            size       = excluded.size
             ",
             url,
             local_path,
@@ -386,25 +480,34 @@ pub mod media {
         Ok(())
     }
 
-    pub async fn insert_ugoira_mp4(
+    /// Insert the file converted from the ugoira zip of `zip_url`.
+    pub async fn insert_ugoira_converted(
+        zip_url: &str,
         local_path: &str,
+        mime: &str,
         size: i32,
         e: impl PgExecutor<'_>,
     ) -> Result<()> {
         query!(
             "
-            insert into pixiv_media (local_path, mime, size)
-            select $1::varchar, $2, $3
-            where not exists (select id from pixiv_media where local_path = $1)
+            insert into pixiv_media (local_path, mime, size, derived_from_id)
+            values ($1, $2, $3, (select id from pixiv_media where url = $4))
+            on conflict (local_path) do update set mime            = excluded.mime,
+                                                   size            = excluded.size,
+                                                   derived_from_id = excluded.derived_from_id
             ",
             local_path,
-            "video/mp4",
-            size
+            mime,
+            size,
+            zip_url
         )
         .execute(e)
         .await
         .with_context(|_| error::Database {
-            message: format!("insert_ugoira_mp4: {:?}, {:?}", local_path, size),
+            message: format!(
+                "insert_ugoira_converted: {:?}, {:?}, {:?}, {:?}",
+                zip_url, local_path, mime, size
+            ),
         })?;
         Ok(())
     }
@@ -423,14 +526,114 @@ pub mod media {
         })?;
         Ok(r.is_some())
     }
+
+    pub async fn set_local_path(id: i64, local_path: &str, e: impl PgExecutor<'_>) -> Result<()> {
+        query!(
+            "
+            update pixiv_media set local_path = $2 where id = $1
+            ",
+            id,
+            local_path
+        )
+        .execute(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("set_local_path: {:?}, {:?}", id, local_path),
+        })?;
+        Ok(())
+    }
+
+    /// A downloaded image or ugoira zip of an illust.
+    #[derive(Debug, Clone, FromRow)]
+    pub struct IllustFileRow {
+        pub id: i64,
+        pub url: String,
+        pub local_path: String,
+        pub source_id: String,
+        pub user_source_id: String,
+        pub user_name: Option<String>,
+        pub title: Option<String>,
+        pub illust_type: Option<String>,
+        pub page_count: i64,
+    }
+
+    /// Get the downloaded files of the illusts, with the latest history linked to each of them.
+    pub async fn illust_files(e: impl PgExecutor<'_>) -> Result<Vec<IllustFileRow>> {
+        sqlx::query_as(
+            "
+            with linked as (select media_id, history_id
+                            from pixiv_illust_history_media
+                            union all
+                            select ugoira_zip_id, id
+                            from pixiv_illust_history
+                            where ugoira_zip_id is not null)
+            select distinct on (m.id) m.id,
+                                      m.url,
+                                      m.local_path,
+                                      i.source_id,
+                                      u.source_id as user_source_id,
+                                      uh.name     as user_name,
+                                      h.title,
+                                      t.name      as illust_type,
+                                      pc.count    as page_count
+            from linked l
+                     join pixiv_media m on m.id = l.media_id
+                     join pixiv_illust_history h on h.id = l.history_id
+                     join pixiv_illust i on i.id = h.item_id
+                     join pixiv_user u on u.id = i.parent_id
+                     left join pixiv_illust_history_type t on t.id = h.type_id
+                     left join lateral (select name
+                                        from pixiv_user_history
+                                        where item_id = u.id
+                                        order by id desc
+                                        limit 1) uh on true
+                     left join lateral (select count(*)
+                                        from pixiv_illust_history_media
+                                        where history_id = h.id) pc on true
+            where m.local_path is not null
+              and m.url is not null
+              and i.source_id is not null
+              and u.source_id is not null
+            order by m.id, h.id desc
+            ",
+        )
+        .fetch_all(e)
+        .await
+        .with_context(|_| error::Database {
+            message: "illust_files",
+        })
+    }
+
+    /// Get the id, the local path and the source id of the files converted from others.
+    pub async fn derived_files(e: impl PgExecutor<'_>) -> Result<Vec<(i64, String, i64)>> {
+        sqlx::query_as(
+            "
+            select id, local_path, derived_from_id
+            from pixiv_media
+            where derived_from_id is not null
+              and local_path is not null
+            ",
+        )
+        .fetch_all(e)
+        .await
+        .with_context(|_| error::Database {
+            message: "derived_files",
+        })
+    }
 }
 
 pub mod illust {
     use pixivcrab::models::illust::Illust;
+    use sqlx::FromRow;
 
     use super::*;
 
-    pub async fn upsert_item_returning_id(illust: &Illust, e: impl PgExecutor<'_>) -> Result<i64> {
+    /// `bookmark_private` is kept if unknown and the illust is still bookmarked.
+    pub async fn upsert_item_returning_id(
+        illust: &Illust,
+        bookmark_private: Option<bool>,
+        e: impl PgExecutor<'_>,
+    ) -> Result<i64> {
         let alias: Vec<String> = flatten_tags_alias(illust.tags.iter())
             .into_iter()
             .map(|x| x.to_string())
@@ -438,7 +641,8 @@ pub mod illust {
         let id = query!(
             "
             insert into pixiv_illust (parent_id, source_id, total_bookmarks, total_view,
-                                      is_bookmarked, tag_ids, source_inaccessible, updated_at)
+                                      is_bookmarked, tag_ids, source_inaccessible, updated_at,
+                                      series_id, bookmark_private, is_muted)
             values ((select id from pixiv_user where source_id = $1),
                     $2,
                     $3,
@@ -446,13 +650,23 @@ pub mod illust {
                     $5,
                     (select array_agg(id) from pixiv_tag where alias && $6::varchar[]),
                     false,
-                    now())
+                    now(),
+                    (select id from pixiv.illust_series where source_id = $7),
+                    case when $5 then $8::boolean end,
+                    $9)
             on conflict (source_id) do update set total_bookmarks     = $3,
                                                   total_view          = $4,
                                                   is_bookmarked       = $5,
                                                   tag_ids             = (select array_agg(id) from pixiv_tag where alias && $6::varchar[]),
                                                   source_inaccessible = false,
-                                                  updated_at          = now()
+                                                  updated_at          = now(),
+                                                  series_id           = excluded.series_id,
+                                                  bookmark_private    = case
+                                                                            when $5 then coalesce($8, pixiv_illust.bookmark_private)
+                                                                            end,
+                                                  lost_at             = case when $10 then null else pixiv_illust.lost_at end,
+                                                  lost_reason         = case when $10 then null else pixiv_illust.lost_reason end,
+                                                  is_muted            = $9
             returning id
             ",
             illust.user.id.to_string(),
@@ -460,7 +674,11 @@ pub mod illust {
             illust.total_bookmarks,
             illust.total_view,
             illust.is_bookmarked,
-            &alias
+            &alias,
+            illust.series.as_ref().map(|s| s.id.to_string()),
+            bookmark_private,
+            illust.is_muted,
+            illust.visible
         )
         .fetch_one(e)
         .await
@@ -471,32 +689,44 @@ pub mod illust {
         Ok(id)
     }
 
+    /// The content ratings are compared as the other fields, since pixiv may change them later.
     pub async fn insert_history_returning_id(
         item_id: i64,
         illust: &Illust,
+        ai_type: i32,
         urls: &[String],
         delay_slice: Option<&[i32]>,
+        zip_url: Option<&str>,
         e: impl PgExecutor<'_>,
     ) -> Result<Option<i64>> {
         let id = query!(
             "
-            insert into pixiv_illust_history (item_id, type_id, caption_html, title, date, ugoira_frame_duration)
+            insert into pixiv_illust_history (item_id, type_id, caption_html, title, date, ugoira_frame_duration,
+                                              ugoira_zip_id, x_restrict, sanity_level, ai_type)
             select $1,
                 (select id from pixiv_illust_history_type where name = $2::varchar),
                 $3,
                 $4::varchar,
                 $5,
-                $6
+                $6,
+                (select id from pixiv_media where url = $8),
+                $9,
+                $10,
+                $11
             where not exists(
-                    select id
-                    from pixiv_illust_detail_lateral_view
-                    where id = $1
-                    and illust_type IS NOT DISTINCT FROM $2
-                    and caption_html IS NOT DISTINCT FROM $3
-                    and title IS NOT DISTINCT FROM $4
-                    and date IS NOT DISTINCT FROM $5
-                    and ugoira_frame_duration IS NOT DISTINCT FROM $6
-                    and image_urls IS NOT DISTINCT FROM $7::varchar[]
+                    select v.id
+                    from pixiv_illust_detail_lateral_view v
+                             join pixiv_illust_history h on h.id = v.history_id
+                    where v.id = $1
+                    and v.illust_type IS NOT DISTINCT FROM $2
+                    and v.caption_html IS NOT DISTINCT FROM $3
+                    and v.title IS NOT DISTINCT FROM $4
+                    and v.date IS NOT DISTINCT FROM $5
+                    and v.ugoira_frame_duration IS NOT DISTINCT FROM $6
+                    and v.image_urls IS NOT DISTINCT FROM $7::varchar[]
+                    and h.x_restrict IS NOT DISTINCT FROM $9
+                    and h.sanity_level IS NOT DISTINCT FROM $10
+                    and h.ai_type IS NOT DISTINCT FROM $11
                 )
             returning id
             ",
@@ -506,7 +736,11 @@ pub mod illust {
             illust.title,
             illust.create_date,
             delay_slice,
-            urls
+            urls,
+            zip_url,
+            illust.x_restrict as i16,
+            illust.sanity_level as i16,
+            ai_type as i16
         )
         .fetch_optional(e)
         .await
@@ -517,6 +751,269 @@ pub mod illust {
         Ok(id)
     }
 
+    /// Link the ugoira zip to the histories of the illust saved before the zip is known.
+    pub async fn link_ugoira_zip(
+        item_id: i64,
+        zip_url: &str,
+        e: impl PgExecutor<'_>,
+    ) -> Result<()> {
+        query!(
+            "
+            update pixiv_illust_history
+            set ugoira_zip_id = (select id from pixiv_media where url = $2)
+            where item_id = $1
+              and ugoira_zip_id is null
+              and type_id = (select id from pixiv_illust_history_type where name = 'ugoira')
+            ",
+            item_id,
+            zip_url
+        )
+        .execute(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("link_ugoira_zip: {:?}, {:?}", item_id, zip_url),
+        })?;
+        Ok(())
+    }
+
+    pub async fn upsert_illust_series(
+        series: impl Iterator<Item = &pixivcrab::models::Series>,
+        e: impl PgExecutor<'_>,
+    ) -> Result<()> {
+        let (source_ids, titles): (Vec<_>, Vec<_>) =
+            series.map(|s| (s.id.to_string(), s.title.as_str())).unzip();
+
+        query_unchecked!(
+            "
+            insert into pixiv.illust_series (source_id, title)
+            select * from unnest($1::varchar[], $2::varchar[])
+            on conflict (source_id) do update set title = excluded.title
+            ",
+            &source_ids,
+            &titles
+        )
+        .execute(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("upsert_illust_series: {:?}, {:?}", source_ids, titles),
+        })?;
+
+        Ok(())
+    }
+
+    /// An illust with its latest history, author and tag names.
+    #[derive(Debug, Clone)]
+    pub struct ExportRow {
+        pub source_id: Option<String>,
+        pub title: Option<String>,
+        pub caption_html: Option<String>,
+        pub date: Option<DateTime<Utc>>,
+        pub image_paths: Option<Vec<Option<String>>>,
+        pub series_title: Option<String>,
+        pub author_name: Option<String>,
+        pub tags: Option<Vec<String>>,
+    }
+
+    pub async fn export_by_id(id: i64, e: impl PgExecutor<'_>) -> Result<Option<ExportRow>> {
+        query_as!(
+            ExportRow,
+            r#"
+            select i.source_id,
+                   i.title,
+                   i.caption_html,
+                   i.date,
+                   i.image_paths as "image_paths: Vec<Option<String>>",
+                   i.series_title,
+                   u.name as author_name,
+                   (select array_agg(t.alias[1] order by t.id)
+                    from pixiv_tag t
+                    where t.id = any (i.tag_ids)) as tags
+            from pixiv_illust_detail_latest_view i
+                     left join pixiv_user_detail_latest_view u on u.id = i.parent_id
+            where i.id = $1
+            "#,
+            id
+        )
+        .fetch_optional(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("export_by_id: {:?}", id),
+        })
+    }
+
+    pub async fn export_by_series_id(
+        series_id: i64,
+        e: impl PgExecutor<'_>,
+    ) -> Result<Vec<ExportRow>> {
+        query_as!(
+            ExportRow,
+            r#"
+            select i.source_id,
+                   i.title,
+                   i.caption_html,
+                   i.date,
+                   i.image_paths as "image_paths: Vec<Option<String>>",
+                   i.series_title,
+                   u.name as author_name,
+                   (select array_agg(t.alias[1] order by t.id)
+                    from pixiv_tag t
+                    where t.id = any (i.tag_ids)) as tags
+            from pixiv_illust_detail_latest_view i
+                     left join pixiv_user_detail_latest_view u on u.id = i.parent_id
+            where i.series_id = $1
+            order by i.date, i.id
+            "#,
+            series_id
+        )
+        .fetch_all(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("export_by_series_id: {:?}", series_id),
+        })
+    }
+
+    /// Record why the illusts are not downloaded, or clear it with `None`.
+    pub async fn set_download_skipped(
+        skipped: &[(String, Option<&str>)],
+        e: impl PgExecutor<'_>,
+    ) -> Result<()> {
+        if skipped.is_empty() {
+            return Ok(());
+        }
+        let (source_ids, reasons): (Vec<&str>, Vec<Option<&str>>) = skipped
+            .iter()
+            .map(|(id, reason)| (id.as_str(), *reason))
+            .unzip();
+        query!(
+            "
+            update pixiv_illust i
+            set download_skipped_reason = s.reason
+            from unnest($1::varchar[], $2::varchar[]) as s(source_id, reason)
+            where i.source_id = s.source_id
+              and i.download_skipped_reason is distinct from s.reason
+            ",
+            &source_ids as &[&str],
+            &reasons as &[Option<&str>]
+        )
+        .execute(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("set_download_skipped: {:?}", skipped),
+        })?;
+        Ok(())
+    }
+
+    /// What is needed to download the files of a saved illust.
+    #[derive(Debug, Clone, FromRow)]
+    pub struct DownloadRow {
+        pub source_id: String,
+        pub user_source_id: String,
+        pub user_name: Option<String>,
+        pub title: Option<String>,
+        pub illust_type: Option<String>,
+        pub image_urls: Option<Vec<Option<String>>>,
+        pub ugoira_zip_url: Option<String>,
+        pub ugoira_frame_duration: Option<Vec<i32>>,
+    }
+
+    /// Get the illusts by the ids, or all illusts skipped by the download filters if `ids` is `None`.
+    pub async fn download_rows(
+        ids: Option<&[i64]>,
+        limit: Option<i64>,
+        e: impl PgExecutor<'_>,
+    ) -> Result<Vec<DownloadRow>> {
+        sqlx::query_as(
+            "
+            select v.source_id,
+                   u.source_id as user_source_id,
+                   uh.name     as user_name,
+                   v.title,
+                   v.illust_type,
+                   v.image_urls,
+                   mz.url      as ugoira_zip_url,
+                   v.ugoira_frame_duration
+            from pixiv_illust_detail_latest_view v
+                     join pixiv_illust i on i.id = v.id
+                     join pixiv_user u on u.id = v.parent_id
+                     join pixiv_illust_history h on h.id = v.history_id
+                     left join pixiv_media mz on mz.id = h.ugoira_zip_id
+                     left join lateral (select name
+                                        from pixiv_user_history
+                                        where item_id = u.id
+                                        order by id desc
+                                        limit 1) uh on true
+            where v.source_id is not null
+              and u.source_id is not null
+              and case when $1::bigint[] is null then i.download_skipped_reason is not null else v.id = any ($1) end
+            order by v.id
+            limit $2
+            ",
+        )
+        .bind(ids)
+        .bind(limit)
+        .fetch_all(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("download_rows: {:?}", ids),
+        })
+    }
+
+    pub async fn id_by_source_id(source_id: &str, e: impl PgExecutor<'_>) -> Result<Option<i64>> {
+        Ok(query!(
+            "
+            select id from pixiv_illust where source_id = $1
+            ",
+            source_id
+        )
+        .fetch_optional(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("id_by_source_id: {:?}", source_id),
+        })?
+        .map(|r| r.id))
+    }
+
+    pub async fn series_id_by_source_id(
+        source_id: &str,
+        e: impl PgExecutor<'_>,
+    ) -> Result<Option<i64>> {
+        Ok(query!(
+            "
+            select id from pixiv.illust_series where source_id = $1
+            ",
+            source_id
+        )
+        .fetch_optional(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("series_id_by_source_id: {:?}", source_id),
+        })?
+        .map(|r| r.id))
+    }
+
+    /// Get the local paths of the illusts by their pixiv ids.
+    pub async fn paths_by_source_ids(
+        source_ids: &[String],
+        e: impl PgExecutor<'_>,
+    ) -> Result<HashMap<String, Vec<Option<String>>>> {
+        let r = query!(
+            "
+            select source_id, image_paths as \"image_paths: Vec<Option<String>>\"
+            from pixiv_illust_detail_latest_view
+            where source_id = any ($1)
+            ",
+            source_ids
+        )
+        .fetch_all(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("paths_by_source_ids: {:?}", source_ids),
+        })?;
+        Ok(r.into_iter()
+            .filter_map(|r| Some((r.source_id?, r.image_paths.unwrap_or_default())))
+            .collect())
+    }
+
     pub async fn insert_history_media(
         history_id: i64,
         media_urls: &[String],
@@ -546,13 +1043,125 @@ pub mod novel {
 
     use super::*;
 
-    pub async fn upsert_item_returning_id(n: &Novel, e: impl PgExecutor<'_>) -> Result<i64> {
+    /// A novel with its latest history, author and tag names.
+    #[derive(Debug, Clone)]
+    pub struct ExportRow {
+        pub source_id: Option<String>,
+        pub title: Option<String>,
+        pub caption_html: Option<String>,
+        pub text: Option<String>,
+        pub date: Option<DateTime<Utc>>,
+        pub series_title: Option<String>,
+        pub cover_path: Option<String>,
+        pub author_name: Option<String>,
+        pub tags: Option<Vec<String>>,
+    }
+
+    pub async fn export_by_id(id: i64, e: impl PgExecutor<'_>) -> Result<Option<ExportRow>> {
+        query_as!(
+            ExportRow,
+            "
+            select n.source_id,
+                   n.title,
+                   n.caption_html,
+                   n.text,
+                   n.date,
+                   n.series_title,
+                   n.cover_path,
+                   u.name as author_name,
+                   (select array_agg(t.alias[1] order by t.id)
+                    from pixiv_tag t
+                    where t.id = any (n.tag_ids)) as tags
+            from pixiv_novel_detail_latest_view n
+                     left join pixiv_user_detail_latest_view u on u.id = n.parent_id
+            where n.id = $1
+            ",
+            id
+        )
+        .fetch_optional(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("export_by_id: {:?}", id),
+        })
+    }
+
+    pub async fn export_by_series_id(
+        series_id: i64,
+        e: impl PgExecutor<'_>,
+    ) -> Result<Vec<ExportRow>> {
+        query_as!(
+            ExportRow,
+            "
+            select n.source_id,
+                   n.title,
+                   n.caption_html,
+                   n.text,
+                   n.date,
+                   n.series_title,
+                   n.cover_path,
+                   u.name as author_name,
+                   (select array_agg(t.alias[1] order by t.id)
+                    from pixiv_tag t
+                    where t.id = any (n.tag_ids)) as tags
+            from pixiv_novel_detail_latest_view n
+                     left join pixiv_user_detail_latest_view u on u.id = n.parent_id
+            where n.series_id = $1
+            order by n.date, n.id
+            ",
+            series_id
+        )
+        .fetch_all(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("export_by_series_id: {:?}", series_id),
+        })
+    }
+
+    pub async fn id_by_source_id(source_id: &str, e: impl PgExecutor<'_>) -> Result<Option<i64>> {
+        Ok(query!(
+            "
+            select id from pixiv_novel where source_id = $1
+            ",
+            source_id
+        )
+        .fetch_optional(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("id_by_source_id: {:?}", source_id),
+        })?
+        .map(|r| r.id))
+    }
+
+    pub async fn series_id_by_source_id(
+        source_id: &str,
+        e: impl PgExecutor<'_>,
+    ) -> Result<Option<i64>> {
+        Ok(query!(
+            "
+            select id from pixiv.novel_series where source_id = $1
+            ",
+            source_id
+        )
+        .fetch_optional(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("series_id_by_source_id: {:?}", source_id),
+        })?
+        .map(|r| r.id))
+    }
+
+    /// `bookmark_private` is kept if unknown and the novel is still bookmarked.
+    pub async fn upsert_item_returning_id(
+        n: &Novel,
+        bookmark_private: Option<bool>,
+        e: impl PgExecutor<'_>,
+    ) -> Result<i64> {
         let alias: Vec<&str> = flatten_tags_alias(n.tags.iter()).into_iter().collect();
         let id = query_unchecked!(
             "
             insert into pixiv_novel (parent_id, source_id, total_bookmarks, total_view,
                                      is_bookmarked, tag_ids, source_inaccessible, updated_at,
-                                     series_id)
+                                     series_id, bookmark_private)
             values ((select id from pixiv_user where source_id = $1),
                     $2,
                     $3,
@@ -561,14 +1170,21 @@ pub mod novel {
                     (select array_agg(id) from pixiv_tag where alias && $6::varchar[]),
                     false,
                     now(),
-                    (select id from pixiv.novel_series where source_id = $7))
+                    (select id from pixiv.novel_series where source_id = $7),
+                    case when $5 then $8::boolean end)
             on conflict (source_id) do update set total_bookmarks     = excluded.total_bookmarks,
                                                   total_view          = excluded.total_view,
                                                   is_bookmarked       = excluded.is_bookmarked,
                                                   tag_ids             = excluded.tag_ids,
                                                   source_inaccessible = false,
                                                   updated_at          = now(),
-                                                  series_id           = excluded.series_id
+                                                  series_id           = excluded.series_id,
+                                                  bookmark_private    = case
+                                                                            when excluded.is_bookmarked
+                                                                                then coalesce(excluded.bookmark_private, pixiv_novel.bookmark_private)
+                                                                            end,
+                                                  lost_at             = case when $9 then null else pixiv_novel.lost_at end,
+                                                  lost_reason         = case when $9 then null else pixiv_novel.lost_reason end
             returning id
             ",
             n.user.id.to_string(),
@@ -577,7 +1193,9 @@ pub mod novel {
             n.total_view,
             n.is_bookmarked,
             alias,
-            n.series.as_ref().map(|s| s.id.to_string())
+            n.series.as_ref().map(|s| s.id.to_string()),
+            bookmark_private,
+            n.visible
         )
         .fetch_one(e)
         .await
@@ -608,16 +1226,18 @@ pub mod novel {
         item_id: i64,
         n: &Novel,
         novel_text: &str,
+        cover_url: Option<&str>,
         e: impl PgExecutor<'_>,
     ) -> Result<()> {
         query!(
             "
-            insert into pixiv_novel_history (item_id, title, date, caption_html, text)
+            insert into pixiv_novel_history (item_id, title, date, caption_html, text, cover_id)
             select $1,
                    $2::varchar,
                    $3,
                    $4,
-                   $5
+                   $5,
+                   (select id from pixiv_media where url = $6)
             where not exists(
                     select id
                     from pixiv_novel_detail_latest_view
@@ -626,6 +1246,7 @@ pub mod novel {
                       and date IS NOT DISTINCT FROM $3
                       and caption_html IS NOT DISTINCT FROM $4
                       and text IS NOT DISTINCT FROM $5
+                      and cover_url IS NOT DISTINCT FROM $6
                 )
             ",
             item_id,
@@ -633,6 +1254,7 @@ pub mod novel {
             n.create_date,
             n.caption,
             novel_text,
+            cover_url,
         )
         .execute(e)
         .await
@@ -668,3 +1290,72 @@ pub mod novel {
         Ok(())
     }
 }
+
+pub mod recheck {
+    use super::*;
+
+    /// Get the `(id, source_id)` of the `illust`s, `novel`s or `user`s not checked since `checked_before`,
+    /// with the ids greater than `after_id`.
+    pub async fn due(
+        kind: &str,
+        checked_before: DateTime<Utc>,
+        after_id: i64,
+        limit: i64,
+        e: impl PgExecutor<'_>,
+    ) -> Result<Vec<(i64, String)>> {
+        sqlx::query_as(&format!(
+            "
+            select id, source_id
+            from pixiv_{kind}
+            where source_id is not null
+              and (checked_at is null or checked_at < $1)
+              and id > $2
+            order by id
+            limit $3
+            "
+        ))
+        .bind(checked_before)
+        .bind(after_id)
+        .bind(limit)
+        .fetch_all(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("recheck::due: {:?}", kind),
+        })
+    }
+
+    /// Record the result of the check, with the reason if the item is lost.
+    ///
+    /// `lost_at` is kept until the item is accessible again.
+    pub async fn set_checked(
+        kind: &str,
+        id: i64,
+        lost_reason: Option<&str>,
+        e: impl PgExecutor<'_>,
+    ) -> Result<()> {
+        // `source_inaccessible` of users means the details are not updated yet.
+        let source_inaccessible = if kind == "user" {
+            ""
+        } else {
+            "source_inaccessible = $2::text is not null,"
+        };
+        query(&format!(
+            "
+            update pixiv_{kind}
+            set {source_inaccessible}
+                checked_at  = now(),
+                lost_at     = case when $2::text is null then null else coalesce(lost_at, now()) end,
+                lost_reason = $2
+            where id = $1
+            "
+        ))
+        .bind(id)
+        .bind(lost_reason)
+        .execute(e)
+        .await
+        .with_context(|_| error::Database {
+            message: format!("recheck::set_checked: {:?}, {:?}, {:?}", kind, id, lost_reason),
+        })?;
+        Ok(())
+    }
+}
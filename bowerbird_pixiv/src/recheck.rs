@@ -0,0 +1,164 @@
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use log::{info, warn};
+use reqwest::StatusCode;
+use snafu::ResultExt;
+
+use crate::{
+    api::{self, WorkVisibility},
+    error,
+    queries::recheck,
+    Error, PixivKit, Result,
+};
+
+/// Why a saved item is not accessible on pixiv anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LostReason {
+    /// Not found, such as deleted by the author or the account is gone.
+    Deleted,
+    /// Made private or limited to my pixiv by the author.
+    Private,
+    /// Hidden from the logged in user by pixiv, such as restricted contents.
+    Restricted,
+}
+
+impl LostReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LostReason::Deleted => "deleted",
+            LostReason::Private => "private",
+            LostReason::Restricted => "restricted",
+        }
+    }
+}
+
+fn is_not_found(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::PixivApi {
+            source: pixivcrab::error::Error::UnexpectedStatus { status, .. }
+        } if *status == StatusCode::NOT_FOUND
+    )
+}
+
+fn work_lost_reason(r: Result<WorkVisibility>) -> Result<Option<LostReason>> {
+    match r {
+        Ok(v) if !v.visible => Ok(Some(LostReason::Restricted)),
+        Ok(v) if v.restrict != 0 => Ok(Some(LostReason::Private)),
+        Ok(_) => Ok(None),
+        Err(e) if is_not_found(&e) => Ok(Some(LostReason::Deleted)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check the item on pixiv, and return why it is lost if it is not accessible.
+async fn check(kind: &str, source_id: &str, kit: &PixivKit) -> Result<Option<LostReason>> {
+    match kind {
+        "illust" => work_lost_reason(api::illust_visibility(kit, source_id).await),
+        "novel" => work_lost_reason(api::novel_visibility(kit, source_id).await),
+        _ => match api::user_detail(kit, source_id).await {
+            Ok(_) => Ok(None),
+            Err(e) if is_not_found(&e) => Ok(Some(LostReason::Deleted)),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+/// Recheck the saved illusts, novels and users not checked within the configured interval.
+///
+/// Lost items are flagged with the reason and the time they are found lost,
+/// and the flags are cleared if they are accessible again.
+/// Items failed to be checked are left for the next run.
+pub async fn recheck(kit: &PixivKit, limit: Option<u32>) -> Result<()> {
+    let config = &kit.config.pixiv.recheck;
+    let checked_before = chrono::Duration::from_std(config.interval)
+        .ok()
+        .and_then(|d| Utc::now().checked_sub_signed(d))
+        .unwrap_or_else(|| DateTime::<Utc>::from(std::time::UNIX_EPOCH));
+    let mut remaining = limit.map(i64::from);
+
+    for kind in ["illust", "novel", "user"] {
+        let (mut checked, mut lost, mut failed) = (0, 0, 0);
+        let mut after_id = 0;
+        loop {
+            let batch_size = remaining
+                .unwrap_or(i64::MAX)
+                .min(config.batch_size.max(1) as i64);
+            if batch_size <= 0 {
+                break;
+            }
+            let items = recheck::due(kind, checked_before, after_id, batch_size, &kit.db).await?;
+            let Some((last_id, _)) = items.last() else {
+                break;
+            };
+            after_id = *last_id;
+            remaining = remaining.map(|r| r - items.len() as i64);
+
+            let results: Vec<_> = stream::iter(&items)
+                .map(|(id, source_id)| async move {
+                    (*id, source_id, check(kind, source_id, kit).await)
+                })
+                .buffer_unordered(config.concurrency.max(1))
+                .collect()
+                .await;
+
+            let mut tx = kit.db.begin().await.context(error::DatabaseTransaction)?;
+            for (id, source_id, r) in results {
+                match r {
+                    Ok(reason) => {
+                        if let Some(reason) = reason {
+                            info!("pixiv {} {} is lost: {}", kind, source_id, reason.as_str());
+                            lost += 1;
+                        }
+                        recheck::set_checked(kind, id, reason.map(LostReason::as_str), &mut tx)
+                            .await?;
+                        checked += 1;
+                    }
+                    Err(e) => {
+                        warn!("cannot recheck pixiv {} {}: {}", kind, source_id, e);
+                        failed += 1;
+                    }
+                }
+            }
+            tx.commit().await.context(error::DatabaseTransaction)?;
+            info!(
+                "rechecked pixiv {}s: {} checked, {} lost, {} failed",
+                kind, checked, lost, failed
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_work_lost_reason() {
+        let visibility = |visible, restrict| Ok(WorkVisibility { visible, restrict });
+        assert_eq!(work_lost_reason(visibility(true, 0)).unwrap(), None);
+        assert_eq!(
+            work_lost_reason(visibility(true, 2)).unwrap(),
+            Some(LostReason::Private)
+        );
+        assert_eq!(
+            work_lost_reason(visibility(false, 0)).unwrap(),
+            Some(LostReason::Restricted)
+        );
+
+        let status = |status| {
+            Err(Error::PixivApi {
+                source: pixivcrab::error::Error::UnexpectedStatus {
+                    status,
+                    text: String::new(),
+                },
+            })
+        };
+        assert_eq!(
+            work_lost_reason(status(StatusCode::NOT_FOUND)).unwrap(),
+            Some(LostReason::Deleted)
+        );
+        assert!(work_lost_reason(status(StatusCode::BAD_GATEWAY)).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Managing the downloaded files in the storage dir.
+
+use bowerbird_core::config::Config;
+use log::{info, warn};
+use path_slash::PathBufExt;
+use snafu::ResultExt;
+use sqlx::PgPool;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error,
+    path_template::{IllustInfo, PathTemplates},
+    queries::media,
+    Result,
+};
+
+/// A file to be moved, with the paths relative to the storage dir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Move {
+    id: i64,
+    from: String,
+    to: String,
+}
+
+/// Get the files whose paths differ from the ones by the templates.
+async fn plan(db: &PgPool, templates: &PathTemplates) -> Result<Vec<Move>> {
+    let mut moves = vec![];
+    let mut new_paths = HashMap::new();
+    for row in media::illust_files(db).await? {
+        let illust_type = row.illust_type.as_deref().unwrap_or_default();
+        let info = IllustInfo {
+            user_id: &row.user_source_id,
+            user_name: row.user_name.as_deref().unwrap_or_default(),
+            illust_id: &row.source_id,
+            title: row.title.as_deref().unwrap_or_default(),
+            illust_type,
+        };
+        let is_multi_page = row.page_count != 1 || illust_type == "ugoira";
+        let to = match templates.illust_path(&info, &row.url, is_multi_page) {
+            Ok(to) => to,
+            Err(e) => {
+                warn!("cannot get the new path of {}: {}", row.local_path, e);
+                continue;
+            }
+        };
+        new_paths.insert(row.id, to.clone());
+        if to != row.local_path {
+            moves.push(Move {
+                id: row.id,
+                from: row.local_path,
+                to,
+            });
+        }
+    }
+
+    // The converted files are kept next to the ugoira zips.
+    for (id, local_path, derived_from_id) in media::derived_files(db).await? {
+        let Some(source_path) = new_paths.get(&derived_from_id) else {
+            continue;
+        };
+        let mut to = PathBuf::from_slash(source_path);
+        if let Some(ext) = Path::new(&local_path).extension() {
+            to.set_extension(ext);
+        }
+        let to = to.to_slash_lossy().to_string();
+        if to != local_path {
+            moves.push(Move {
+                id,
+                from: local_path,
+                to,
+            });
+        }
+    }
+    Ok(moves)
+}
+
+/// Make sure no file is overwritten by the moves.
+fn check_conflicts(moves: &[Move], exists: impl Fn(&str) -> bool) -> Result<()> {
+    let mut targets = HashSet::new();
+    for m in moves {
+        if !targets.insert(m.to.as_str()) {
+            return error::InvalidArgument {
+                message: format!("multiple files would be moved to {}", m.to),
+            }
+            .fail();
+        }
+        if exists(&m.to) {
+            return error::InvalidArgument {
+                message: format!("{} already exists, to be replaced by {}", m.to, m.from),
+            }
+            .fail();
+        }
+    }
+    Ok(())
+}
+
+async fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|_| error::Io {
+                message: format!("cannot create {:?}", parent),
+            })?;
+    }
+    tokio::fs::rename(from, to)
+        .await
+        .with_context(|_| error::Io {
+            message: format!("cannot move {:?} to {:?}", from, to),
+        })
+}
+
+/// Move the files and update their paths in one transaction,
+/// and record the indexes of the moved ones.
+async fn move_files(
+    moves: &[Move],
+    storage_dir: &Path,
+    db: &PgPool,
+    moved: &mut Vec<usize>,
+) -> Result<()> {
+    let mut tx = db.begin().await.context(error::DatabaseTransaction)?;
+    for (i, m) in moves.iter().enumerate() {
+        let from = storage_dir.join(&m.from);
+        if tokio::fs::metadata(&from).await.is_err() {
+            warn!("skip moving {}: file not found", m.from);
+            continue;
+        }
+        media::set_local_path(m.id, &m.to, &mut tx).await?;
+        move_file(&from, &storage_dir.join(&m.to)).await?;
+        moved.push(i);
+    }
+    tx.commit().await.context(error::DatabaseTransaction)
+}
+
+/// Remove the parent dirs of the file if they are empty.
+async fn remove_empty_dirs(path: &Path, storage_dir: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == storage_dir || !d.starts_with(storage_dir) {
+            break;
+        }
+        if tokio::fs::remove_dir(d).await.is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+/// Move the downloaded illust files to the paths by the configured templates,
+/// and update their local paths in database. Return the number of the files moved.
+///
+/// Nothing is changed if any file would be overwritten, and the moved files are moved back
+/// if the paths in database cannot be updated.
+pub async fn relayout(db: &PgPool, config: &Config, dry_run: bool) -> Result<usize> {
+    let templates = PathTemplates::new(&config.pixiv.path_template)?;
+    let storage_dir = config.sub_dir(&config.pixiv.storage_dir);
+
+    let moves = plan(db, &templates).await?;
+    check_conflicts(&moves, |p| storage_dir.join(p).exists())?;
+    if dry_run {
+        for m in &moves {
+            info!("{} -> {}", m.from, m.to);
+        }
+        return Ok(moves.len());
+    }
+
+    let mut moved = vec![];
+    if let Err(e) = move_files(&moves, &storage_dir, db, &mut moved).await {
+        for m in moved.iter().rev().map(|i| &moves[*i]) {
+            if let Err(e) = move_file(&storage_dir.join(&m.to), &storage_dir.join(&m.from)).await {
+                log::error!("cannot move back {}: {}", m.to, e);
+            }
+        }
+        return Err(e);
+    }
+    for m in moved.iter().map(|i| &moves[*i]) {
+        remove_empty_dirs(&storage_dir.join(&m.from), &storage_dir).await;
+    }
+    info!("{} files moved", moved.len());
+    Ok(moved.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_conflicts() {
+        let m = |from: &str, to: &str| Move {
+            id: 0,
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        let moves = [m("a/1.jpg", "b/1.jpg"), m("a/2.jpg", "b/2.jpg")];
+        assert!(check_conflicts(&moves, |_| false).is_ok());
+        assert!(check_conflicts(&moves, |p| p == "b/2.jpg").is_err());
+        assert!(
+            check_conflicts(&[m("a/1.jpg", "c.jpg"), m("a/2.jpg", "c.jpg")], |_| false).is_err()
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use bowerbird_core::config::RateLimitConfig;
+use log::debug;
+use rand::Rng;
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Counters of the throttling since the kit is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ThrottleStats {
+    /// Requests sent to the api, including retries.
+    pub requests: u64,
+    /// Requests delayed by the rate limiter.
+    pub throttled: u64,
+    /// Total time waited by the rate limiter in milliseconds.
+    pub throttled_ms: u64,
+    /// Retries on http errors.
+    pub retries: u64,
+    /// Responses of rate limit from pixiv.
+    pub rate_limited: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: AtomicU64,
+    throttled: AtomicU64,
+    throttled_ms: AtomicU64,
+    retries: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Tokens left, negative if requests are waiting for tokens.
+    tokens: f64,
+    updated_at: Instant,
+    paused_until: Option<Instant>,
+    retry_after: Option<Duration>,
+}
+
+/// A token bucket shared by all requests to the pixiv api, with the backoff of retries.
+#[derive(Debug)]
+pub struct Throttle {
+    config: RateLimitConfig,
+    bucket: Mutex<Bucket>,
+    counters: Counters,
+}
+
+/// Get the exponential backoff of the attempt starting from 0.
+///
+/// `jitter` in `[0, 1)` randomizes the second half of the delay.
+fn backoff_delay(base: Duration, max: Duration, attempt: u32, jitter: f64) -> Duration {
+    let delay = base
+        .checked_mul(1 << attempt.min(16))
+        .unwrap_or(max)
+        .min(max);
+    delay / 2 + delay.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Parse the value of `Retry-After` in seconds or an HTTP date.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Whether the response means the rate limit of pixiv is reached.
+pub(crate) fn is_rate_limited(status: StatusCode, text: &str) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || (status == StatusCode::FORBIDDEN && text.to_ascii_lowercase().contains("rate limit"))
+}
+
+impl Throttle {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                tokens: config.burst.max(1) as f64,
+                updated_at: Instant::now(),
+                paused_until: None,
+                retry_after: None,
+            }),
+            config,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Take a token and get how long to wait before sending the request.
+    fn reserve(&self, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let paused = bucket
+            .paused_until
+            .map(|t| t.saturating_duration_since(now))
+            .unwrap_or_default();
+        if self.config.requests_per_minute == 0 {
+            return paused;
+        }
+
+        let rate = self.config.requests_per_minute as f64 / 60.0; // tokens per second
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * rate).min(self.config.burst.max(1) as f64);
+        bucket.updated_at = now;
+        bucket.tokens -= 1.0;
+
+        let refill = if bucket.tokens < 0.0 {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        } else {
+            Duration::ZERO
+        };
+        refill.max(paused)
+    }
+
+    /// Wait until the request is allowed to be sent.
+    pub async fn acquire(&self) {
+        self.counters.requests.fetch_add(1, Ordering::Relaxed);
+        let wait = self.reserve(Instant::now());
+        if !wait.is_zero() {
+            debug!("pixiv api request throttled for {:?}", wait);
+            self.counters.throttled.fetch_add(1, Ordering::Relaxed);
+            self.counters
+                .throttled_ms
+                .fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Get the delay before retrying on http errors.
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        self.counters.retries.fetch_add(1, Ordering::Relaxed);
+        backoff_delay(
+            self.config.backoff_base,
+            self.config.backoff_max,
+            attempt,
+            rand::thread_rng().gen(),
+        )
+    }
+
+    /// Remember the `Retry-After` of a response to be used by the next rate limit.
+    pub(crate) fn set_retry_after(&self, headers: &HeaderMap) {
+        let retry_after = headers
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_retry_after(v, SystemTime::now()));
+        if retry_after.is_some() {
+            self.bucket.lock().unwrap().retry_after = retry_after;
+        }
+    }
+
+    /// Pause all requests on rate limit and get the delay before retrying.
+    ///
+    /// `Retry-After` is used if it was sent, or the delay grows exponentially.
+    pub fn rate_limit_delay(&self, attempt: u32) -> Duration {
+        self.counters.rate_limited.fetch_add(1, Ordering::Relaxed);
+        let mut bucket = self.bucket.lock().unwrap();
+        let delay = bucket.retry_after.take().unwrap_or_else(|| {
+            backoff_delay(
+                self.config.rate_limit_backoff_base,
+                self.config.backoff_max,
+                attempt,
+                rand::thread_rng().gen(),
+            )
+        });
+        let until = Instant::now() + delay;
+        bucket.paused_until = Some(bucket.paused_until.map_or(until, |t| t.max(until)));
+        delay
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        ThrottleStats {
+            requests: self.counters.requests.load(Ordering::Relaxed),
+            throttled: self.counters.throttled.load(Ordering::Relaxed),
+            throttled_ms: self.counters.throttled_ms.load(Ordering::Relaxed),
+            retries: self.counters.retries.load(Ordering::Relaxed),
+            rate_limited: self.counters.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        let base = Duration::from_secs(2);
+        let max = Duration::from_secs(60);
+        assert_eq!(backoff_delay(base, max, 0, 0.0), Duration::from_secs(1));
+        assert_eq!(backoff_delay(base, max, 2, 0.0), Duration::from_secs(4));
+        assert_eq!(backoff_delay(base, max, 2, 0.5), Duration::from_secs(6));
+        assert_eq!(backoff_delay(base, max, 10, 0.0), Duration::from_secs(30));
+        assert_eq!(backoff_delay(base, max, 100, 0.0), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:00 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let throttle = Throttle::new(RateLimitConfig {
+            requests_per_minute: 60,
+            burst: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert_eq!(throttle.reserve(now), Duration::ZERO);
+        assert_eq!(throttle.reserve(now), Duration::ZERO);
+        assert_eq!(throttle.reserve(now), Duration::from_secs(1));
+        assert_eq!(throttle.reserve(now), Duration::from_secs(2));
+        // Tokens are refilled at one per second.
+        assert_eq!(
+            throttle.reserve(now + Duration::from_secs(4)),
+            Duration::ZERO
+        );
+
+        throttle.bucket.lock().unwrap().retry_after = Some(Duration::from_secs(30));
+        assert_eq!(throttle.rate_limit_delay(0), Duration::from_secs(30));
+        assert!(throttle.reserve(Instant::now()) > Duration::from_secs(29));
+        assert_eq!(throttle.stats().rate_limited, 1);
+    }
+
+    #[test]
+    fn test_is_rate_limited() {
+        assert!(is_rate_limited(StatusCode::TOO_MANY_REQUESTS, ""));
+        assert!(is_rate_limited(
+            StatusCode::FORBIDDEN,
+            r#"{"error":{"message":"Rate Limit"}}"#
+        ));
+        assert!(!is_rate_limited(StatusCode::FORBIDDEN, "forbidden"));
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::anyhow;
+use bowerbird_core::config::{UgoiraCodec, UgoiraFormat};
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Get the path of the converted file next to the ugoira zip.
+pub fn output_path(zip_path: impl AsRef<Path>, format: UgoiraFormat) -> PathBuf {
+    zip_path.as_ref().with_extension(format.extension())
+}
+
+fn ffmpeg_args(input: &[OsString], codec: &UgoiraCodec, output: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = ["-y", "-hide_banner", "-loglevel", "error"]
+        .iter()
+        .map(OsString::from)
+        .collect();
+    args.extend(input.iter().cloned());
+    if codec.exact_timing {
+        // Keep the timestamps of the frames instead of duplicating or dropping them.
+        // `-fps_mode` replaces the deprecated `-vsync` since ffmpeg 5.1.
+        args.extend(["-fps_mode", "vfr"].iter().map(OsString::from));
+    }
+    args.extend(codec.args.iter().map(OsString::from));
+    args.push(output.as_os_str().to_owned());
+    args
+}
+
+/// Build the ffconcat script with the duration of each frame.
+///
+/// The last frame is listed twice, or its duration will be ignored by the demuxer.
+fn ffconcat(frames: &[(String, i32)]) -> String {
+    let mut out = String::from("ffconcat version 1.0\n");
+    for (name, delay) in frames {
+        out.push_str(&format!(
+            "file '{}'\nduration {}.{:03}\n",
+            name.replace('\'', "'\\''"),
+            delay / 1000,
+            delay % 1000
+        ));
+    }
+    if let Some((name, _)) = frames.last() {
+        out.push_str(&format!("file '{}'\n", name.replace('\'', "'\\''")));
+    }
+    out
+}
+
+fn run_ffmpeg(
+    ffmpeg_path: &Path,
+    args: Vec<OsString>,
+    write_stdin: impl FnOnce(&mut std::process::ChildStdin) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut ffmpeg = Command::new(ffmpeg_path)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    {
+        let mut stdin = ffmpeg
+            .stdin
+            .take()
+            .expect("failed to take stdin from ffmpeg");
+        write_stdin(&mut stdin)?;
+    } // close stdin to get status
+    let status = ffmpeg.wait()?;
+    if !status.success() {
+        Err(anyhow!("ffmpeg exited with status {status}"))?
+    }
+    Ok(())
+}
+
+/// Frames are repeated to fit the delays at the frame rate of the codec.
+fn convert_resampled(
+    ffmpeg_path: &Path,
+    zip_file: &mut zip::ZipArchive<File>,
+    frame_delay: &[i32],
+    codec: &UgoiraCodec,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let input: Vec<OsString> = ["-f", "image2pipe", "-framerate"]
+        .iter()
+        .map(OsString::from)
+        .chain([codec.framerate.to_string().into(), "-i".into(), "-".into()])
+        .collect();
+
+    run_ffmpeg(ffmpeg_path, ffmpeg_args(&input, codec, output), |stdin| {
+        let frame_ms = 1000.0 / codec.framerate.max(1) as f32;
+        let mut t: f32 = 0.0; // video length in milliseconds
+        let mut frame = 0;
+        for i in 0..zip_file.len() {
+            t += *frame_delay
+                .get(i)
+                .ok_or_else(|| anyhow!("cannot get ugoira frame {i} from {frame_delay:?}"))?
+                as f32; // add delay for each frame
+            let next_frame = (t / frame_ms).round() as i32; // get the next frame count
+            for _ in frame..next_frame {
+                // repeatly push the same frame to stdin
+                let mut file = zip_file.by_index(i)?;
+                std::io::copy(&mut file, stdin)?;
+            }
+            frame = next_frame;
+        }
+        Ok(())
+    })
+}
+
+/// Frames are extracted next to the output and fed to the concat demuxer with their delays.
+fn convert_exact(
+    ffmpeg_path: &Path,
+    zip_file: &mut zip::ZipArchive<File>,
+    frame_delay: &[i32],
+    codec: &UgoiraCodec,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let mut frames_dir = output.as_os_str().to_owned();
+    frames_dir.push(".frames");
+    let frames_dir = PathBuf::from(frames_dir);
+    std::fs::create_dir_all(&frames_dir)?;
+
+    let result = (|| {
+        let mut frames = vec![];
+        for i in 0..zip_file.len() {
+            let delay = *frame_delay
+                .get(i)
+                .ok_or_else(|| anyhow!("cannot get ugoira frame {i} from {frame_delay:?}"))?;
+            let mut file = zip_file.by_index(i)?;
+            let ext = Path::new(file.name())
+                .extension()
+                .map(|e| e.to_string_lossy().to_string())
+                .unwrap_or_else(|| "jpg".to_string());
+            let name = format!("{i:06}.{ext}");
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            std::fs::write(frames_dir.join(&name), data)?;
+            frames.push((name, delay));
+        }
+        let script = frames_dir.join("frames.ffconcat");
+        std::fs::write(&script, ffconcat(&frames))?;
+
+        let input: Vec<OsString> = vec![
+            "-f".into(),
+            "concat".into(),
+            "-i".into(),
+            script.into_os_string(),
+        ];
+        run_ffmpeg(ffmpeg_path, ffmpeg_args(&input, codec, output), |_| Ok(()))
+    })();
+
+    // The error of the conversion is more useful than the one of the cleanup.
+    if let Err(e) = std::fs::remove_dir_all(&frames_dir) {
+        if result.is_ok() {
+            return Err(e.into());
+        }
+    }
+    result
+}
+
+/// Convert the ugoira zip with ffmpeg and return the path of the output.
+pub fn convert(
+    ffmpeg_path: impl AsRef<Path>,
+    zip_path: impl AsRef<Path>,
+    frame_delay: &[i32],
+    format: UgoiraFormat,
+    codec: &UgoiraCodec,
+) -> anyhow::Result<PathBuf> {
+    let zip_path = zip_path.as_ref();
+    let output = output_path(zip_path, format);
+    let mut zip_file = zip::ZipArchive::new(File::open(zip_path)?)?;
+
+    if codec.exact_timing {
+        convert_exact(
+            ffmpeg_path.as_ref(),
+            &mut zip_file,
+            frame_delay,
+            codec,
+            &output,
+        )?;
+    } else {
+        convert_resampled(
+            ffmpeg_path.as_ref(),
+            &mut zip_file,
+            frame_delay,
+            codec,
+            &output,
+        )?;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use bowerbird_core::config::UgoiraConfig;
+    use std::io::Write;
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+
+    #[test]
+    fn test_ffmpeg_args() {
+        let config = UgoiraConfig::default();
+        let output = output_path("/a/1_ugoira1920x1080.zip", UgoiraFormat::Gif);
+        assert_eq!(output, PathBuf::from("/a/1_ugoira1920x1080.gif"));
+
+        let input = vec!["-i".into(), "-".into()];
+        let args = ffmpeg_args(&input, config.codec(UgoiraFormat::Gif), &output);
+        let args: Vec<_> = args.iter().map(|a| a.to_string_lossy()).collect();
+        assert_eq!(args[4..6], ["-i", "-"]);
+        assert!(!args.contains(&"vfr".into()));
+        assert_eq!(args.last().unwrap(), "/a/1_ugoira1920x1080.gif");
+    }
+
+    #[test]
+    fn test_ffconcat() {
+        let frames = vec![
+            ("000000.jpg".to_string(), 80),
+            ("000001.jpg".to_string(), 1250),
+        ];
+        assert_eq!(
+            ffconcat(&frames),
+            "ffconcat version 1.0\n\
+             file '000000.jpg'\nduration 0.080\n\
+             file '000001.jpg'\nduration 1.250\n\
+             file '000001.jpg'\n"
+        );
+    }
+
+    /// Get the timestamps in milliseconds of the video frames with ffprobe.
+    fn probe_timestamps(path: &Path) -> Vec<i64> {
+        let output = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                "-show_entries",
+                "frame=best_effort_timestamp_time",
+                "-of",
+                "csv=p=0",
+            ])
+            .arg(path)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|l| l.trim().trim_end_matches(',').parse::<f64>().ok())
+            .map(|t| (t * 1000.0).round() as i64)
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg and ffprobe"]
+    fn test_exact_timing() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let zip_path = tempdir.path().join("1_ugoira600x600.zip");
+
+        // Make frames of different colors with ffmpeg.
+        let mut zip = ZipWriter::new(File::create(&zip_path).unwrap());
+        for (i, color) in ["red", "green", "blue", "white"].iter().enumerate() {
+            let frame = tempdir.path().join(format!("{i}.png"));
+            let status = Command::new("ffmpeg")
+                .args(["-y", "-loglevel", "error", "-f", "lavfi", "-i"])
+                .arg(format!("color=c={color}:s=16x16"))
+                .args(["-frames:v", "1"])
+                .arg(&frame)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            zip.start_file(format!("{i:06}.png"), FileOptions::default())
+                .unwrap();
+            zip.write_all(&std::fs::read(&frame).unwrap()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let delay = [70, 130, 1000, 45];
+        let codec = UgoiraCodec {
+            exact_timing: true,
+            ..UgoiraCodec::default_for(UgoiraFormat::Apng)
+        };
+        let output = convert("ffmpeg", &zip_path, &delay, UgoiraFormat::Apng, &codec).unwrap();
+        assert!(!tempdir.path().join("1_ugoira600x600.apng.frames").exists());
+
+        let timestamps = probe_timestamps(&output);
+        assert_eq!(timestamps[..4], [0, 70, 200, 1200]);
+    }
+}
@@ -1,12 +1,6 @@
-use anyhow::anyhow;
 use chrono::NaiveDate;
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::{
-    fs::File,
-    path::{Path, PathBuf},
-    process::{Command, Stdio},
-};
 use url::Url;
 
 use crate::{error, Result};
@@ -62,73 +56,6 @@ impl<'a> IllustUrl<'a> {
     }
 }
 
-pub fn ugoira_to_mp4(
-    ffmpeg_path: impl AsRef<Path>,
-    zip_path: impl AsRef<Path>,
-    frame_delay: Vec<i32>,
-) -> anyhow::Result<PathBuf> {
-    let zip_path = zip_path.as_ref();
-    let mut mp4_path = PathBuf::from(zip_path);
-
-    let mut file = File::open(zip_path)?;
-    let mut zip_file = zip::ZipArchive::new(&mut file)?;
-    mp4_path.set_extension("mp4");
-
-    let mut ffmpeg = Command::new(ffmpeg_path.as_ref())
-        .args([
-            "-y",
-            "-hide_banner",
-            "-loglevel",
-            "error",
-            "-f",
-            "image2pipe",
-            "-framerate",
-            "60",
-            "-i",
-            "-",
-            "-c:v",
-            "libx264",
-            "-preset",
-            "slow",
-            "-crf",
-            "22",
-            "-pix_fmt",
-            "yuv420p",
-            "-vf",
-            "pad=ceil(iw/2)*2:ceil(ih/2)*2",
-        ])
-        .arg(mp4_path.as_os_str())
-        .stdin(Stdio::piped())
-        .spawn()?;
-    {
-        let mut stdin = ffmpeg
-            .stdin
-            .take()
-            .expect("failed to take stdin from ffmpeg");
-
-        let mut t: f32 = 0.0; // video length in milliseconds
-        let mut frame = 0;
-        for i in 0..zip_file.len() {
-            t += *frame_delay
-                .get(i)
-                .ok_or_else(|| anyhow!("cannot get ugoira frame {i} from {frame_delay:?}"))?
-                as f32; // add delay for each frame
-            let next_frame = (t / (1000.0 / 60.0)).round() as i32; // get the next frame count at 60fps
-            for _ in frame..next_frame {
-                // repeatly push the same frame to stdin
-                let mut file = zip_file.by_index(i)?;
-                std::io::copy(&mut file, &mut stdin)?;
-            }
-            frame = next_frame;
-        }
-    } // close stdin to get status
-    let status = ffmpeg.wait()?;
-    if !status.success() {
-        Err(anyhow!("ffmpeg exited with status {status}"))?
-    }
-    Ok(mp4_path)
-}
-
 pub fn filename_from_url(url: &str) -> Result<String> {
     let filename = (|| -> Option<String> {
         Some(Url::parse(url).ok()?.path_segments()?.last()?.to_string())
@@ -0,0 +1,477 @@
+//! Authentication by API tokens, and the browser sessions logged in with them.
+//!
+//! Only the SHA-256 hashes of the tokens and the sessions are stored in database.
+
+use actix_web::{
+    body::EitherBody,
+    cookie::{time::Duration, Cookie, SameSite},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, StatusCode},
+    post,
+    web::{Data, Json},
+    FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use bowerbird_core::config::Config;
+use chrono::{DateTime, Utc};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use rand::RngCore;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, FromRow, PgPool};
+use std::{fmt, rc::Rc, str::FromStr};
+
+use super::{error::*, openapi::Api};
+
+pub const SESSION_COOKIE: &str = "bowerbird_session";
+const TOKEN_PREFIX: &str = "bb_";
+const SESSION_PREFIX: &str = "bbs_";
+
+/// What a token is allowed to do, ordered by the privilege.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Only the requests which do not change anything.
+    Read,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("unknown scope {s:?}, expected read or admin")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scope: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn new_secret(prefix: &str) -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{prefix}{}", hex::encode(bytes))
+}
+
+/// Issue a token, and return its id and the token which can not be got again.
+pub async fn create_token(db: &PgPool, name: &str, scope: Scope) -> sqlx::Result<(i64, String)> {
+    let token = new_secret(TOKEN_PREFIX);
+    let (id,): (i64,) = query_as(
+        "
+        insert into api_token (name, token_hash, scope)
+        values ($1, $2, $3)
+        returning id
+        ",
+    )
+    .bind(name)
+    .bind(hash_secret(&token))
+    .bind(scope.as_str())
+    .fetch_one(db)
+    .await?;
+    Ok((id, token))
+}
+
+pub async fn list_tokens(db: &PgPool) -> sqlx::Result<Vec<ApiToken>> {
+    query_as(
+        "
+        select id, name, scope, created_at, last_used_at, revoked_at
+        from api_token
+        order by id
+        ",
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Revoke the token and log out its sessions. Return false if there is no such active token.
+pub async fn revoke_token(db: &PgPool, id: i64) -> sqlx::Result<bool> {
+    let mut tx = db.begin().await?;
+    let revoked =
+        query("update api_token set revoked_at = now() where id = $1 and revoked_at is null")
+            .bind(id)
+            .execute(&mut tx)
+            .await?
+            .rows_affected()
+            > 0;
+    query("delete from api_session where token_id = $1")
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(revoked)
+}
+
+/// Get the id and the scope of the active token.
+async fn token_scope(db: &PgPool, token: &str) -> sqlx::Result<Option<(i64, Scope)>> {
+    let row: Option<(i64, String)> = query_as(
+        "
+        select id, scope
+        from api_token
+        where token_hash = $1
+          and revoked_at is null
+        ",
+    )
+    .bind(hash_secret(token))
+    .fetch_optional(db)
+    .await?;
+    let Some((id, scope)) = row else {
+        return Ok(None);
+    };
+    // Not to write on every request.
+    query(
+        "
+        update api_token
+        set last_used_at = now()
+        where id = $1
+          and (last_used_at is null or last_used_at < now() - interval '1 minute')
+        ",
+    )
+    .bind(id)
+    .execute(db)
+    .await?;
+    Ok(scope.parse().ok().map(|s| (id, s)))
+}
+
+async fn session_scope(db: &PgPool, session: &str) -> sqlx::Result<Option<Scope>> {
+    let row: Option<(String,)> = query_as(
+        "
+        select t.scope
+        from api_session s
+                 join api_token t on t.id = s.token_id
+        where s.session_hash = $1
+          and s.expires_at > now()
+          and t.revoked_at is null
+        ",
+    )
+    .bind(hash_secret(session))
+    .fetch_optional(db)
+    .await?;
+    Ok(row.and_then(|(s,)| s.parse().ok()))
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Check the credentials of the request, from the `Authorization` header or the session cookie.
+async fn authorize(db: &PgPool, req: &HttpRequest) -> super::Result<Scope> {
+    let scope = if let Some(token) = bearer_token(req) {
+        token_scope(db, token).await.with_interal()?.map(|x| x.1)
+    } else if let Some(session) = req.cookie(SESSION_COOKIE) {
+        session_scope(db, session.value()).await.with_interal()?
+    } else {
+        None
+    };
+    let Some(scope) = scope else {
+        return Err(Error::with_msg(
+            StatusCode::UNAUTHORIZED,
+            "missing or invalid credentials",
+        ));
+    };
+    Ok(scope)
+}
+
+/// Whether the request has valid credentials, for the public routes showing more to them.
+pub async fn is_authenticated(db: &PgPool, req: &HttpRequest) -> bool {
+    authorize(db, req).await.is_ok()
+}
+
+/// Extractor of the handlers which change anything, requiring the `admin` scope.
+///
+/// The scope of the request is set by [`RequireAuth`],
+/// so the requests are allowed if the authentication is disabled.
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let scope = req.extensions().get::<Scope>().copied();
+        ready(match scope {
+            Some(scope) if scope < Scope::Admin => Err(Error::with_msg(
+                StatusCode::FORBIDDEN,
+                "the token is not allowed to make this request",
+            )),
+            _ => Ok(Admin),
+        })
+    }
+}
+
+/// Middleware rejecting the requests without valid credentials.
+pub struct RequireAuth {
+    db: PgPool,
+}
+
+impl RequireAuth {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RequireAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireAuthMiddleware {
+            service: Rc::new(service),
+            db: self.db.clone(),
+        }))
+    }
+}
+
+pub struct RequireAuthMiddleware<S> {
+    service: Rc<S>,
+    db: PgPool,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let db = self.db.clone();
+        Box::pin(async move {
+            match authorize(&db, req.request()).await {
+                Ok(scope) => {
+                    req.extensions_mut().insert(scope);
+                    service
+                        .call(req)
+                        .await
+                        .map(ServiceResponse::map_into_left_body)
+                }
+                Err(e) => {
+                    let mut res = req.error_response(e);
+                    if res.status() == StatusCode::UNAUTHORIZED {
+                        res.headers_mut().insert(
+                            header::WWW_AUTHENTICATE,
+                            header::HeaderValue::from_static("Bearer"),
+                        );
+                    }
+                    Ok(res.map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+struct LoginForm {
+    token: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+struct LoginResponse {
+    scope: Scope,
+}
+
+/// Log in with an API token, for the browsers which can not set the `Authorization` header,
+/// such as loading images.
+#[post("/login")]
+async fn login(
+    form: Json<LoginForm>,
+    db: Data<PgPool>,
+    config: Data<Config>,
+) -> super::Result<HttpResponse> {
+    let Some((token_id, scope)) = token_scope(&db, &form.token).await.with_interal()? else {
+        return Err(Error::with_msg(StatusCode::UNAUTHORIZED, "invalid token"));
+    };
+
+    let ttl_hours = config.server.session_ttl_hours;
+    let session = new_secret(SESSION_PREFIX);
+    query("delete from api_session where expires_at <= now()")
+        .execute(db.as_ref())
+        .await
+        .with_interal()?;
+    query(
+        "
+        insert into api_session (session_hash, token_id, expires_at)
+        values ($1, $2, now() + make_interval(hours => $3))
+        ",
+    )
+    .bind(hash_secret(&session))
+    .bind(token_id)
+    .bind(ttl_hours as i32)
+    .execute(db.as_ref())
+    .await
+    .with_interal()?;
+
+    let cookie = Cookie::build(SESSION_COOKIE, session)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(Duration::hours(ttl_hours.into()))
+        .finish();
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .json(LoginResponse { scope }))
+}
+
+#[post("/logout")]
+async fn logout(req: HttpRequest, db: Data<PgPool>) -> super::Result<HttpResponse> {
+    if let Some(session) = req.cookie(SESSION_COOKIE) {
+        query("delete from api_session where session_hash = $1")
+            .bind(hash_secret(session.value()))
+            .execute(db.as_ref())
+            .await
+            .with_interal()?;
+    }
+    let mut res = HttpResponse::NoContent().finish();
+    let mut cookie = Cookie::named(SESSION_COOKIE);
+    cookie.set_path("/");
+    res.add_removal_cookie(&cookie).with_interal()?;
+    Ok(res)
+}
+
+pub(crate) fn openapi(api: &mut Api) {
+    const TAG: &str = "auth";
+    api.route("post", "/auth/login", TAG, "Log in with an API token")
+        .body::<LoginForm>()
+        .json::<LoginResponse>(200, "The session cookie is set")
+        .public();
+    api.route("post", "/auth/logout", TAG, "Log out the session")
+        .empty(204, "The session cookie is removed")
+        .public();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_scope() {
+        assert!(Scope::Admin > Scope::Read);
+        assert_eq!("admin".parse::<Scope>(), Ok(Scope::Admin));
+        assert!("write".parse::<Scope>().is_err());
+    }
+
+    /// A `read` token can search but not start jobs, with a migrated database at `DATABASE_URL`.
+    #[actix_web::test]
+    async fn test_read_token() {
+        use crate::{jobs, pixiv};
+        use actix_web::{test, web, App};
+
+        let db = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
+            .await
+            .unwrap();
+        let (id, token) = create_token(&db, "test_read_token", Scope::Read)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new().app_data(Data::new(db.clone())).service(
+                web::scope("/pixiv")
+                    .wrap(RequireAuth::new(db.clone()))
+                    .service(pixiv::find_illust)
+                    .service(jobs::start_job),
+            ),
+        )
+        .await;
+        let bearer = (header::AUTHORIZATION, format!("Bearer {token}"));
+
+        let req = test::TestRequest::post()
+            .uri("/pixiv/illust/find")
+            .insert_header(bearer.clone())
+            .set_json(serde_json::json!({ "limit": 1, "offset": 0 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/pixiv/jobs")
+            .insert_header(bearer)
+            .set_json(serde_json::json!({ "kind": "illust_bookmarks" }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/pixiv/illust/find")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        query("delete from api_token where id = $1")
+            .bind(id)
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer bb_abc"))
+            .to_http_request();
+        assert_eq!(bearer_token(&req), Some("bb_abc"));
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Basic YTpi"))
+            .to_http_request();
+        assert_eq!(bearer_token(&req), None);
+    }
+
+    #[test]
+    fn test_secret() {
+        let token = new_secret(TOKEN_PREFIX);
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_ne!(token, new_secret(TOKEN_PREFIX));
+        assert_eq!(hash_secret(&token).len(), 64);
+        assert_eq!(hash_secret(&token), hash_secret(&token));
+    }
+}
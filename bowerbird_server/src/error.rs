@@ -1,17 +1,67 @@
-use actix_web::http::StatusCode;
+use actix_web::{http::StatusCode, HttpResponse};
 use log::error;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::fmt::{self, Debug};
 
+use super::request_id;
+
+/// Machine readable kind of the error, in the JSON body of the error responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    /// The request is well-formed but some of its fields are invalid.
+    Validation,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    RangeNotSatisfiable,
+    /// The database can not be reached, the request may be retried later.
+    DbUnavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::FORBIDDEN => Self::Forbidden,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::CONFLICT => Self::Conflict,
+            StatusCode::RANGE_NOT_SATISFIABLE => Self::RangeNotSatisfiable,
+            s if s.is_server_error() => Self::Internal,
+            _ => Self::BadRequest,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Error {
+    pub code: ErrorCode,
     pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
     #[serde(skip)]
     pub status: StatusCode,
     #[serde(skip)]
     pub source: Option<anyhow::Error>,
 }
 
+/// The body of the error responses.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ErrorBody<'a> {
+    pub code: ErrorCode,
+    pub message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<&'a Value>,
+    /// Also in the `X-Request-Id` header, to find the request in the logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}: {}", self.status, self.message)
@@ -27,6 +77,7 @@ impl Error {
         print_source: bool,
     ) -> Error {
         Error {
+            code: ErrorCode::from_status(status),
             status,
             message: if print_source {
                 if !message.is_empty() {
@@ -37,14 +88,17 @@ impl Error {
             } else {
                 message.to_string()
             },
+            details: None,
             source: Some(anyhow::anyhow!(source)),
         }
     }
 
     pub fn with_msg(status: StatusCode, message: &str) -> Error {
         Error {
+            code: ErrorCode::from_status(status),
             status,
             message: message.to_string(),
+            details: None,
             source: None,
         }
     }
@@ -52,10 +106,32 @@ impl Error {
     pub fn not_found() -> Error {
         Error::with_msg(StatusCode::NOT_FOUND, "not found in database")
     }
+
+    /// The field of the request is invalid.
+    pub fn validation(field: &str, message: &str) -> Error {
+        Error::with_msg(StatusCode::BAD_REQUEST, &format!("{field}: {message}"))
+            .with_code(ErrorCode::Validation)
+            .with_details(serde_json::json!({ "field": field }))
+    }
+
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = code;
+        self
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
 }
 impl actix_web::error::ResponseError for Error {
-    fn error_response(&self) -> actix_web::HttpResponse {
-        actix_web::HttpResponse::build(self.status_code()).body(self.message.clone())
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            code: self.code,
+            message: &self.message,
+            details: self.details.as_ref(),
+            request_id: request_id::current(),
+        })
     }
 
     fn status_code(&self) -> StatusCode {
@@ -63,6 +139,13 @@ impl actix_web::error::ResponseError for Error {
     }
 }
 
+/// Render the errors of the extractors, e.g. the malformed JSON or query, as [`Error`].
+pub fn extractor_error(err: impl fmt::Display) -> actix_web::Error {
+    Error::with_msg(StatusCode::BAD_REQUEST, &err.to_string())
+        .with_code(ErrorCode::Validation)
+        .into()
+}
+
 pub trait ServerErrorExt<T>
 where
     Self: Sized,
@@ -94,7 +177,18 @@ where
 
     fn with_interal(self) -> Result<T, Error> {
         self.map_err(|err| {
-            error!("Internal Server Error: {}", err);
+            let request_id = request_id::current().unwrap_or_default();
+            if is_db_unavailable(&err) {
+                error!("[{request_id}] Database unavailable: {}", err);
+                return Error::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "database unavailable",
+                    err,
+                    false,
+                )
+                .with_code(ErrorCode::DbUnavailable);
+            }
+            error!("[{request_id}] Internal Server Error: {}", err);
             Error::new(
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "internal server error",
@@ -105,6 +199,14 @@ where
     }
 }
 
+/// Whether the error is the database not reachable, rather than a failed query.
+fn is_db_unavailable(err: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        err.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_))
+    )
+}
+
 // pub struct StrErr(pub &'static str);
 // impl Display for StrErr {
 //     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -0,0 +1,179 @@
+//! Health and readiness of the server for the orchestrators.
+//!
+//! `/healthz` answers as long as the server is serving without checking anything,
+//! and `/readyz` fails with 503 if any of the critical checks fails.
+//! The details of the checks are only shown to the authenticated requests.
+
+use actix_web::{get, http::StatusCode, web::Data, HttpRequest, HttpResponse};
+use bowerbird_core::config::Config;
+use bowerbird_pixiv::PixivKit;
+use futures::{future, Future};
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt::Display, path::Path, time::Duration};
+use tokio::time::timeout;
+
+use super::{auth, PixivConfig};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    /// Only the checks not critical failed.
+    Degraded,
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Check {
+    ok: bool,
+    /// Whether the server is not ready if the check fails.
+    critical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Report {
+    status: Status,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+impl Report {
+    fn new(checks: BTreeMap<&'static str, Check>) -> Self {
+        let failed = checks.values().filter(|c| !c.ok);
+        let status = match failed.map(|c| c.critical).max() {
+            None => Status::Ok,
+            Some(false) => Status::Degraded,
+            Some(true) => Status::Unavailable,
+        };
+        Self { status, checks }
+    }
+
+    fn hide_details(&mut self) {
+        for check in self.checks.values_mut() {
+            check.detail = None;
+        }
+    }
+}
+
+/// Run the check with a timeout, with the detail of the success or the error.
+async fn check<F, E>(critical: bool, f: F) -> Check
+where
+    F: Future<Output = Result<Option<String>, E>>,
+    E: Display,
+{
+    let (ok, detail) = match timeout(CHECK_TIMEOUT, f).await {
+        Ok(Ok(detail)) => (true, detail),
+        Ok(Err(e)) => (false, Some(e.to_string())),
+        Err(_) => (false, Some(format!("timed out in {CHECK_TIMEOUT:?}"))),
+    };
+    Check {
+        ok,
+        critical,
+        detail,
+    }
+}
+
+async fn check_storage(storage_dir: &Path) -> Result<Option<String>, String> {
+    let meta = tokio::fs::metadata(storage_dir)
+        .await
+        .map_err(|e| format!("cannot access the storage dir: {e}"))?;
+    if !meta.is_dir() {
+        return Err("the storage dir is not a directory".to_string());
+    }
+    if meta.permissions().readonly() {
+        return Err("the storage dir is read-only".to_string());
+    }
+    let read = async { tokio::fs::read_dir(storage_dir).await?.next_entry().await };
+    read.await
+        .map_err(|e| format!("cannot read the storage dir: {e}"))?;
+    Ok(None)
+}
+
+async fn report(kit: &PixivKit, storage_dir: &Path) -> Report {
+    let (postgres, migrations, storage, aria2, ffmpeg) = future::join5(
+        check(true, async {
+            sqlx::query("select 1").execute(&kit.db).await.map(|_| None)
+        }),
+        check(true, async {
+            let pending = bowerbird_core::pending_migrations(&kit.db)
+                .await
+                .map_err(|e| e.to_string())?;
+            if !pending.is_empty() {
+                return Err(format!("pending migrations: {pending:?}"));
+            }
+            Ok(None)
+        }),
+        check(true, check_storage(storage_dir)),
+        check(true, async { kit.downloader.version().await.map(Some) }),
+        // Only the ugoira conversion depends on ffmpeg, which is found when the kit is made.
+        check(false, async {
+            match &kit.task_config.ffmpeg_path {
+                Some(path) => Ok(Some(path.display().to_string())),
+                None => Err("ffmpeg not found"),
+            }
+        }),
+    )
+    .await;
+    Report::new(BTreeMap::from([
+        ("postgres", postgres),
+        ("migrations", migrations),
+        ("storage", storage),
+        ("aria2", aria2),
+        ("ffmpeg", ffmpeg),
+    ]))
+}
+
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+#[get("/readyz")]
+async fn readyz(
+    req: HttpRequest,
+    kit: Data<PixivKit>,
+    config: Data<Config>,
+    pixiv_config: Data<PixivConfig>,
+) -> HttpResponse {
+    let mut report = report(&kit, &pixiv_config.storage_dir).await;
+    if config.server.require_auth && !auth::is_authenticated(&kit.db, &req).await {
+        report.hide_details();
+    }
+    let status = match report.status {
+        Status::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    HttpResponse::build(status).json(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_report() {
+        let ok = check(true, async { Ok::<_, String>(None) }).await;
+        let failed = check(false, async { Err("not found") }).await;
+        assert_eq!(failed.detail.as_deref(), Some("not found"));
+        let critical = Check {
+            critical: true,
+            ..failed.clone()
+        };
+
+        let report = Report::new(BTreeMap::from([("a", ok.clone())]));
+        assert_eq!(report.status, Status::Ok);
+        let report = Report::new(BTreeMap::from([("a", ok), ("b", failed.clone())]));
+        assert_eq!(report.status, Status::Degraded);
+        let mut report = Report::new(BTreeMap::from([("a", failed), ("b", critical)]));
+        assert_eq!(report.status, Status::Unavailable);
+        report.hide_details();
+        assert!(report.checks.values().all(|c| c.detail.is_none()));
+
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_storage(dir.path()).await.is_ok());
+        assert!(check_storage(&dir.path().join("missing")).await.is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! Sync jobs started over HTTP, with their progress streamed as server-sent events.
+
+use actix_web::{
+    delete, get,
+    http::{
+        header::{CacheControl, CacheDirective},
+        StatusCode,
+    },
+    post,
+    web::{self, Data, Json},
+    HttpResponse,
+};
+use bowerbird_pixiv::{
+    database, illust_bookmarks, illust_uploads,
+    job::{with_progress, Progress, ProgressSnapshot},
+    novel_bookmarks, novel_uploads, PixivKit,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream;
+use log::{error, info};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{runtime::Handle, task::JoinHandle};
+
+use super::{auth::Admin, error::*, openapi::Api, Result};
+
+/// Max number of the finished jobs kept to be listed.
+const MAX_FINISHED_JOBS: usize = 50;
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The sync job to start, with the same options as the CLI.
+///
+/// `user_id` is the logged in user if not set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobRequest {
+    IllustBookmarks {
+        user_id: Option<String>,
+        limit: Option<u32>,
+        #[serde(default)]
+        private: bool,
+        #[serde(default)]
+        reconcile: bool,
+        tag: Option<String>,
+    },
+    IllustUploads {
+        user_id: Option<String>,
+        limit: Option<u32>,
+    },
+    NovelBookmarks {
+        user_id: Option<String>,
+        limit: Option<u32>,
+        #[serde(default)]
+        update_exists: bool,
+        #[serde(default)]
+        private: bool,
+        #[serde(default)]
+        reconcile: bool,
+        tag: Option<String>,
+    },
+    NovelUploads {
+        user_id: Option<String>,
+        limit: Option<u32>,
+        #[serde(default)]
+        update_exists: bool,
+    },
+    /// Update the details of the users.
+    Users { user_ids: Vec<String> },
+}
+
+impl JobRequest {
+    async fn run(&self, kit: &PixivKit) -> std::result::Result<(), bowerbird_pixiv::Error> {
+        let target = |user_id: &Option<String>| {
+            user_id
+                .clone()
+                .unwrap_or_else(|| kit.current_user_id().to_string())
+        };
+        match self {
+            JobRequest::IllustBookmarks {
+                user_id,
+                limit,
+                private,
+                reconcile,
+                tag,
+            } => {
+                let user_id = target(user_id);
+                illust_bookmarks(kit, &user_id, *limit, *private, *reconcile, tag.as_deref()).await
+            }
+            JobRequest::IllustUploads { user_id, limit } => {
+                illust_uploads(kit, &target(user_id), *limit).await
+            }
+            JobRequest::NovelBookmarks {
+                user_id,
+                limit,
+                update_exists,
+                private,
+                reconcile,
+                tag,
+            } => {
+                let user_id = target(user_id);
+                novel_bookmarks(
+                    kit,
+                    &user_id,
+                    *limit,
+                    *update_exists,
+                    *private,
+                    *reconcile,
+                    tag.as_deref(),
+                )
+                .await
+            }
+            JobRequest::NovelUploads {
+                user_id,
+                limit,
+                update_exists,
+            } => novel_uploads(kit, &target(user_id), *limit, *update_exists).await,
+            JobRequest::Users { user_ids } => {
+                database::update_user_id_set(user_ids.iter().cloned().collect(), kit).await
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(tag = "state", content = "error", rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Finished,
+    Failed(String),
+    Cancelled,
+}
+
+struct Job {
+    request: JobRequest,
+    started_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    state: JobState,
+    progress: Arc<Progress>,
+    handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct JobInfo {
+    id: u64,
+    request: JobRequest,
+    started_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    state: JobState,
+    progress: ProgressSnapshot,
+    /// Downloads not completed yet, shared by all the jobs.
+    downloads_pending: usize,
+}
+
+/// The running jobs, and the latest finished ones.
+pub struct JobManager {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    /// The runtime the jobs are spawned on, instead of the workers of the server.
+    runtime: Handle,
+}
+
+impl JobManager {
+    pub fn new(runtime: Handle) -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            jobs: Mutex::default(),
+            runtime,
+        }
+    }
+
+    /// Spawn the job, and return its id.
+    ///
+    /// The job is rejected if the same one is running.
+    fn start(manager: Data<Self>, kit: Data<PixivKit>, request: JobRequest) -> Result<u64> {
+        let mut jobs = manager.jobs.lock().unwrap();
+        if let Some(id) = running_job(&jobs, &request) {
+            return Err(Error::with_msg(
+                StatusCode::CONFLICT,
+                &format!("the same job {id} is running"),
+            ));
+        }
+        let id = manager.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let progress = Arc::new(Progress::default());
+        jobs.insert(
+            id,
+            Job {
+                request: request.clone(),
+                started_at: Utc::now(),
+                finished_at: None,
+                state: JobState::Running,
+                progress: progress.clone(),
+                handle: None,
+            },
+        );
+
+        info!("job {} started: {:?}", id, request);
+        let handle = manager.runtime.spawn({
+            let manager = manager.clone();
+            async move {
+                let state = match with_progress(progress.clone(), request.run(&kit)).await {
+                    Ok(()) => JobState::Finished,
+                    Err(e) => {
+                        error!("job {} failed: {}", id, e);
+                        progress.record_error(&e);
+                        JobState::Failed(e.to_string())
+                    }
+                };
+                manager.finish(id, state);
+            }
+        });
+        if let Some(job) = jobs.get_mut(&id) {
+            job.handle = Some(handle);
+        }
+        Ok(id)
+    }
+
+    fn finish(&self, id: u64, state: JobState) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            if job.state == JobState::Running {
+                info!("job {} {:?}", id, state);
+                job.state = state;
+                job.finished_at = Some(Utc::now());
+                job.handle = None;
+            }
+        }
+        prune(&mut jobs);
+    }
+
+    /// Abort the job and the tasks spawned by it. The downloads already queued are not cancelled.
+    fn cancel(&self, id: u64) -> Result<()> {
+        let handle = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = jobs.get_mut(&id).ok_or_else(Error::not_found)?;
+            if job.state != JobState::Running {
+                return Err(Error::with_msg(StatusCode::CONFLICT, "job is not running"));
+            }
+            job.progress.cancel();
+            job.handle.take()
+        };
+        if let Some(handle) = handle {
+            handle.abort();
+        }
+        self.finish(id, JobState::Cancelled);
+        Ok(())
+    }
+
+    fn info(&self, id: u64, kit: &PixivKit) -> Option<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(&id).map(|job| job_info(id, job, kit))
+    }
+
+    fn list(&self, kit: &PixivKit) -> Vec<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter()
+            .rev()
+            .map(|(id, job)| job_info(*id, job, kit))
+            .collect()
+    }
+}
+
+fn job_info(id: u64, job: &Job, kit: &PixivKit) -> JobInfo {
+    JobInfo {
+        id,
+        request: job.request.clone(),
+        started_at: job.started_at,
+        finished_at: job.finished_at,
+        state: job.state.clone(),
+        progress: job.progress.snapshot(),
+        downloads_pending: kit.downloader.pending_tasks(),
+    }
+}
+
+/// Get the id of the running job of the same request.
+fn running_job(jobs: &BTreeMap<u64, Job>, request: &JobRequest) -> Option<u64> {
+    jobs.iter()
+        .find(|(_, job)| job.state == JobState::Running && &job.request == request)
+        .map(|(id, _)| *id)
+}
+
+/// Remove the oldest finished jobs beyond [`MAX_FINISHED_JOBS`].
+fn prune(jobs: &mut BTreeMap<u64, Job>) {
+    let finished: Vec<u64> = jobs
+        .iter()
+        .filter(|(_, job)| job.state != JobState::Running)
+        .map(|(id, _)| *id)
+        .collect();
+    for id in finished
+        .iter()
+        .take(finished.len().saturating_sub(MAX_FINISHED_JOBS))
+    {
+        jobs.remove(id);
+    }
+}
+
+fn sse_event(event: &str, data: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    Bytes::from(format!("event: {event}\ndata: {data}\n\n"))
+}
+
+#[post("/jobs")]
+async fn start_job(
+    _: Admin,
+    jobs: Data<JobManager>,
+    kit: Data<PixivKit>,
+    request: Json<JobRequest>,
+) -> Result<HttpResponse> {
+    let id = JobManager::start(jobs.clone(), kit.clone(), request.into_inner())?;
+    let info = jobs.info(id, &kit).ok_or_else(Error::not_found)?;
+    Ok(HttpResponse::Accepted().json(info))
+}
+
+#[get("/jobs")]
+async fn list_jobs(jobs: Data<JobManager>, kit: Data<PixivKit>) -> Json<Vec<JobInfo>> {
+    Json(jobs.list(&kit))
+}
+
+#[get("/jobs/{id}")]
+async fn get_job(
+    jobs: Data<JobManager>,
+    kit: Data<PixivKit>,
+    path: web::Path<(u64,)>,
+) -> Result<Json<JobInfo>> {
+    jobs.info(path.0, &kit)
+        .map(Json)
+        .ok_or_else(Error::not_found)
+}
+
+#[delete("/jobs/{id}")]
+async fn cancel_job(
+    _: Admin,
+    jobs: Data<JobManager>,
+    kit: Data<PixivKit>,
+    path: web::Path<(u64,)>,
+) -> Result<Json<JobInfo>> {
+    jobs.cancel(path.0)?;
+    jobs.info(path.0, &kit)
+        .map(Json)
+        .ok_or_else(Error::not_found)
+}
+
+/// Stream the job as a `progress` event every second while it is running,
+/// and an `end` event when it is finished, failed or cancelled.
+#[get("/jobs/{id}/events")]
+async fn job_events(
+    jobs: Data<JobManager>,
+    kit: Data<PixivKit>,
+    path: web::Path<(u64,)>,
+) -> Result<HttpResponse> {
+    let id = path.0;
+    jobs.info(id, &kit).ok_or_else(Error::not_found)?;
+
+    let events = stream::unfold(Some(true), move |next| {
+        let jobs = jobs.clone();
+        let kit = kit.clone();
+        async move {
+            let first = next?;
+            if !first {
+                tokio::time::sleep(EVENT_INTERVAL).await;
+            }
+            let info = jobs.info(id, &kit)?;
+            let running = info.state == JobState::Running;
+            let event = sse_event(if running { "progress" } else { "end" }, &info);
+            Some((Ok::<_, actix_web::Error>(event), running.then_some(false)))
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(events))
+}
+
+pub(crate) fn openapi(api: &mut Api) {
+    const TAG: &str = "jobs";
+    api.route("post", "/pixiv/jobs", TAG, "Start a sync job")
+        .body::<JobRequest>()
+        .json::<JobInfo>(202, "The job is started")
+        .admin();
+    api.route(
+        "get",
+        "/pixiv/jobs",
+        TAG,
+        "List the running and the latest finished jobs",
+    )
+    .json::<Vec<JobInfo>>(200, "The jobs, the latest first");
+    api.route("get", "/pixiv/jobs/{id}", TAG, "Get a job")
+        .path_param::<u64>("id", "id of the job")
+        .json::<JobInfo>(200, "The job");
+    api.route("delete", "/pixiv/jobs/{id}", TAG, "Cancel a running job")
+        .path_param::<u64>("id", "id of the job")
+        .json::<JobInfo>(200, "The cancelled job")
+        .admin();
+    api.route(
+        "get",
+        "/pixiv/jobs/{id}/events",
+        TAG,
+        "Stream the progress of a job",
+    )
+    .path_param::<u64>("id", "id of the job")
+    .content(
+        200,
+        "`progress` events of the job every second, and an `end` event",
+        &["text/event-stream"],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_request() {
+        let request: JobRequest =
+            serde_json::from_str(r#"{"kind": "illust_bookmarks", "private": true}"#).unwrap();
+        assert_eq!(
+            request,
+            JobRequest::IllustBookmarks {
+                user_id: None,
+                limit: None,
+                private: true,
+                reconcile: false,
+                tag: None
+            }
+        );
+        assert!(serde_json::from_str::<JobRequest>(r#"{"kind": "users"}"#).is_err());
+        assert!(serde_json::from_str::<JobRequest>(r#"{"kind": "unknown"}"#).is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        let job = |state| Job {
+            request: JobRequest::Users { user_ids: vec![] },
+            started_at: Utc::now(),
+            finished_at: None,
+            state,
+            progress: Default::default(),
+            handle: None,
+        };
+        let mut jobs = BTreeMap::new();
+        jobs.insert(0, job(JobState::Running));
+        for id in 1..=MAX_FINISHED_JOBS as u64 + 2 {
+            jobs.insert(id, job(JobState::Finished));
+        }
+        prune(&mut jobs);
+        assert_eq!(jobs.len(), MAX_FINISHED_JOBS + 1);
+        assert!(jobs.contains_key(&0));
+        assert!(!jobs.contains_key(&1) && !jobs.contains_key(&2));
+
+        let request = JobRequest::Users { user_ids: vec![] };
+        assert_eq!(running_job(&jobs, &request), Some(0));
+        jobs.get_mut(&0).unwrap().state = JobState::Cancelled;
+        assert_eq!(running_job(&jobs, &request), None);
+    }
+
+    #[test]
+    fn test_sse_event() {
+        assert_eq!(
+            sse_event("end", &JobState::Failed("a\nb".to_string())),
+            "event: end\ndata: {\"state\":\"failed\",\"error\":\"a\\nb\"}\n\n"
+        );
+    }
+}
@@ -1,17 +1,35 @@
-use actix_files::Files;
 use actix_web::{
+    dev::Service,
+    middleware::Condition,
     web::{self, Data},
     App, HttpServer,
 };
+use bowerbird_core::config::Config;
 use bowerbird_pixiv::PixivKit;
-use log::info;
-use std::{path::PathBuf, sync::Mutex};
+use log::{info, warn};
+use sqlx::PgPool;
+use std::{path::PathBuf, sync::Mutex, time::Instant};
 use tokio::sync::Semaphore;
 
-use utils::ThumbnailCache;
+use auth::RequireAuth;
+use error::extractor_error;
+use jobs::JobManager;
+use request_id::RequestId;
+use thumbnail::ThumbnailCache;
 
+pub use auth::{create_token, list_tokens, revoke_token, ApiToken, Scope};
+pub use thumbnail::ThumbnailFormat;
+
+mod auth;
 mod error;
+mod health;
+mod jobs;
+mod metrics;
+mod openapi;
 mod pixiv;
+mod request_id;
+mod thumbnail;
+mod ugoira;
 mod utils;
 
 type Result<T> = std::result::Result<T, error::Error>;
@@ -21,28 +39,86 @@ struct PixivConfig {
     storage_dir: PathBuf,
 }
 
+fn open_thumbnail_cache(config: &Config) -> std::io::Result<ThumbnailCache> {
+    ThumbnailCache::open(
+        config.sub_dir(&config.server.thumbnail_cache_dir),
+        config.server.thumbnail_cache_size,
+    )
+}
+
+/// Make the thumbnails of the downloaded images in the sizes and formats into the cache.
+pub async fn pregenerate_thumbnails(
+    config: &Config,
+    db: &PgPool,
+    sizes: &[u32],
+    formats: &[ThumbnailFormat],
+    crop_to_center: bool,
+) -> anyhow::Result<()> {
+    let cache = Mutex::new(open_thumbnail_cache(config)?);
+    thumbnail::pregenerate(
+        db,
+        &config.sub_dir(&config.pixiv.storage_dir),
+        &cache,
+        &config.server,
+        sizes,
+        formats,
+        crop_to_center,
+    )
+    .await
+}
+
 pub async fn run(kit: PixivKit) -> std::io::Result<()> {
-    let thumbnail_cache = Data::new(Mutex::new(ThumbnailCache::new()));
+    let thumbnail_cache = Data::new(Mutex::new(open_thumbnail_cache(&kit.config)?));
     let pixiv_config = Data::new(PixivConfig {
         storage_dir: kit.config.sub_dir(&kit.config.pixiv.storage_dir),
     });
     let listen_addr = kit.config.server.listen_addr;
+    let require_auth = kit.config.server.require_auth;
+    if !require_auth && !listen_addr.ip().is_loopback() {
+        warn!("authentication is disabled, the archive is open to everyone who can reach it");
+    }
     let kit = Data::new(kit);
     let db = Data::new(kit.db.clone());
     let config = Data::new(kit.config.clone());
 
     let cpu_workers_sem = Data::new(Semaphore::new(num_cpus::get()));
+    let jobs = Data::new(JobManager::new(tokio::runtime::Handle::current()));
 
     info!("server listening on http://{}", listen_addr);
     HttpServer::new({
         move || {
             let scope_pixiv = web::scope("/pixiv")
-                .service(Files::new("/storage", pixiv_config.storage_dir.clone()))
+                .wrap(Condition::new(
+                    require_auth,
+                    RequireAuth::new(db.as_ref().clone()),
+                ))
+                .service(pixiv::storage)
                 .service(pixiv::thumbnail)
                 .service(pixiv::find_illust)
                 .service(pixiv::find_tag)
-                .service(pixiv::find_user);
-            let scope_v2 = web::scope("/api/v2").service(scope_pixiv);
+                .service(pixiv::find_user)
+                .service(pixiv::find_lost_work)
+                .service(pixiv::novel_text)
+                .service(pixiv::novel_epub)
+                .service(pixiv::novel_series_epub)
+                .service(pixiv::illust_cbz)
+                .service(pixiv::illust_series_cbz)
+                .service(pixiv::download_illust)
+                .service(pixiv::illust_ugoira)
+                .service(pixiv::illust_ugoira_frame)
+                .service(jobs::start_job)
+                .service(jobs::list_jobs)
+                .service(jobs::get_job)
+                .service(jobs::cancel_job)
+                .service(jobs::job_events);
+            let scope_auth = web::scope("/auth")
+                .service(auth::login)
+                .service(auth::logout);
+            let scope_v2 = web::scope(openapi::BASE_PATH)
+                .service(openapi::openapi_json)
+                .service(openapi::docs)
+                .service(scope_auth)
+                .service(scope_pixiv);
 
             App::new()
                 .app_data(db.clone())
@@ -51,6 +127,30 @@ pub async fn run(kit: PixivKit) -> std::io::Result<()> {
                 .app_data(cpu_workers_sem.clone())
                 .app_data(config.clone())
                 .app_data(kit.clone())
+                .app_data(jobs.clone())
+                .app_data(web::JsonConfig::default().error_handler(|e, _| extractor_error(e)))
+                .app_data(web::QueryConfig::default().error_handler(|e, _| extractor_error(e)))
+                .app_data(web::PathConfig::default().error_handler(|e, _| extractor_error(e)))
+                .wrap(RequestId)
+                .wrap_fn(|req, srv| {
+                    let start = Instant::now();
+                    let res = srv.call(req);
+                    async move {
+                        let res = res.await?;
+                        metrics::observe_request(&res, start);
+                        Ok(res)
+                    }
+                })
+                .service(
+                    web::resource("/metrics")
+                        .wrap(Condition::new(
+                            require_auth,
+                            RequireAuth::new(db.as_ref().clone()),
+                        ))
+                        .route(web::get().to(metrics::metrics)),
+                )
+                .service(health::healthz)
+                .service(health::readyz)
                 .service(scope_v2)
         }
     })
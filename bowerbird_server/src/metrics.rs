@@ -0,0 +1,187 @@
+//! Prometheus metrics at `/metrics`.
+//!
+//! The metrics of the server are registered in the default registry,
+//! and the counters kept by the kit are read when scraped.
+
+use actix_web::{dev::ServiceResponse, web::Data, HttpResponse};
+use bowerbird_pixiv::PixivKit;
+use lazy_static::lazy_static;
+use prometheus::{
+    proto::MetricFamily, register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, Registry, TextEncoder,
+};
+use std::time::{Duration, Instant};
+
+use super::{error::*, thumbnail::ThumbnailFormat, Result};
+
+lazy_static! {
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "bowerbird_http_request_duration_seconds",
+        "Latency of the HTTP requests by the matched route.",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref THUMBNAIL_CACHE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "bowerbird_thumbnail_cache_requests_total",
+        "Thumbnails requested, by whether they are found in the cache.",
+        &["result"]
+    )
+    .unwrap();
+    static ref THUMBNAIL_GENERATION_DURATION: HistogramVec = register_histogram_vec!(
+        "bowerbird_thumbnail_generation_seconds",
+        "Time to make a thumbnail not in the cache.",
+        &["format"],
+        vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+    )
+    .unwrap();
+}
+
+/// Record the latency of the request started at `start`.
+pub fn observe_request<B>(res: &ServiceResponse<B>, start: Instant) {
+    let req = res.request();
+    // The unmatched paths are not labeled to keep the number of the series bounded.
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[req.method().as_str(), &route, res.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+pub fn observe_thumbnail_cache(hit: bool) {
+    THUMBNAIL_CACHE_REQUESTS
+        .with_label_values(&[if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+pub fn observe_thumbnail_generation(format: ThumbnailFormat, elapsed: Duration) {
+    THUMBNAIL_GENERATION_DURATION
+        .with_label_values(&[format.extension()])
+        .observe(elapsed.as_secs_f64());
+}
+
+fn register_counter(
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    value: u64,
+) -> prometheus::Result<()> {
+    let counter = IntCounter::new(name, help)?;
+    counter.inc_by(value);
+    registry.register(Box::new(counter))
+}
+
+fn register_gauge(
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    value: i64,
+) -> prometheus::Result<()> {
+    let gauge = IntGauge::new(name, help)?;
+    gauge.set(value);
+    registry.register(Box::new(gauge))
+}
+
+/// The pixiv api calls, the downloads and the database pool of the kit.
+fn kit_metrics(kit: &PixivKit) -> prometheus::Result<Vec<MetricFamily>> {
+    let registry = Registry::new();
+    let throttle = kit.throttle.stats();
+    register_counter(
+        &registry,
+        "bowerbird_pixiv_api_requests_total",
+        "Requests sent to the pixiv api, including the retries.",
+        throttle.requests,
+    )?;
+    register_counter(
+        &registry,
+        "bowerbird_pixiv_api_retries_total",
+        "Retries of the pixiv api requests on http errors.",
+        throttle.retries,
+    )?;
+    register_counter(
+        &registry,
+        "bowerbird_pixiv_api_rate_limited_total",
+        "Responses of rate limit from pixiv.",
+        throttle.rate_limited,
+    )?;
+    register_counter(
+        &registry,
+        "bowerbird_pixiv_api_throttled_total",
+        "Requests delayed by the rate limiter.",
+        throttle.throttled,
+    )?;
+
+    let downloads = kit.downloader.stats();
+    register_counter(
+        &registry,
+        "bowerbird_downloads_queued_total",
+        "Downloads added to aria2.",
+        downloads.queued,
+    )?;
+    register_counter(
+        &registry,
+        "bowerbird_downloads_completed_total",
+        "Downloads completed.",
+        downloads.completed,
+    )?;
+    register_counter(
+        &registry,
+        "bowerbird_downloads_failed_total",
+        "Downloads failed.",
+        downloads.failed,
+    )?;
+    register_gauge(
+        &registry,
+        "bowerbird_downloads_pending",
+        "Downloads not completed or failed yet.",
+        kit.downloader.pending_tasks() as i64,
+    )?;
+
+    register_gauge(
+        &registry,
+        "bowerbird_db_pool_connections",
+        "Connections open in the database pool.",
+        kit.db.size() as i64,
+    )?;
+    register_gauge(
+        &registry,
+        "bowerbird_db_pool_idle_connections",
+        "Idle connections in the database pool.",
+        kit.db.num_idle() as i64,
+    )?;
+    Ok(registry.gather())
+}
+
+pub async fn metrics(kit: Data<PixivKit>) -> Result<HttpResponse> {
+    let mut families = prometheus::gather();
+    families.extend(kit_metrics(&kit).with_interal()?);
+    let mut body = vec![];
+    TextEncoder::new()
+        .encode(&families, &mut body)
+        .with_interal()?;
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        observe_thumbnail_cache(true);
+        observe_thumbnail_generation(ThumbnailFormat::Webp, Duration::from_millis(20));
+        let registry = Registry::new();
+        register_counter(&registry, "test_total", "test", 3).unwrap();
+        let mut families = prometheus::gather();
+        families.extend(registry.gather());
+
+        let mut body = vec![];
+        TextEncoder::new().encode(&families, &mut body).unwrap();
+        let text = String::from_utf8(body).unwrap();
+        assert!(text.contains("bowerbird_thumbnail_cache_requests_total{result=\"hit\"}"));
+        assert!(text.contains("bowerbird_thumbnail_generation_seconds_count{format=\"webp\"} 1"));
+        assert!(text.contains("test_total 3"));
+    }
+}
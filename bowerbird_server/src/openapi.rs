@@ -0,0 +1,279 @@
+//! OpenAPI 3 document of the v2 API.
+//!
+//! The schemas are generated from the types of the handlers, and each handler module
+//! describes its routes next to them, see `pixiv::openapi` for example.
+
+use actix_web::{get, HttpResponse};
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::{Schema, SchemaObject},
+    JsonSchema,
+};
+use serde_json::{json, Map, Value};
+
+use super::{auth, error::ErrorBody, jobs, pixiv};
+
+pub const BASE_PATH: &str = "/api/v2";
+
+/// The paths and the schemas of the document being built.
+pub struct Api {
+    gen: SchemaGenerator,
+    paths: Map<String, Value>,
+}
+
+/// An operation added to [`Api`], to be described by the chained calls.
+pub struct Route<'a> {
+    gen: &'a mut SchemaGenerator,
+    op: &'a mut Map<String, Value>,
+}
+
+impl Api {
+    fn new() -> Self {
+        Self {
+            gen: SchemaSettings::openapi3().into_generator(),
+            paths: Map::new(),
+        }
+    }
+
+    /// Add the operation of the path relative to [`BASE_PATH`].
+    pub fn route(&mut self, method: &str, path: &str, tag: &str, summary: &str) -> Route<'_> {
+        let error = serde_json::to_value(self.gen.subschema_for::<ErrorBody>()).unwrap();
+        let item = self
+            .paths
+            .entry(path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap();
+        item.insert(
+            method.to_string(),
+            json!({
+                "tags": [tag],
+                "summary": summary,
+                "responses": {
+                    "default": {
+                        "description": "Error",
+                        "content": { "application/json": { "schema": error } }
+                    }
+                }
+            }),
+        );
+        Route {
+            gen: &mut self.gen,
+            op: item.get_mut(method).unwrap().as_object_mut().unwrap(),
+        }
+    }
+
+    fn into_document(mut self) -> Value {
+        let schemas: Map<String, Value> = self
+            .gen
+            .take_definitions()
+            .into_iter()
+            .map(|(name, schema)| (name, serde_json::to_value(schema).unwrap()))
+            .collect();
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "bowerbird",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "servers": [{ "url": BASE_PATH }],
+            "paths": self.paths,
+            "components": {
+                "schemas": schemas,
+                "securitySchemes": {
+                    "token": { "type": "http", "scheme": "bearer" },
+                    "session": { "type": "apiKey", "in": "cookie", "name": auth::SESSION_COOKIE },
+                }
+            },
+            "security": [{ "token": [] }, { "session": [] }],
+        })
+    }
+}
+
+impl Route<'_> {
+    fn schema<T: JsonSchema>(&mut self) -> Value {
+        serde_json::to_value(self.gen.subschema_for::<T>()).unwrap()
+    }
+
+    fn push_param(&mut self, param: Value) {
+        self.op
+            .entry("parameters")
+            .or_insert_with(|| json!([]))
+            .as_array_mut()
+            .unwrap()
+            .push(param);
+    }
+
+    pub fn path_param<T: JsonSchema>(mut self, name: &str, description: &str) -> Self {
+        let schema = self.schema::<T>();
+        self.push_param(json!({
+            "name": name,
+            "in": "path",
+            "required": true,
+            "description": description,
+            "schema": schema,
+        }));
+        self
+    }
+
+    /// Add the fields of the struct as the query parameters.
+    pub fn query<T: JsonSchema>(mut self) -> Self {
+        let schema = self.gen.root_schema_for::<T>().schema;
+        let object = schema.object.unwrap_or_default();
+        for (name, schema) in object.properties {
+            let required = object.required.contains(&name);
+            let schema = serde_json::to_value(schema).unwrap();
+            self.push_param(json!({
+                "name": name,
+                "in": "query",
+                "required": required,
+                "schema": schema,
+            }));
+        }
+        self
+    }
+
+    pub fn body<T: JsonSchema>(mut self) -> Self {
+        let schema = self.schema::<T>();
+        self.op.insert(
+            "requestBody".to_string(),
+            json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema } }
+            }),
+        );
+        self
+    }
+
+    pub fn json<T: JsonSchema>(mut self, status: u16, description: &str) -> Self {
+        let schema = self.schema::<T>();
+        self.response(
+            status,
+            description,
+            json!({ "application/json": { "schema": schema } }),
+        )
+    }
+
+    /// A response of binary or text content.
+    pub fn content(self, status: u16, description: &str, content_types: &[&str]) -> Self {
+        let schema = Schema::Object(SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            format: Some("binary".to_string()),
+            ..Default::default()
+        });
+        let content: Map<String, Value> = content_types
+            .iter()
+            .map(|t| (t.to_string(), json!({ "schema": schema })))
+            .collect();
+        self.response(status, description, Value::Object(content))
+    }
+
+    pub fn empty(self, status: u16, description: &str) -> Self {
+        self.response(status, description, Value::Null)
+    }
+
+    /// Add the response, with the content types merged if the status is already added.
+    fn response(self, status: u16, description: &str, content: Value) -> Self {
+        let response = &mut self.op["responses"][status.to_string()];
+        if response.is_null() {
+            *response = json!({ "description": description });
+        }
+        if let Value::Object(content) = content {
+            let merged = &mut response["content"];
+            if merged.is_null() {
+                *merged = json!({});
+            }
+            merged.as_object_mut().unwrap().extend(content);
+        }
+        self
+    }
+
+    /// Require the `admin` scope of the token, see [`auth::Admin`].
+    pub fn admin(self) -> Self {
+        self.op.insert(
+            "description".to_string(),
+            json!("Requires a token of the `admin` scope."),
+        );
+        self
+    }
+
+    /// Allow the operation without credentials.
+    pub fn public(self) -> Self {
+        self.op.insert("security".to_string(), json!([]));
+        self
+    }
+}
+
+/// Generate the document.
+pub fn document() -> Value {
+    let mut api = Api::new();
+    auth::openapi(&mut api);
+    pixiv::openapi(&mut api);
+    jobs::openapi(&mut api);
+    api.into_document()
+}
+
+#[get("/openapi.json")]
+async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(document())
+}
+
+/// A page listing the operations and the schemas in the document, with no external assets.
+#[get("/docs")]
+async fn docs() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("docs.html"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// Sort the keys of the objects, as the order depends on the features of `serde_json`.
+    fn sort_keys(value: Value) -> Value {
+        match value {
+            Value::Object(map) => {
+                let sorted: std::collections::BTreeMap<_, _> =
+                    map.into_iter().map(|(k, v)| (k, sort_keys(v))).collect();
+                Value::Object(sorted.into_iter().collect())
+            }
+            Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+            v => v,
+        }
+    }
+
+    /// Fail if the document differs from `openapi.json` of the crate,
+    /// which is updated instead with `UPDATE_OPENAPI=1`.
+    #[test]
+    fn test_openapi_drift() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("openapi.json");
+        let generated = sort_keys(document());
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            let text = serde_json::to_string_pretty(&generated).unwrap() + "\n";
+            std::fs::write(&path, text).unwrap();
+            return;
+        }
+        let saved: Value = std::fs::read(&path)
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default();
+        assert!(
+            saved == generated,
+            "the API differs from {path:?}, review and update it with UPDATE_OPENAPI=1"
+        );
+    }
+
+    #[test]
+    fn test_document() {
+        let doc = document();
+        let find_illust = &doc["paths"]["/pixiv/illust/find"]["post"];
+        let body_ref = find_illust["requestBody"]["content"]["application/json"]["schema"]["$ref"]
+            .as_str()
+            .unwrap();
+        let name = body_ref.strip_prefix("#/components/schemas/").unwrap();
+        assert!(doc["components"]["schemas"][name]["properties"]["tag_ids"].is_object());
+        assert_eq!(doc["paths"]["/auth/login"]["post"]["security"], json!([]));
+    }
+}
@@ -1,6 +1,13 @@
+use actix_files::{file_extension_to_mime, NamedFile};
 use actix_web::{
     get,
-    http::header::{self, CacheDirective, ContentType},
+    http::{
+        header::{
+            self, CacheDirective, ContentDisposition, ContentType, DispositionParam,
+            DispositionType, EntityTag,
+        },
+        StatusCode,
+    },
     post,
     web::{self, Data, Json},
     HttpRequest, HttpResponse,
@@ -8,30 +15,59 @@ use actix_web::{
 use bowerbird_core::{
     config::Config,
     model::{
-        pixiv::{PixivIllust, PixivUser},
+        pixiv::{LostWork, PixivIllust, PixivUser},
         Item, Tag,
     },
 };
 
+use bowerbird_pixiv::{
+    download, export,
+    novel_markup::{self, ImageRef},
+    PixivKit,
+};
 use chrono::{DateTime, Utc};
-use log::debug;
+use log::{debug, error};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::{query_as, PgPool};
-use std::sync::Mutex;
-use tokio::sync::Semaphore;
+use std::{path::Path, sync::Mutex};
+use tokio::{sync::Semaphore, task::spawn_blocking};
 
 use super::{
+    auth::Admin,
     error::*,
-    utils::{cached_image_thumbnail, ThumbnailCache},
+    openapi::Api,
+    thumbnail::{
+        cached_image_thumbnail, thumbnail_key, ThumbnailCache, ThumbnailFormat, ThumbnailParams,
+        CROP_RATIO,
+    },
+    ugoira,
+    utils::{ranged_response, resolve_storage_path, spawn_semaphore, storage_url, Validators},
     PixivConfig, Result,
 };
 
 type OptionUtc = Option<DateTime<Utc>>;
 
-#[derive(Debug, Clone, Deserialize)]
+/// The downloaded files, with the conditional and range requests handled.
+#[get("/storage/{path:.*}")]
+async fn storage(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    pixiv_config: Data<PixivConfig>,
+) -> Result<HttpResponse> {
+    let path = resolve_storage_path(&pixiv_config.storage_dir, &path.0).await?;
+    let file = NamedFile::open_async(path)
+        .await
+        .with_status(StatusCode::NOT_FOUND)?;
+    Ok(file.into_response(&req))
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 struct ThumbnailQuery {
     size: u32,
     crop_to_center: bool,
+    /// Negotiated by the `Accept` header if not set.
+    format: Option<ThumbnailFormat>,
 }
 #[get("/thumbnail/{path:.*}")]
 async fn thumbnail(
@@ -43,31 +79,52 @@ async fn thumbnail(
     cache: Data<Mutex<ThumbnailCache>>,
     semaphore: Data<Semaphore>,
 ) -> Result<HttpResponse> {
-    if req.headers().get(header::RANGE).is_some() {
-        return Ok(HttpResponse::NotImplemented().finish());
+    let relative = &path.0;
+    let path = resolve_storage_path(&pixiv_config.storage_dir, relative).await?;
+
+    let format = match query.format {
+        Some(f) if !f.is_supported() => {
+            return Err(Error::with_msg(
+                StatusCode::BAD_REQUEST,
+                "unsupported thumbnail format",
+            ))
+        }
+        Some(f) => f,
+        None => ThumbnailFormat::negotiate(
+            req.headers()
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default(),
+        ),
+    };
+
+    let params = ThumbnailParams {
+        size: query.size,
+        format,
+        quality: format.quality(&config.server),
+        target_ratio: query.crop_to_center.then_some(CROP_RATIO),
+    };
+    let (key, modified) = thumbnail_key(relative, &path, params).await?;
+    let validators = Validators::new(EntityTag::new_strong(key.clone()), modified);
+
+    let not_modified = validators.is_not_modified(&req);
+    let mut builder = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    builder
+        .append_header((header::VARY, "Accept"))
+        .append_header(header::CacheControl(vec![CacheDirective::MaxAge(604800)]));
+    if not_modified {
+        validators.insert_headers(&mut builder);
+        return Ok(builder.finish());
     }
-    let path = pixiv_config
-        .storage_dir
-        .join(path.0.replace("../", "").replace("..\\", ""));
-
-    let img = cached_image_thumbnail(
-        path,
-        query.size,
-        cache.as_ref(),
-        semaphore.as_ref(),
-        config.server.thumbnail_jpeg_quality,
-        if query.crop_to_center {
-            Some(0.75)
-        } else {
-            None
-        },
-    )
-    .await?;
 
-    Ok(HttpResponse::Ok()
-        .content_type(ContentType::jpeg())
-        .append_header(header::CacheControl(vec![CacheDirective::MaxAge(604800)]))
-        .body(img))
+    let img =
+        cached_image_thumbnail(&path, key, params, cache.as_ref(), semaphore.as_ref()).await?;
+    builder.content_type(format.mime());
+    Ok(ranged_response(&req, &validators, &mut builder, img))
 }
 
 fn count_items<E, H>(r: &[Item<E, H>]) -> i64 {
@@ -75,12 +132,27 @@ fn count_items<E, H>(r: &[Item<E, H>]) -> i64 {
 }
 
 // TODO: Optimize offset: https://stackoverflow.com/questions/34110504/optimize-query-with-offset-on-large-table/34291099#34291099
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 struct Cursor {
+    #[schemars(range(min = 1, max = 500))]
     limit: u16,
     offset: u16,
 }
 
+impl Cursor {
+    const MAX_LIMIT: u16 = 500;
+
+    fn validate(&self) -> Result<()> {
+        if !(1..=Self::MAX_LIMIT).contains(&self.limit) {
+            return Err(Error::validation(
+                "limit",
+                &format!("must be between 1 and {}", Self::MAX_LIMIT),
+            ));
+        }
+        Ok(())
+    }
+}
+
 // #[derive(Debug, Clone, Deserialize)]
 // struct FindImageMediaForm {
 //     h_range: Option<(f32, f32)>,
@@ -116,7 +188,7 @@ struct Cursor {
 //     Ok(Json(r))
 // }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 struct TagFindForm {
     ids: Option<Vec<i64>>,
     search: Option<String>,
@@ -127,6 +199,7 @@ struct TagFindForm {
 async fn find_tag(db: Data<PgPool>, form: Json<TagFindForm>) -> Result<Json<Vec<Tag>>> {
     // TODO: change return type
     let form = form.into_inner();
+    form.cursor.validate()?;
 
     let r = query_as(
         "
@@ -154,21 +227,30 @@ async fn find_tag(db: Data<PgPool>, form: Json<TagFindForm>) -> Result<Json<Vec<
     Ok(Json(r))
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, JsonSchema)]
 struct ItemsResponse<T> {
     pub total: i64,
     pub items: Vec<T>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 struct IllustFindForm {
     tag_ids: Option<Vec<i64>>,
     tag_ids_exclude: Option<Vec<i64>>,
     ids: Option<Vec<i64>>,
     search: Option<String>, // Search in title and caption
+    // Tuples are not supported by OpenAPI 3.0.
+    #[schemars(with = "Option<[OptionUtc; 2]>")]
     date_range: Option<(OptionUtc, OptionUtc)>,
+    #[schemars(with = "Option<[Option<u16>; 2]>")]
     bookmark_range: Option<(Option<u16>, Option<u16>)>, // (min, max)
     parent_ids: Option<Vec<i64>>,
+    bookmark_tag_ids: Option<Vec<i64>>,
+    bookmark_tag_ids_exclude: Option<Vec<i64>>,
+    x_restrict: Option<Vec<i16>>, // 0 for all ages, 1 for R-18, and 2 for R-18G
+    max_sanity_level: Option<i16>,
+    ai_types: Option<Vec<i16>>, // 0 for unknown, 1 for not AI-generated, and 2 for AI-generated
+    hide_muted: Option<bool>,
     #[serde(flatten)]
     cursor: Cursor,
 }
@@ -178,6 +260,7 @@ async fn find_illust(
     form: Json<IllustFindForm>,
 ) -> Result<Json<ItemsResponse<PixivIllust>>> {
     let form = form.into_inner();
+    form.cursor.validate()?;
     debug!("find illust: {:?}", form);
 
     let r: Vec<PixivIllust> = query_as(
@@ -197,6 +280,12 @@ async fn find_illust(
             and ($5 is null or total_bookmarks >= $5)
             and ($6 is null or total_bookmarks <= $6)
             and ($2::text is null or title ilike $2 or caption_html ilike $2)
+            and ($12::bigint[] is null or bookmark_tag_ids @> $12)
+            and ($13::bigint[] is null or bookmark_tag_ids is null or not bookmark_tag_ids && $13)
+            and ($14::smallint[] is null or x_restrict = any($14))
+            and ($15::smallint is null or sanity_level <= $15)
+            and ($16::smallint[] is null or coalesce(ai_type, 0) = any($16))
+            and ($17::boolean is not true or is_muted is not true)
         order by id desc
         limit $10 offset $11
         ",
@@ -212,6 +301,12 @@ async fn find_illust(
     .bind(form.tag_ids_exclude)
     .bind(form.cursor.limit as i64)
     .bind(form.cursor.offset as i64)
+    .bind(form.bookmark_tag_ids)
+    .bind(form.bookmark_tag_ids_exclude)
+    .bind(form.x_restrict)
+    .bind(form.max_sanity_level)
+    .bind(form.ai_types)
+    .bind(form.hide_muted)
     .fetch_all(db.as_ref())
     .await
     .with_interal()?;
@@ -222,7 +317,7 @@ async fn find_illust(
     }))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 struct UserFindForm {
     ids: Option<Vec<i64>>,
     search: Option<String>, // Search in name
@@ -235,6 +330,7 @@ async fn find_user(
     form: Json<UserFindForm>,
 ) -> Result<Json<ItemsResponse<PixivUser>>> {
     let form = form.into_inner();
+    form.cursor.validate()?;
     debug!("find user: {:?}", form);
 
     let r = query_as(
@@ -262,6 +358,230 @@ async fn find_user(
     }))
 }
 
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+struct LostWorkFindForm {
+    kinds: Option<Vec<String>>,   // illust or novel
+    reasons: Option<Vec<String>>, // deleted, private, restricted or invisible
+    parent_ids: Option<Vec<i64>>,
+    preserved: Option<bool>,
+    #[serde(flatten)]
+    cursor: Cursor,
+}
+/// Find the works not accessible on pixiv anymore, the latest lost first.
+#[post("/lost/find")]
+async fn find_lost_work(
+    db: Data<PgPool>,
+    form: Json<LostWorkFindForm>,
+) -> Result<Json<ItemsResponse<LostWork>>> {
+    let form = form.into_inner();
+    form.cursor.validate()?;
+    debug!("find lost work: {:?}", form);
+
+    let r: Vec<LostWork> = query_as(
+        "
+        select count(*) over () _count, *
+        from pixiv_lost_work_view
+        where
+            ($1::text[] is null or kind = any($1))
+            and ($2::text[] is null or lost_reason = any($2))
+            and ($3::bigint[] is null or parent_id = any($3))
+            and ($4::boolean is null or preserved = $4)
+        order by lost_at desc, id desc
+        limit $5 offset $6
+        ",
+    )
+    .bind(form.kinds)
+    .bind(form.reasons)
+    .bind(form.parent_ids)
+    .bind(form.preserved)
+    .bind(form.cursor.limit as i64)
+    .bind(form.cursor.offset as i64)
+    .fetch_all(db.as_ref())
+    .await
+    .with_interal()?;
+
+    Ok(Json(ItemsResponse {
+        total: r.first().and_then(|x| x._count).unwrap_or(0),
+        items: r,
+    }))
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum NovelTextFormat {
+    #[default]
+    Html,
+    Text,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+struct NovelTextQuery {
+    #[serde(default)]
+    format: NovelTextFormat,
+}
+
+#[get("/novel/{id}/text")]
+async fn novel_text(
+    db: Data<PgPool>,
+    path: web::Path<(i64,)>,
+    query: web::Query<NovelTextQuery>,
+) -> Result<HttpResponse> {
+    let (title, text): (Option<String>, Option<String>) = query_as(
+        "
+        select title, text
+        from pixiv_novel_detail_latest_view
+        where id = $1
+        ",
+    )
+    .bind(path.0)
+    .fetch_optional(db.as_ref())
+    .await
+    .with_interal()?
+    .ok_or_else(Error::not_found)?;
+
+    let doc = novel_markup::parse(&text.unwrap_or_default());
+
+    Ok(match query.format {
+        NovelTextFormat::Text => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(novel_markup::render_text(&doc)),
+        NovelTextFormat::Html => {
+            let illust_ids: Vec<String> = doc
+                .images()
+                .filter_map(|i| match i {
+                    ImageRef::Pixiv { illust_id, .. } => Some(illust_id.clone()),
+                    _ => None,
+                })
+                .collect();
+            let paths = export::illust_paths_by_source_ids(db.as_ref(), &illust_ids)
+                .await
+                .map_err(export_error)?;
+
+            let body = novel_markup::render_html(&doc, |i: &ImageRef| match i {
+                ImageRef::Pixiv { illust_id, page } => paths
+                    .get(illust_id)?
+                    .get((*page as usize).checked_sub(1)?)?
+                    .as_deref()
+                    .map(storage_url),
+                // Uploaded images are not saved.
+                ImageRef::Uploaded { .. } => None,
+            });
+            let title = novel_markup::escape_html(&title.unwrap_or_default());
+            HttpResponse::Ok().content_type(ContentType::html()).body(format!(
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\" />\n<title>{title}</title>\n</head>\n<body>\n<h1>{title}</h1>\n{body}</body>\n</html>\n"
+            ))
+        }
+    })
+}
+
+fn export_error(err: bowerbird_pixiv::Error) -> Error {
+    if let bowerbird_pixiv::Error::NotFound { .. } = err {
+        return Error::not_found();
+    }
+    error!("Internal Server Error: {}", err);
+    Error::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal server error",
+        err,
+        false,
+    )
+}
+
+fn attachment(data: Vec<u8>, content_type: &str, filename: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .append_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        })
+        .body(data)
+}
+
+#[get("/novel/{id}/epub")]
+async fn novel_epub(
+    db: Data<PgPool>,
+    pixiv_config: Data<PixivConfig>,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse> {
+    let id = path.0;
+    let data = export::novel_epub(db.as_ref(), &pixiv_config.storage_dir, id)
+        .await
+        .map_err(export_error)?;
+    Ok(attachment(
+        data,
+        "application/epub+zip",
+        format!("novel_{id}.epub"),
+    ))
+}
+
+#[get("/novel/series/{id}/epub")]
+async fn novel_series_epub(
+    db: Data<PgPool>,
+    pixiv_config: Data<PixivConfig>,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse> {
+    let id = path.0;
+    let data = export::novel_series_epub(db.as_ref(), &pixiv_config.storage_dir, id)
+        .await
+        .map_err(export_error)?;
+    Ok(attachment(
+        data,
+        "application/epub+zip",
+        format!("novel_series_{id}.epub"),
+    ))
+}
+
+#[get("/illust/{id}/cbz")]
+async fn illust_cbz(
+    db: Data<PgPool>,
+    pixiv_config: Data<PixivConfig>,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse> {
+    let id = path.0;
+    let data = export::illust_cbz(db.as_ref(), &pixiv_config.storage_dir, id)
+        .await
+        .map_err(export_error)?;
+    Ok(attachment(
+        data,
+        "application/vnd.comicbook+zip",
+        format!("illust_{id}.cbz"),
+    ))
+}
+
+#[get("/illust/series/{id}/cbz")]
+async fn illust_series_cbz(
+    db: Data<PgPool>,
+    pixiv_config: Data<PixivConfig>,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse> {
+    let id = path.0;
+    let data = export::illust_series_cbz(db.as_ref(), &pixiv_config.storage_dir, id)
+        .await
+        .map_err(export_error)?;
+    Ok(attachment(
+        data,
+        "application/vnd.comicbook+zip",
+        format!("illust_series_{id}.cbz"),
+    ))
+}
+
+/// Download the files of a saved illust, such as one skipped by the download filters.
+#[post("/illust/{id}/download")]
+async fn download_illust(
+    _: Admin,
+    kit: Data<PixivKit>,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse> {
+    let ids = [path.0];
+    let count = download::download_saved_illusts(Some(&ids), None, kit.as_ref())
+        .await
+        .map_err(export_error)?;
+    if count == 0 {
+        return Err(Error::not_found());
+    }
+    Ok(HttpResponse::Accepted().finish())
+}
+
 // #[derive(Debug, Clone, Deserialize)]
 // struct UserPreviewForm {
 //     id: i32,
@@ -286,3 +606,239 @@ async fn find_user(
 
 //     Ok(Json(r))
 // }
+
+/// Get the frame delays and the path relative to the storage dir of the zip of the ugoira.
+async fn ugoira_by_id(db: &PgPool, id: i64) -> Result<(Vec<i32>, String)> {
+    let (delay, zip_path): (Option<Vec<i32>>, Option<String>) = query_as(
+        "
+        select ugoira_frame_duration, ugoira_zip_path
+        from pixiv_illust_detail_latest_view
+        where id = $1
+          and illust_type = 'ugoira'
+        ",
+    )
+    .bind(id)
+    .fetch_optional(db)
+    .await
+    .with_interal()?
+    .ok_or_else(Error::not_found)?;
+
+    match (delay, zip_path) {
+        (Some(delay), Some(zip_path)) => Ok((delay, zip_path)),
+        _ => Err(Error::not_found()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum UgoiraFormat {
+    #[default]
+    Json,
+    Gif,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+struct UgoiraQuery {
+    #[serde(default)]
+    format: UgoiraFormat,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+struct UgoiraFrame {
+    url: String,
+    delay: i32,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+struct UgoiraResponse {
+    zip_url: String,
+    frames: Vec<UgoiraFrame>,
+}
+
+/// Play the ugoira without ffmpeg.
+///
+/// Returns the frame URLs with their delays for a client side player,
+/// or a GIF encoded from the frames if `format=gif`.
+#[get("/illust/{id}/ugoira")]
+async fn illust_ugoira(
+    req: HttpRequest,
+    db: Data<PgPool>,
+    pixiv_config: Data<PixivConfig>,
+    semaphore: Data<Semaphore>,
+    path: web::Path<(i64,)>,
+    query: web::Query<UgoiraQuery>,
+) -> Result<HttpResponse> {
+    let id = path.0;
+    let (delay, zip_path) = ugoira_by_id(db.as_ref(), id).await?;
+
+    Ok(match query.format {
+        UgoiraFormat::Json => HttpResponse::Ok().json(UgoiraResponse {
+            zip_url: storage_url(&zip_path),
+            frames: delay
+                .into_iter()
+                .enumerate()
+                .map(|(i, delay)| UgoiraFrame {
+                    url: format!("/api/v2/pixiv/illust/{id}/ugoira/frame/{i}"),
+                    delay,
+                })
+                .collect(),
+        }),
+        UgoiraFormat::Gif => {
+            let zip_path = resolve_storage_path(&pixiv_config.storage_dir, &zip_path).await?;
+            let validators = ugoira::gif_validators(&zip_path, &delay).await?;
+
+            let not_modified = validators.is_not_modified(&req);
+            let mut builder = if not_modified {
+                HttpResponse::NotModified()
+            } else {
+                HttpResponse::Ok()
+            };
+            builder.append_header(header::CacheControl(vec![CacheDirective::MaxAge(604800)]));
+            validators.insert_headers(&mut builder);
+            if not_modified {
+                return Ok(builder.finish());
+            }
+
+            let gif = spawn_semaphore(semaphore.as_ref(), move || {
+                ugoira::encode_gif(zip_path, &delay, 10)
+            })
+            .await?;
+            builder.content_type("image/gif").body(gif)
+        }
+    })
+}
+
+#[get("/illust/{id}/ugoira/frame/{index}")]
+async fn illust_ugoira_frame(
+    db: Data<PgPool>,
+    pixiv_config: Data<PixivConfig>,
+    path: web::Path<(i64, usize)>,
+) -> Result<HttpResponse> {
+    let (id, index) = path.into_inner();
+    let (_, zip_path) = ugoira_by_id(db.as_ref(), id).await?;
+    let zip_path = resolve_storage_path(&pixiv_config.storage_dir, &zip_path).await?;
+
+    let (name, data) = spawn_blocking(move || ugoira::read_frame(zip_path, index))
+        .await
+        .with_interal()??;
+    let ext = Path::new(&name)
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    Ok(HttpResponse::Ok()
+        .content_type(file_extension_to_mime(&ext))
+        .append_header(header::CacheControl(vec![CacheDirective::MaxAge(604800)]))
+        .body(data))
+}
+
+pub(crate) fn openapi(api: &mut Api) {
+    const TAG: &str = "pixiv";
+    const IMAGES: &[&str] = &["image/*"];
+    api.route("get", "/pixiv/storage/{path}", TAG, "Get a downloaded file")
+        .path_param::<String>("path", "path relative to the storage dir")
+        .content(200, "The file", &["application/octet-stream"])
+        .content(
+            206,
+            "The requested range of the file",
+            &["application/octet-stream"],
+        )
+        .empty(304, "Not modified");
+    api.route(
+        "get",
+        "/pixiv/thumbnail/{path}",
+        TAG,
+        "Get the thumbnail of a downloaded image",
+    )
+    .path_param::<String>("path", "path relative to the storage dir")
+    .query::<ThumbnailQuery>()
+    .content(200, "The thumbnail", IMAGES)
+    .content(206, "The requested range of the thumbnail", IMAGES)
+    .empty(304, "Not modified");
+    api.route("post", "/pixiv/illust/find", TAG, "Find illusts")
+        .body::<IllustFindForm>()
+        .json::<ItemsResponse<PixivIllust>>(200, "The illusts and the total count");
+    api.route("post", "/pixiv/tag/find", TAG, "Find tags")
+        .body::<TagFindForm>()
+        .json::<Vec<Tag>>(200, "The tags");
+    api.route("post", "/pixiv/user/find", TAG, "Find users")
+        .body::<UserFindForm>()
+        .json::<ItemsResponse<PixivUser>>(200, "The users and the total count");
+    api.route(
+        "post",
+        "/pixiv/lost/find",
+        TAG,
+        "Find the works lost on pixiv",
+    )
+    .body::<LostWorkFindForm>()
+    .json::<ItemsResponse<LostWork>>(200, "The lost works and the total count");
+    api.route(
+        "get",
+        "/pixiv/novel/{id}/text",
+        TAG,
+        "Get the text of a novel",
+    )
+    .path_param::<i64>("id", "database id of the novel")
+    .query::<NovelTextQuery>()
+    .content(200, "The rendered text", &["text/html", "text/plain"]);
+    api.route(
+        "get",
+        "/pixiv/novel/{id}/epub",
+        TAG,
+        "Export a novel as EPUB",
+    )
+    .path_param::<i64>("id", "database id of the novel")
+    .content(200, "The EPUB", &["application/epub+zip"]);
+    api.route(
+        "get",
+        "/pixiv/novel/series/{id}/epub",
+        TAG,
+        "Export a novel series as EPUB",
+    )
+    .path_param::<i64>("id", "database id of the novel series")
+    .content(200, "The EPUB", &["application/epub+zip"]);
+    api.route(
+        "get",
+        "/pixiv/illust/{id}/cbz",
+        TAG,
+        "Export an illust as CBZ",
+    )
+    .path_param::<i64>("id", "database id of the illust")
+    .content(200, "The CBZ", &["application/vnd.comicbook+zip"]);
+    api.route(
+        "get",
+        "/pixiv/illust/series/{id}/cbz",
+        TAG,
+        "Export an illust series as CBZ",
+    )
+    .path_param::<i64>("id", "database id of the illust series")
+    .content(200, "The CBZ", &["application/vnd.comicbook+zip"]);
+    api.route(
+        "post",
+        "/pixiv/illust/{id}/download",
+        TAG,
+        "Download the files of a saved illust",
+    )
+    .path_param::<i64>("id", "database id of the illust")
+    .empty(202, "The download is started")
+    .admin();
+    api.route("get", "/pixiv/illust/{id}/ugoira", TAG, "Play an ugoira")
+        .path_param::<i64>("id", "database id of the illust")
+        .query::<UgoiraQuery>()
+        .json::<UgoiraResponse>(200, "The frames, or the GIF with `format=gif`")
+        .content(
+            200,
+            "The frames, or the GIF with `format=gif`",
+            &["image/gif"],
+        )
+        .empty(304, "Not modified, with `format=gif`");
+    api.route(
+        "get",
+        "/pixiv/illust/{id}/ugoira/frame/{index}",
+        TAG,
+        "Get a frame of an ugoira",
+    )
+    .path_param::<i64>("id", "database id of the illust")
+    .path_param::<u32>("index", "index of the frame from 0")
+    .content(200, "The frame", IMAGES);
+}
@@ -0,0 +1,137 @@
+//! Id of the request, to be found in the error responses and in the logs.
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use rand::RngCore;
+use std::rc::Rc;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled in the current task.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Take the id from the `X-Request-Id` header set by a proxy if it is sane, or generate one.
+fn request_id(req: &ServiceRequest) -> String {
+    let valid = |s: &&str| {
+        !s.is_empty()
+            && s.len() <= 64
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    };
+    match req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(valid)
+    {
+        Some(id) => id.to_string(),
+        None => {
+            let mut bytes = [0u8; 8];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            hex::encode(bytes)
+        }
+    }
+}
+
+/// Middleware setting the id of the request for [`current`], and in the response header.
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let id = request_id(&req);
+        Box::pin(async move {
+            let header = HeaderValue::from_str(&id).ok();
+            let mut res = REQUEST_ID.scope(id, service.call(req)).await?;
+            if let Some(header) = header {
+                res.headers_mut().insert(REQUEST_ID_HEADER, header);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use actix_web::{get, test, App};
+
+    #[get("/fail")]
+    async fn fail() -> crate::Result<&'static str> {
+        Err(Error::validation("limit", "out of range"))
+    }
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let app = test::init_service(App::new().wrap(RequestId).service(fail)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/fail")
+            .insert_header((REQUEST_ID_HEADER, "abc-1"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-1");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "code": "validation",
+                "message": "limit: out of range",
+                "details": { "field": "limit" },
+                "request_id": "abc-1",
+            })
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/fail")
+            .insert_header((REQUEST_ID_HEADER, "not valid"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let id = res.headers().get(REQUEST_ID_HEADER).unwrap().to_owned();
+        assert_eq!(id.len(), 16);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["request_id"], id.to_str().unwrap());
+    }
+}
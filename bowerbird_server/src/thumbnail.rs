@@ -0,0 +1,607 @@
+//! Thumbnails of the images, cached on disk.
+
+use actix_web::http::StatusCode;
+use bowerbird_core::config::ServerConfig;
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use image::{imageops::FilterType::Lanczos3, GenericImageView, ImageOutputFormat};
+use log::{debug, info, warn};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs, io,
+    io::Cursor,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Instant, SystemTime},
+};
+use tokio::{sync::Semaphore, task::spawn_blocking};
+
+use crate::{error::ServerErrorExt, metrics, utils::spawn_semaphore};
+
+/// The target ratio of the thumbnails cropped to the center.
+pub const CROP_RATIO: f32 = 0.75;
+
+/// From 0 to 10, where 0 is the slowest and 10 is the fastest.
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFormat {
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl ThumbnailFormat {
+    /// Formats which can be encoded, in the order of preference.
+    const SUPPORTED: &'static [ThumbnailFormat] = &[
+        #[cfg(feature = "avif")]
+        ThumbnailFormat::Avif,
+        ThumbnailFormat::Webp,
+        ThumbnailFormat::Jpeg,
+    ];
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Webp => "image/webp",
+            ThumbnailFormat::Avif => "image/avif",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Webp => "webp",
+            ThumbnailFormat::Avif => "avif",
+        }
+    }
+
+    pub fn is_supported(self) -> bool {
+        Self::SUPPORTED.contains(&self)
+    }
+
+    pub fn quality(self, config: &ServerConfig) -> u8 {
+        match self {
+            ThumbnailFormat::Jpeg => config.thumbnail_jpeg_quality,
+            ThumbnailFormat::Webp => config.thumbnail_webp_quality,
+            ThumbnailFormat::Avif => config.thumbnail_avif_quality,
+        }
+    }
+
+    /// Choose the supported format accepted with the highest q-value by the `Accept` header,
+    /// or Jpeg if none of them is accepted.
+    pub fn negotiate(accept: &str) -> Self {
+        let q_value = |mime: &str| {
+            accept
+                .split(',')
+                .filter_map(|item| {
+                    let mut params = item.split(';').map(str::trim);
+                    if params.next()? != mime {
+                        return None;
+                    }
+                    Some(
+                        params
+                            .find_map(|p| p.strip_prefix("q="))
+                            .and_then(|q| q.parse::<f32>().ok())
+                            .unwrap_or(1.0),
+                    )
+                })
+                .fold(0.0, f32::max)
+        };
+        let mut best = (0.0, ThumbnailFormat::Jpeg);
+        for format in Self::SUPPORTED {
+            let q = q_value(format.mime());
+            if q > best.0 {
+                best = (q, *format);
+            }
+        }
+        best.1
+    }
+}
+
+impl FromStr for ThumbnailFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpeg" | "jpg" => Ok(ThumbnailFormat::Jpeg),
+            "webp" => Ok(ThumbnailFormat::Webp),
+            "avif" => Ok(ThumbnailFormat::Avif),
+            _ => Err(format!("unknown thumbnail format: {s}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    size: u64,
+    tick: u64,
+}
+
+/// The index of the thumbnails cached in a dir, evicting the least recently used ones
+/// when their total size exceeds the limit.
+///
+/// The access time is kept as the mtime of the files, so the order survives restarts.
+#[derive(Debug)]
+pub struct ThumbnailCache {
+    dir: PathBuf,
+    max_size: u64,
+    total_size: u64,
+    tick: u64,
+    entries: HashMap<String, Entry>,
+    lru: BTreeSet<(u64, String)>,
+}
+
+impl ThumbnailCache {
+    fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self {
+            dir,
+            max_size,
+            total_size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeSet::new(),
+        }
+    }
+
+    /// Load the thumbnails cached in the dir, which is created if not exists.
+    pub fn open(dir: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut files = vec![];
+        for sub in fs::read_dir(&dir)? {
+            let sub = sub?;
+            if !sub.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(sub.path())? {
+                let file = file?;
+                let path = file.path();
+                let is_thumbnail = path.extension().and_then(|e| e.to_str()).is_some_and(|e| {
+                    [
+                        ThumbnailFormat::Jpeg,
+                        ThumbnailFormat::Webp,
+                        ThumbnailFormat::Avif,
+                    ]
+                    .iter()
+                    .any(|f| f.extension() == e)
+                });
+                if is_thumbnail {
+                    if let Some(key) = path.file_name().and_then(|s| s.to_str()) {
+                        let meta = file.metadata()?;
+                        let mtime = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                        files.push((mtime, key.to_string(), meta.len()));
+                    }
+                }
+            }
+        }
+        files.sort();
+
+        let mut cache = Self::new(dir, max_size);
+        let mut evicted = vec![];
+        for (_, key, size) in files {
+            evicted.extend(cache.record(key, size));
+        }
+        for path in evicted {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("cannot remove thumbnail {:?}: {}", path, e);
+            }
+        }
+        info!(
+            "thumbnail cache loaded: {} files, {} bytes",
+            cache.entries.len(),
+            cache.total_size
+        );
+        Ok(cache)
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(key)
+    }
+
+    /// Mark the thumbnail as used, and return whether it is cached.
+    pub fn touch(&mut self, key: &str) -> bool {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        self.lru.remove(&(entry.tick, key.to_string()));
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.lru.insert((self.tick, key.to_string()));
+        true
+    }
+
+    /// Add the thumbnail written to [`Self::path`], and return the paths of the evicted ones.
+    pub fn record(&mut self, key: String, size: u64) -> Vec<PathBuf> {
+        self.remove(&key);
+        self.tick += 1;
+        self.total_size += size;
+        self.lru.insert((self.tick, key.clone()));
+        self.entries.insert(
+            key,
+            Entry {
+                size,
+                tick: self.tick,
+            },
+        );
+
+        let mut evicted = vec![];
+        while self.total_size > self.max_size {
+            let Some((_, key)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.total_size -= entry.size;
+            }
+            evicted.push(self.path(&key));
+        }
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.total_size -= entry.size;
+            self.lru.remove(&(entry.tick, key.to_string()));
+        }
+    }
+}
+
+/// How the thumbnail is made from the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThumbnailParams {
+    pub size: u32,
+    pub format: ThumbnailFormat,
+    pub quality: u8,
+    /// The target ratio in height/width, or `None` to preserve the ratio of the image.
+    pub target_ratio: Option<f32>,
+}
+
+/// Get the key of the thumbnail, which changes if the image is modified.
+///
+/// The image is identified by its path relative to the storage dir,
+/// so the key is the same whether the storage dir is resolved or not.
+/// It is also the file name of the cached thumbnail.
+fn cache_key(relative: &str, meta: &fs::Metadata, params: ThumbnailParams) -> String {
+    let ThumbnailParams {
+        size,
+        format,
+        quality,
+        target_ratio,
+    } = params;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let mut hasher = Sha256::new();
+    let relative: Vec<&str> = relative
+        .split(['/', '\\'])
+        .filter(|p| !p.is_empty())
+        .collect();
+    hasher.update(relative.join("/").as_bytes());
+    hasher.update(format!(
+        "\0{}\0{}\0{}\0{:?}\0{}\0{:?}",
+        mtime.as_nanos(),
+        meta.len(),
+        size,
+        format,
+        quality,
+        target_ratio.map(f32::to_bits)
+    ));
+    format!("{}.{}", hex::encode(hasher.finalize()), format.extension())
+}
+
+async fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // Not to leave a partial file to be read as cached.
+    // The name is unique as the same thumbnail may be written by concurrent requests.
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+    let tmp_path = PathBuf::from(tmp_path);
+    let r = async {
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+    .await;
+    if r.is_err() {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+    r
+}
+
+/// Get the key of the thumbnail and the time the image is modified, without making it.
+///
+/// `relative` is the path of the image at `local_path` relative to the storage dir.
+pub async fn thumbnail_key(
+    relative: &str,
+    local_path: &Path,
+    params: ThumbnailParams,
+) -> super::Result<(String, SystemTime)> {
+    let meta = tokio::fs::metadata(local_path)
+        .await
+        .with_status(StatusCode::NOT_FOUND)?;
+    let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    Ok((cache_key(relative, &meta, params), modified))
+}
+
+/// Get the thumbnail of the key from [`thumbnail_key`], made if not cached.
+pub async fn cached_image_thumbnail(
+    local_path: impl AsRef<Path>,
+    key: String,
+    params: ThumbnailParams,
+    cache: &Mutex<ThumbnailCache>,
+    semaphore: &Semaphore,
+) -> super::Result<Bytes> {
+    let local_path = local_path.as_ref().to_path_buf();
+    let (cached, path) = {
+        let mut cache = cache.lock().unwrap();
+        (cache.touch(&key), cache.path(&key))
+    };
+
+    // The thumbnail may be written by another process, such as the pregenerating command.
+    if let Ok(b) = tokio::fs::read(&path).await {
+        metrics::observe_thumbnail_cache(true);
+        if cached {
+            spawn_blocking(move || {
+                fs::File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_modified(SystemTime::now()))
+            });
+        } else {
+            let evicted = cache.lock().unwrap().record(key, b.len() as u64);
+            remove_files(evicted).await;
+        }
+        return Ok(Bytes::from(b));
+    }
+    if cached {
+        cache.lock().unwrap().remove(&key);
+    }
+    metrics::observe_thumbnail_cache(false);
+
+    let b = spawn_semaphore(semaphore, move || make_thumbnail(local_path, params)).await?;
+
+    match write_file(&path, &b).await {
+        Ok(_) => {
+            let evicted = cache.lock().unwrap().record(key, b.len() as u64);
+            remove_files(evicted).await;
+        }
+        Err(e) => warn!("cannot write thumbnail {:?}: {}", path, e),
+    }
+    Ok(b)
+}
+
+async fn remove_files(paths: Vec<PathBuf>) {
+    for path in paths {
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            warn!("cannot remove thumbnail {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Get the thumbnail of the image in bytes.
+///
+/// For example, if the target ratio is `Some(0.75)`,
+/// a 16:9 image will be resized to a 4:3 image,
+/// and a 9:16 image will be resized to a 3:4 image.
+fn make_thumbnail(local_path: impl AsRef<Path>, params: ThumbnailParams) -> super::Result<Bytes> {
+    let ThumbnailParams {
+        size,
+        format,
+        quality,
+        target_ratio,
+    } = params;
+    let t = Instant::now();
+    let mut img = image::io::Reader::open(&local_path)
+        .with_status(StatusCode::NOT_FOUND)?
+        .decode()
+        .with_interal()?;
+    let (w, h) = img.dimensions();
+    if w > size || h > size {
+        img = if let Some(target_ratio) = target_ratio {
+            let wdh = (w as f32) / (h as f32);
+            if wdh < target_ratio {
+                img.resize_to_fill((size as f32 * target_ratio) as u32, size, Lanczos3)
+            } else if wdh > (1.0 / target_ratio) {
+                img.resize_to_fill(size, (size as f32 * target_ratio) as u32, Lanczos3)
+            } else {
+                img.resize(size, size, Lanczos3)
+            }
+        } else {
+            img.resize(size, size, Lanczos3)
+        }
+    }
+    let mut b = match format {
+        ThumbnailFormat::Jpeg => {
+            let mut b = Cursor::new(Vec::with_capacity(1024 * 50));
+            img.write_to(&mut b, ImageOutputFormat::Jpeg(quality))
+                .with_interal()?;
+            b.into_inner()
+        }
+        ThumbnailFormat::Webp => {
+            let rgba = img.to_rgba8();
+            webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode(quality as f32)
+                .to_vec()
+        }
+        #[cfg(feature = "avif")]
+        ThumbnailFormat::Avif => {
+            let rgba = img.to_rgba8();
+            let mut b = vec![];
+            image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut b, AVIF_SPEED, quality)
+                .write_image(
+                    rgba.as_raw(),
+                    rgba.width(),
+                    rgba.height(),
+                    image::ColorType::Rgba8,
+                )
+                .with_interal()?;
+            b
+        }
+        #[cfg(not(feature = "avif"))]
+        ThumbnailFormat::Avif => {
+            return Err(super::error::Error::with_msg(
+                StatusCode::BAD_REQUEST,
+                "avif is not supported",
+            ))
+        }
+    };
+    b.shrink_to_fit();
+    metrics::observe_thumbnail_generation(format, t.elapsed());
+    debug!(
+        "made thumbnail for {:?}: {:?}",
+        local_path.as_ref(),
+        t.elapsed()
+    );
+    Ok(Bytes::from(b))
+}
+
+/// Make the thumbnails of all the downloaded images in the sizes and formats,
+/// if not cached yet.
+pub async fn pregenerate(
+    db: &PgPool,
+    storage_dir: &Path,
+    cache: &Mutex<ThumbnailCache>,
+    config: &ServerConfig,
+    sizes: &[u32],
+    formats: &[ThumbnailFormat],
+    crop_to_center: bool,
+) -> anyhow::Result<()> {
+    if let Some(f) = formats.iter().find(|f| !f.is_supported()) {
+        anyhow::bail!("unsupported thumbnail format: {:?}", f);
+    }
+    let paths: Vec<(String,)> = sqlx::query_as(
+        "
+        select local_path
+        from pixiv_media
+        where local_path is not null
+          and mime like 'image/%'
+          and derived_from_id is null
+        order by id
+        ",
+    )
+    .fetch_all(db)
+    .await?;
+    info!("pregenerating thumbnails of {} images", paths.len());
+
+    let semaphore = Semaphore::new(num_cpus::get());
+    let target_ratio = crop_to_center.then_some(CROP_RATIO);
+    let tasks = paths.iter().flat_map(|(path,)| {
+        sizes
+            .iter()
+            .flat_map(move |size| formats.iter().map(move |format| (path, *size, *format)))
+    });
+    let mut results = stream::iter(tasks)
+        .map(|(relative, size, format)| {
+            let semaphore = &semaphore;
+            let params = ThumbnailParams {
+                size,
+                format,
+                quality: format.quality(config),
+                target_ratio,
+            };
+            let path = storage_dir.join(relative);
+            async move {
+                let r = match thumbnail_key(relative, &path, params).await {
+                    Ok((key, _)) => {
+                        cached_image_thumbnail(&path, key, params, cache, semaphore).await
+                    }
+                    Err(e) => Err(e),
+                };
+                (path, r)
+            }
+        })
+        .buffer_unordered(num_cpus::get());
+
+    let (mut done, mut failed) = (0, 0);
+    while let Some((path, r)) = results.next().await {
+        done += 1;
+        if let Err(e) = r {
+            warn!("cannot make thumbnail for {:?}: {}", path, e);
+            failed += 1;
+        }
+        if done % 1000 == 0 {
+            info!("{} thumbnails processed", done);
+        }
+    }
+    info!("{} thumbnails processed, {} failed", done, failed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = ThumbnailCache::new(PathBuf::from("cache"), 10);
+        assert!(cache.record("aa1.jpg".to_string(), 4).is_empty());
+        assert!(cache.record("bb2.webp".to_string(), 4).is_empty());
+        assert!(cache.touch("aa1.jpg"));
+        assert_eq!(
+            cache.record("cc3.jpg".to_string(), 4),
+            [PathBuf::from("cache/bb/bb2.webp")]
+        );
+        assert!(!cache.touch("bb2.webp"));
+        assert_eq!(cache.total_size, 8);
+
+        // Replacing the same key does not count twice.
+        assert!(cache.record("cc3.jpg".to_string(), 6).is_empty());
+        assert_eq!(cache.total_size, 10);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        use ThumbnailFormat::*;
+        let chrome = "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8";
+        let expected = if cfg!(feature = "avif") { Avif } else { Webp };
+        assert_eq!(ThumbnailFormat::negotiate(chrome), expected);
+        assert_eq!(
+            ThumbnailFormat::negotiate("image/webp;q=0.5, image/jpeg"),
+            Jpeg
+        );
+        assert_eq!(ThumbnailFormat::negotiate("image/webp;q=0"), Jpeg);
+        assert_eq!(ThumbnailFormat::negotiate("*/*"), Jpeg);
+        assert_eq!(ThumbnailFormat::negotiate(""), Jpeg);
+    }
+
+    #[actix_web::test]
+    async fn test_write_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ab").join("abc.jpg");
+        let (a, b) = futures::join!(write_file(&path, b"a"), write_file(&path, b"b"));
+        a.unwrap();
+        b.unwrap();
+        let files: Vec<_> = fs::read_dir(dir.path().join("ab")).unwrap().collect();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let meta = file.as_file().metadata().unwrap();
+        let params = ThumbnailParams {
+            size: 256,
+            format: ThumbnailFormat::Jpeg,
+            quality: 80,
+            target_ratio: None,
+        };
+        let key = cache_key("pixiv/1/a.jpg", &meta, params);
+        assert_eq!(key, cache_key("pixiv//1\\a.jpg", &meta, params));
+        assert_ne!(key, cache_key("pixiv/1/b.jpg", &meta, params));
+        let webp = ThumbnailParams {
+            format: ThumbnailFormat::Webp,
+            ..params
+        };
+        assert_ne!(key, cache_key("pixiv/1/a.jpg", &meta, webp));
+    }
+}
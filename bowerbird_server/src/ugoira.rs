@@ -0,0 +1,134 @@
+use actix_web::http::{header::EntityTag, StatusCode};
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, Frame,
+};
+use sha2::{Digest, Sha256};
+use std::{fs::File, io::Read, path::Path, time::SystemTime};
+
+use crate::{error::ServerErrorExt, utils::Validators};
+
+fn open_zip(zip_path: impl AsRef<Path>) -> super::Result<zip::ZipArchive<File>> {
+    let file = File::open(zip_path).with_status(StatusCode::NOT_FOUND)?;
+    zip::ZipArchive::new(file).with_interal()
+}
+
+/// Get the name and the data of the frame in the ugoira zip.
+pub fn read_frame(zip_path: impl AsRef<Path>, index: usize) -> super::Result<(String, Vec<u8>)> {
+    let mut zip_file = open_zip(zip_path)?;
+    let mut file = zip_file
+        .by_index(index)
+        .with_status(StatusCode::NOT_FOUND)?;
+    let mut data = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut data).with_interal()?;
+    Ok((file.name().to_string(), data))
+}
+
+/// Get the validators of the GIF encoded from the ugoira zip, by the metadata of the zip.
+pub async fn gif_validators(zip_path: &Path, frame_delay: &[i32]) -> super::Result<Validators> {
+    let meta = tokio::fs::metadata(zip_path)
+        .await
+        .with_status(StatusCode::NOT_FOUND)?;
+    let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    let mtime = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(zip_path.to_string_lossy().as_bytes());
+    hasher.update(format!(
+        "\0{}\0{}\0{:?}",
+        mtime.as_nanos(),
+        meta.len(),
+        frame_delay
+    ));
+    Ok(Validators::new(
+        EntityTag::new_strong(hex::encode(hasher.finalize())),
+        modified,
+    ))
+}
+
+/// Encode the frames of the ugoira zip as an infinitely looped GIF.
+///
+/// GIF delays are in centiseconds, so the frame delays are rounded.
+pub fn encode_gif(
+    zip_path: impl AsRef<Path>,
+    frame_delay: &[i32],
+    speed: i32,
+) -> super::Result<Vec<u8>> {
+    let mut zip_file = open_zip(zip_path)?;
+    let mut out = vec![];
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut out, speed);
+        encoder.set_repeat(Repeat::Infinite).with_interal()?;
+        for i in 0..zip_file.len() {
+            let delay = frame_delay.get(i).copied().unwrap_or(100).max(0) as u32;
+            let mut file = zip_file.by_index(i).with_interal()?;
+            let mut data = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut data).with_interal()?;
+            let img = image::load_from_memory(&data).with_interal()?.to_rgba8();
+            encoder
+                .encode_frame(Frame::from_parts(
+                    img,
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(delay, 1),
+                ))
+                .with_interal()?;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::gif::GifDecoder, AnimationDecoder, ImageOutputFormat, Rgba, RgbaImage};
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_encode_gif() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let zip_path = tempdir.path().join("1_ugoira600x600.zip");
+
+        let mut zip = ZipWriter::new(File::create(&zip_path).unwrap());
+        for (i, color) in [[255, 0, 0, 255], [0, 0, 255, 255]].iter().enumerate() {
+            let mut data = Cursor::new(vec![]);
+            RgbaImage::from_pixel(4, 4, Rgba(*color))
+                .write_to(&mut data, ImageOutputFormat::Png)
+                .unwrap();
+            zip.start_file(format!("{i:06}.png"), FileOptions::default())
+                .unwrap();
+            zip.write_all(data.get_ref()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let (name, _) = read_frame(&zip_path, 1).unwrap();
+        assert_eq!(name, "000001.png");
+        assert!(read_frame(&zip_path, 2).is_err());
+
+        let gif = encode_gif(&zip_path, &[50, 120], 10).unwrap();
+
+        let validators = gif_validators(&zip_path, &[50, 120]).await.unwrap();
+        assert_eq!(
+            validators.etag,
+            gif_validators(&zip_path, &[50, 120]).await.unwrap().etag
+        );
+        assert_ne!(
+            validators.etag,
+            gif_validators(&zip_path, &[50, 100]).await.unwrap().etag
+        );
+
+        let frames = GifDecoder::new(Cursor::new(gif))
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        let delays: Vec<_> = frames
+            .iter()
+            .map(|f| Into::<std::time::Duration>::into(f.delay()).as_millis())
+            .collect();
+        assert_eq!(delays, [50, 120]);
+    }
+}
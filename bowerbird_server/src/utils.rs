@@ -1,26 +1,30 @@
-use actix_web::http::StatusCode;
+use actix_files::HttpRange;
+use actix_web::{
+    http::{
+        header::{
+            self, ETag, EntityTag, Header, HttpDate, IfModifiedSince, IfNoneMatch, IfRange,
+            LastModified,
+        },
+        StatusCode,
+    },
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+};
 use bytes::Bytes;
-use image::{imageops::FilterType::Lanczos3, GenericImageView, ImageOutputFormat};
-use log::debug;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use std::{
-    collections::HashMap,
-    io::Cursor,
-    path::{Path, PathBuf},
-    sync::Mutex,
-    time::Instant,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::{sync::Semaphore, task::spawn_blocking};
 
-use crate::error::ServerErrorExt;
-
-#[derive(Debug, Hash, PartialEq, Eq, Clone)]
-pub struct ThumbnailCacheKey {
-    size: u32,
-    local_path: PathBuf,
-    target_ratio: Option<u32>,
-}
+use super::{error::*, openapi::BASE_PATH, Result};
 
-pub type ThumbnailCache = HashMap<ThumbnailCacheKey, Bytes>;
+/// The characters kept as is in a path segment of the URLs, the unreserved ones of RFC 3986.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
 
 /// Spawns cpu-bound task and await for result.
 /// The spawned task is aborted when the handle is dropped.
@@ -36,97 +40,326 @@ where
     spawn_blocking(f).await.unwrap()
 }
 
-pub async fn cached_image_thumbnail(
-    local_path: impl AsRef<Path>,
-    size: u32,
-    cache: &Mutex<ThumbnailCache>,
-    semaphore: &Semaphore,
-    quality: u8,
-    target_ratio: Option<f32>,
-) -> super::Result<Bytes> {
-    let local_path = local_path.as_ref().to_path_buf();
-    let target_ratio_u32 = target_ratio.map(|t| (t * 100.0) as u32);
-
-    {
-        let mut cache_guard = cache.lock().unwrap();
-        if cache_guard.len() > 500 {
-            let k = cache_guard.keys().next().unwrap().clone();
-            cache_guard.remove(&k);
+/// Check the path relative to the storage dir without touching the file system.
+///
+/// Both `/` and `\\` are separators, and only normal components are allowed,
+/// so that `..`, absolute paths and drive prefixes are rejected on all platforms.
+fn relative_storage_path(relative: &str) -> Option<PathBuf> {
+    // `:` is never in the stored paths, which are sanitized.
+    if relative.starts_with(['/', '\\']) || relative.contains(['\0', ':']) {
+        return None;
+    }
+    let mut path = PathBuf::new();
+    for part in relative.split(['/', '\\']).filter(|p| !p.is_empty()) {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(c)), None) => path.push(c),
+            _ => return None,
         }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
 
-        if let Some(b) = cache_guard
-            .get(&ThumbnailCacheKey {
-                local_path: local_path.clone(),
-                size,
-                target_ratio: target_ratio_u32,
-            })
-            .cloned()
-        {
-            return Ok(b);
-        }
+/// Resolve the path relative to the storage dir of a file to be served.
+///
+/// The path is canonicalized with the symlinks followed,
+/// and rejected if the file is not inside the storage dir.
+pub async fn resolve_storage_path(storage_dir: &Path, relative: &str) -> Result<PathBuf> {
+    let relative = relative_storage_path(relative)
+        .ok_or_else(|| Error::with_msg(StatusCode::BAD_REQUEST, "invalid path"))?;
+    let root = tokio::fs::canonicalize(storage_dir)
+        .await
+        .with_msg(StatusCode::NOT_FOUND, "file not found")?;
+    let path = tokio::fs::canonicalize(root.join(relative))
+        .await
+        .with_msg(StatusCode::NOT_FOUND, "file not found")?;
+    if !path.starts_with(&root) {
+        return Err(Error::with_msg(
+            StatusCode::FORBIDDEN,
+            "path outside storage",
+        ));
     }
-    // Use a scope to make cache_guard being dropped here.
-
-    let b = spawn_semaphore(semaphore, {
-        let local_path = local_path.clone();
-        move || make_thumbnail(local_path, size, quality, target_ratio)
-    })
-    .await?;
-
-    cache.lock().unwrap().insert(
-        ThumbnailCacheKey {
-            local_path,
-            size,
-            target_ratio: target_ratio_u32,
-        },
-        b.clone(),
-    );
-    Ok(b)
+    Ok(path)
 }
 
-/// Get the Jpeg thumbnail of the image in bytes.
+/// The URL of the file served by the storage route, with each segment of the path percent-encoded.
 ///
-/// The `target_ratio` is the target ratio in height/width.
-/// For example, if the target ratio is `Some(0.75)`,
-/// a 16:9 image will be resized to a 4:3 image,
-/// and a 9:16 image will be resized to a 3:4 image.
+/// Both `/` and `\\` in the path relative to the storage dir are separators.
+pub fn storage_url(relative: &str) -> String {
+    let segments: Vec<String> = relative
+        .split(['/', '\\'])
+        .filter(|p| !p.is_empty())
+        .map(|p| utf8_percent_encode(p, SEGMENT).to_string())
+        .collect();
+    format!("{BASE_PATH}/pixiv/storage/{}", segments.join("/"))
+}
+
+/// The validators of a response for the conditional requests.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: EntityTag,
+    /// Truncated to seconds, the precision of HTTP dates.
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    pub fn new(etag: EntityTag, last_modified: SystemTime) -> Self {
+        let secs = last_modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self {
+            etag,
+            last_modified: UNIX_EPOCH + Duration::from_secs(secs),
+        }
+    }
+
+    pub fn insert_headers(&self, builder: &mut HttpResponseBuilder) {
+        builder
+            .insert_header(ETag(self.etag.clone()))
+            .insert_header(LastModified(HttpDate::from(self.last_modified)));
+    }
+
+    /// Whether the copy of the client is still fresh, by `If-None-Match` or `If-Modified-Since`.
+    pub fn is_not_modified(&self, req: &HttpRequest) -> bool {
+        // `If-Modified-Since` is ignored if `If-None-Match` is present.
+        if req.headers().contains_key(header::IF_NONE_MATCH) {
+            return match IfNoneMatch::parse(req) {
+                Ok(IfNoneMatch::Any) => true,
+                Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|t| t.weak_eq(&self.etag)),
+                Err(_) => false,
+            };
+        }
+        match IfModifiedSince::parse(req) {
+            Ok(IfModifiedSince(since)) => self.last_modified <= SystemTime::from(since),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the range can be served, which is false if `If-Range` does not match.
+    fn is_range_fresh(&self, req: &HttpRequest) -> bool {
+        if !req.headers().contains_key(header::IF_RANGE) {
+            return true;
+        }
+        match IfRange::parse(req) {
+            Ok(IfRange::EntityTag(tag)) => tag.strong_eq(&self.etag),
+            Ok(IfRange::Date(date)) => self.last_modified == SystemTime::from(date),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Respond with the body, or the single range of it requested by the `Range` header.
 ///
-/// If the `target_ratio` is `None`, the ratio of the image will be preserved.
-fn make_thumbnail(
-    local_path: impl AsRef<Path>,
-    size: u32,
-    quality: u8,
-    target_ratio: Option<f32>,
-) -> super::Result<Bytes> {
-    let t = Instant::now();
-    let mut img = image::io::Reader::open(&local_path)
-        .with_status(StatusCode::NOT_FOUND)?
-        .decode()
-        .with_interal()?;
-    let (w, h) = img.dimensions();
-    if w > size || h > size {
-        img = if let Some(target_ratio) = target_ratio {
-            let wdh = (w as f32) / (h as f32);
-            if wdh < target_ratio {
-                img.resize_to_fill((size as f32 * target_ratio) as u32, size, Lanczos3)
-            } else if wdh > (1.0 / target_ratio) {
-                img.resize_to_fill(size, (size as f32 * target_ratio) as u32, Lanczos3)
-            } else {
-                img.resize(size, size, Lanczos3)
+/// The whole body is sent for multiple ranges.
+pub fn ranged_response(
+    req: &HttpRequest,
+    validators: &Validators,
+    builder: &mut HttpResponseBuilder,
+    body: Bytes,
+) -> HttpResponse {
+    validators.insert_headers(builder);
+    builder.insert_header((header::ACCEPT_RANGES, "bytes"));
+    let Some(range) = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|r| r.to_str().ok())
+    else {
+        return builder.body(body);
+    };
+    if !validators.is_range_fresh(req) {
+        return builder.body(body);
+    }
+
+    let len = body.len() as u64;
+    match HttpRange::parse(range, len).as_deref() {
+        Ok([r]) => {
+            let end = r.start + r.length;
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .insert_header((
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", r.start, end - 1, len),
+                ))
+                .body(body.slice(r.start as usize..end as usize))
+        }
+        Ok(_) => builder.body(body),
+        Err(_) => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{len}")))
+            .finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body::to_bytes, test::TestRequest};
+
+    #[test]
+    fn test_relative_storage_path() {
+        assert_eq!(
+            relative_storage_path("123/a_p0.jpg"),
+            Some(PathBuf::from("123").join("a_p0.jpg"))
+        );
+        assert_eq!(
+            relative_storage_path("123//./a.jpg"),
+            None,
+            "'.' is not a normal component"
+        );
+        for path in [
+            "",
+            "/",
+            "../a.jpg",
+            "123/../../a.jpg",
+            "..\\a.jpg",
+            "123\\..\\..\\a.jpg",
+            "/etc/passwd",
+            "\\\\server\\share",
+            "C:\\Windows",
+            "a\0.jpg",
+        ] {
+            assert!(relative_storage_path(path).is_none(), "{path:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_resolve_storage_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_dir = dir.path().join("storage");
+        std::fs::create_dir_all(storage_dir.join("123")).unwrap();
+        std::fs::write(storage_dir.join("123/a.jpg"), b"").unwrap();
+        std::fs::write(dir.path().join("secret"), b"").unwrap();
+
+        let resolved = resolve_storage_path(&storage_dir, "123/a.jpg")
+            .await
+            .unwrap();
+        assert!(resolved.ends_with("123/a.jpg"));
+
+        let status = |path: &'static str| {
+            let storage_dir = storage_dir.clone();
+            async move {
+                resolve_storage_path(&storage_dir, path)
+                    .await
+                    .unwrap_err()
+                    .status
             }
-        } else {
-            img.resize(size, size, Lanczos3)
+        };
+        assert_eq!(status("../secret").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("123/../../secret").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("123/b.jpg").await, StatusCode::NOT_FOUND);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path().join("secret"), storage_dir.join("link"))
+                .unwrap();
+            std::os::unix::fs::symlink(dir.path(), storage_dir.join("123/up")).unwrap();
+            assert_eq!(status("link").await, StatusCode::FORBIDDEN);
+            assert_eq!(status("123/up/secret").await, StatusCode::FORBIDDEN);
         }
     }
-    let mut b = Cursor::new(Vec::with_capacity(1024 * 50));
-    img.write_to(&mut b, ImageOutputFormat::Jpeg(quality))
-        .with_interal()?;
-    let mut b = b.into_inner();
-    b.shrink_to_fit();
-    debug!(
-        "made thumbnail for {:?}: {:?}",
-        local_path.as_ref(),
-        t.elapsed()
-    );
-    Ok(Bytes::from(b))
+
+    #[actix_web::test]
+    async fn test_storage_url() {
+        use actix_web::{test, web, App};
+        use bowerbird_core::config::PathTemplateConfig;
+        use bowerbird_pixiv::path_template::{IllustInfo, PathTemplates};
+
+        let templates = PathTemplates::new(&PathTemplateConfig {
+            single_page: "{user_id}/{title} {illust_id}_p{page}.{ext}".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let info = IllustInfo {
+            user_id: "123",
+            user_name: "user",
+            illust_id: "456",
+            title: "a#b?c%d",
+            illust_type: "illust",
+        };
+        let relative = templates
+            .illust_path(
+                &info,
+                "https://i.pximg.net/img-original/img/2021/08/22/22/03/33/456_p0.png",
+                false,
+            )
+            .unwrap();
+        assert_eq!(relative, "123/a#b_c%d 456_p0.png");
+        let url = storage_url(&relative);
+        assert_eq!(url, "/api/v2/pixiv/storage/123/a%23b_c%25d%20456_p0.png");
+        assert_eq!(storage_url(&relative.replace('/', "\\")), url);
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("123")).unwrap();
+        std::fs::write(dir.path().join(&relative), b"png").unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(crate::PixivConfig {
+                    storage_dir: dir.path().to_path_buf(),
+                }))
+                .service(web::scope("/api/v2/pixiv").service(crate::pixiv::storage)),
+        )
+        .await;
+        let res = test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, "png");
+    }
+
+    fn validators() -> Validators {
+        Validators::new(
+            EntityTag::new_strong("abc".to_string()),
+            UNIX_EPOCH + Duration::from_millis(1_600_000_000_500),
+        )
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let v = validators();
+        let req = |name, value| {
+            TestRequest::default()
+                .insert_header((name, value))
+                .to_http_request()
+        };
+        assert!(v.is_not_modified(&req(header::IF_NONE_MATCH, "\"x\", W/\"abc\"")));
+        assert!(v.is_not_modified(&req(header::IF_NONE_MATCH, "*")));
+        assert!(!v.is_not_modified(&req(header::IF_NONE_MATCH, "\"x\"")));
+        let date = HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_600_000_000)).to_string();
+        assert!(v.is_not_modified(&req(header::IF_MODIFIED_SINCE, &date)));
+        let date = HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_500_000_000)).to_string();
+        assert!(!v.is_not_modified(&req(header::IF_MODIFIED_SINCE, &date)));
+        assert!(!v.is_not_modified(&TestRequest::default().to_http_request()));
+    }
+
+    #[actix_web::test]
+    async fn test_ranged_response() {
+        let v = validators();
+        let body = Bytes::from_static(b"0123456789");
+        let respond = |headers: &[(header::HeaderName, &str)]| {
+            let mut req = TestRequest::default();
+            for h in headers {
+                req = req.insert_header(h.clone());
+            }
+            ranged_response(
+                &req.to_http_request(),
+                &v,
+                &mut HttpResponse::Ok(),
+                body.clone(),
+            )
+        };
+
+        let res = respond(&[(header::RANGE, "bytes=2-4")]);
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            res.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes 2-4/10"
+        );
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "234");
+
+        let res = respond(&[(header::RANGE, "bytes=20-")]);
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let res = respond(&[(header::RANGE, "bytes=2-4"), (header::IF_RANGE, "\"old\"")]);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), body);
+    }
 }
@@ -2,7 +2,13 @@ use aria2_ws::Client;
 use futures::{future::BoxFuture, FutureExt};
 use log::{debug, warn};
 use snafu::ResultExt;
-use std::time::{Duration, Instant};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
     process::{Child, Command},
     time::timeout,
@@ -14,11 +20,27 @@ pub use reqwest::header::HeaderMap;
 
 use super::Task;
 
+/// Counters of the downloads since the downloader is started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DownloadStats {
+    pub queued: u64,
+    pub completed: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queued: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+}
+
 pub struct Aria2Downloader {
     client: Client,
     /// Spawned aria2 process. Will be killed when dropped.
     _child: Child,
     waitgroup: WaitGroup,
+    counters: Arc<Counters>,
 }
 
 impl Aria2Downloader {
@@ -57,11 +79,25 @@ impl Aria2Downloader {
             client,
             _child: child,
             waitgroup: WaitGroup::new(),
+            counters: Default::default(),
         })
     }
 
-    fn map_hook(&self, hook: Option<super::BoxFutureResult>) -> BoxFuture<'static, ()> {
+    fn map_hook(
+        &self,
+        hook: Option<super::BoxFutureResult>,
+        succeeded: bool,
+    ) -> BoxFuture<'static, ()> {
         let waitgroup = self.waitgroup.clone();
+        let counters = self.counters.clone();
+        let count = move || {
+            let counter = if succeeded {
+                &counters.completed
+            } else {
+                &counters.failed
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        };
         if let Some(hook) = hook {
             async move {
                 let i = Instant::now();
@@ -69,27 +105,52 @@ impl Aria2Downloader {
                     warn!("error on hook: {}", err);
                 }
                 debug!("hook took {:?}", i.elapsed());
+                count();
                 waitgroup.done();
             }
             .boxed()
         } else {
-            async move { waitgroup.done() }.boxed()
+            async move {
+                count();
+                waitgroup.done()
+            }
+            .boxed()
         }
     }
 
     pub async fn add_task(&self, task: Task) -> Result<()> {
         let hooks = task.hooks.map(|hooks| aria2_ws::Callbacks {
-            on_download_complete: Some(self.map_hook(hooks.on_success)),
-            on_error: Some(self.map_hook(hooks.on_error)),
+            on_download_complete: Some(self.map_hook(hooks.on_success, true)),
+            on_error: Some(self.map_hook(hooks.on_error, false)),
         });
         self.client
             .add_uri(vec![task.url], task.options, None, hooks)
             .await
             .context(error::Aria2)?;
         self.waitgroup.add(1);
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
+    /// Number of the downloads not completed or failed yet.
+    pub fn pending_tasks(&self) -> usize {
+        self.waitgroup.count()
+    }
+
+    pub fn stats(&self) -> DownloadStats {
+        DownloadStats {
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            completed: self.counters.completed.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Version of aria2, to check the RPC connection is alive.
+    pub async fn version(&self) -> Result<String> {
+        let version = self.client.get_version().await.context(error::Aria2)?;
+        Ok(version.version)
+    }
+
     pub async fn wait_and_shutdown(self) {
         self.waitgroup.await;
         let _ = self.client.force_shutdown().await;
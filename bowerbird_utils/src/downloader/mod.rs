@@ -1,6 +1,6 @@
 use futures::future::BoxFuture;
 
-pub use aria2::Aria2Downloader;
+pub use aria2::{Aria2Downloader, DownloadStats};
 
 mod aria2;
 